@@ -0,0 +1,291 @@
+//! Destinations a portal may reach through dynamic forwarding.
+//!
+//! A rule is `<host>[:<ports>]`. The host is an IP address, a CIDR block, a
+//! hostname, a `*.suffix` domain pattern or `*` for any host; IPv6 hosts are
+//! written in brackets. Ports are a single port, a `first-last` range or `*`,
+//! all ports when left out. Examples: `192.168.1.0/24:502`, `plc.local`,
+//! `[fd00::/8]:8000-8100`.
+
+use crate::refusal::{StreamError, StreamErrorKind};
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use tokio::net::lookup_host;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostPattern {
+    Any,
+    Net {
+        addr: IpAddr,
+        prefix: u8,
+    },
+    Name(String),
+    /// Strict subdomains of the suffix, stored with its leading dot
+    Domain(String),
+}
+
+impl HostPattern {
+    fn matches_ip(&self, ip: IpAddr) -> bool {
+        match (self, ip) {
+            (Self::Any, _) => true,
+            (Self::Net { addr: IpAddr::V4(net), prefix }, IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                u32::from(*net) & mask == u32::from(ip) & mask
+            }
+            (Self::Net { addr: IpAddr::V6(net), prefix }, IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                u128::from(*net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    fn matches_name(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        match self {
+            Self::Any => true,
+            Self::Name(pattern) => *pattern == name,
+            Self::Domain(suffix) => name.ends_with(suffix.as_str()),
+            Self::Net { .. } => false,
+        }
+    }
+}
+
+impl FromStr for HostPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            return Ok(Self::Any);
+        }
+        if let Some(suffix) = s.strip_prefix("*.") {
+            return Ok(Self::Domain(format!(".{}", suffix.to_ascii_lowercase())));
+        }
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        match addr.parse::<IpAddr>() {
+            Ok(addr) => {
+                let max = if addr.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    Some(p) => p.parse().ok().filter(|p| *p <= max),
+                    None => Some(max),
+                };
+                let prefix = prefix.ok_or_else(|| anyhow!("Invalid CIDR block: {}", s))?;
+                Ok(Self::Net { addr, prefix })
+            }
+            Err(_) if prefix.is_none() && !s.is_empty() => Ok(Self::Name(s.to_ascii_lowercase())),
+            Err(_) => Err(anyhow!("Invalid host pattern: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Any => write!(f, "*"),
+            Self::Net { addr: IpAddr::V4(addr), prefix: 32 } => write!(f, "{}", addr),
+            Self::Net { addr: IpAddr::V6(addr), prefix: 128 } => write!(f, "[{}]", addr),
+            Self::Net { addr: IpAddr::V4(addr), prefix } => write!(f, "{}/{}", addr, prefix),
+            Self::Net { addr: IpAddr::V6(addr), prefix } => write!(f, "[{}/{}]", addr, prefix),
+            Self::Name(name) => write!(f, "{}", name),
+            Self::Domain(suffix) => write!(f, "*{}", suffix),
+        }
+    }
+}
+
+/// One allowed host pattern and port range
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowRule {
+    host: HostPattern,
+    ports: (u16, u16),
+}
+
+impl AllowRule {
+    fn allows_port(&self, port: u16) -> bool {
+        (self.ports.0..=self.ports.1).contains(&port)
+    }
+
+    /// Whether the address itself matches, name rules never do. Port 0 only
+    /// matches rules allowing all ports.
+    pub(crate) fn allows_addr(&self, addr: SocketAddr) -> bool {
+        let port = match addr.port() {
+            0 => self.ports == (1, u16::MAX),
+            port => self.allows_port(port),
+        };
+        port && self.host.matches_ip(addr.ip())
+    }
+}
+
+impl FromStr for AllowRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, ports) = match s.strip_prefix('[') {
+            Some(rest) => {
+                let (host, rest) =
+                    rest.split_once(']').ok_or_else(|| anyhow!("Unclosed '[' in {}", s))?;
+                match rest {
+                    "" => (host, None),
+                    _ => {
+                        let ports = rest.strip_prefix(':');
+                        (host, Some(ports.ok_or_else(|| anyhow!("Invalid allow rule: {}", s))?))
+                    }
+                }
+            }
+            // More than one colon is a bare IPv6 address without ports
+            None => match s.split_once(':') {
+                Some((host, ports)) if !ports.contains(':') => (host, Some(ports)),
+                _ => (s, None),
+            },
+        };
+
+        let ports = match ports {
+            None | Some("*") => (1, u16::MAX),
+            Some(ports) => {
+                let (first, last) = ports.split_once('-').unwrap_or((ports, ports));
+                let range = (first.parse::<u16>(), last.parse::<u16>());
+                match range {
+                    (Ok(first), Ok(last)) if first <= last => (first, last),
+                    _ => return Err(anyhow!("Invalid port range: {}", ports)),
+                }
+            }
+        };
+        Ok(Self { host: host.parse()?, ports })
+    }
+}
+
+impl fmt::Display for AllowRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ports {
+            (1, u16::MAX) => write!(f, "{}", self.host),
+            (first, last) if first == last => write!(f, "{}:{}", self.host, first),
+            (first, last) => write!(f, "{}:{}-{}", self.host, first, last),
+        }
+    }
+}
+
+/// Rules checked before a dynamically forwarded connection is made. An empty
+/// list allows nothing, which keeps dynamic forwarding off.
+#[derive(Debug, Clone, Default)]
+pub struct Allowlist {
+    rules: Vec<AllowRule>,
+}
+
+impl Allowlist {
+    pub fn push(&mut self, rule: AllowRule) {
+        self.rules.push(rule);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Resolve a `host:port` destination to the addresses the rules allow.
+    ///
+    /// A hostname matching a name rule allows every address it resolves to,
+    /// otherwise each resolved address has to match an address rule itself.
+    /// Connecting to the returned addresses rather than resolving again keeps
+    /// the name from pointing elsewhere in between.
+    pub async fn resolve(&self, dest: &str) -> Result<Vec<SocketAddr>> {
+        let not_allowed = |message: String| StreamError::new(StreamErrorKind::NotAllowed, message);
+        let denied = || not_allowed(format!("Destination not allowed: {}", dest)).into();
+        if self.rules.is_empty() {
+            return Err(
+                not_allowed(format!("Dynamic forwarding disabled, {} refused", dest)).into()
+            );
+        }
+
+        let (host, port) = dest
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+            .ok_or_else(|| anyhow!("Invalid destination, expected host:port: {}", dest))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let rules: Vec<_> = self.rules.iter().filter(|r| r.allows_port(port)).collect();
+
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return match rules.iter().any(|r| r.host.matches_ip(ip)) {
+                true => Ok(vec![SocketAddr::new(ip, port)]),
+                false => Err(denied()),
+            };
+        }
+
+        let by_name = rules.iter().any(|r| r.host.matches_name(host));
+        if !by_name && !rules.iter().any(|r| matches!(r.host, HostPattern::Net { .. })) {
+            return Err(denied());
+        }
+        let addrs: Vec<SocketAddr> = lookup_host((host, port))
+            .await
+            .map_err(|e| {
+                let message = format!("Failed to resolve {}: {}", host, e);
+                StreamError::new(StreamErrorKind::Unreachable, message)
+            })?
+            .filter(|addr| by_name || rules.iter().any(|r| r.host.matches_ip(addr.ip())))
+            .collect();
+        if addrs.is_empty() {
+            return Err(denied());
+        }
+        Ok(addrs)
+    }
+}
+
+impl fmt::Display for Allowlist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rules: Vec<String> = self.rules.iter().map(|r| r.to_string()).collect();
+        write!(f, "{}", rules.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist(rules: &[&str]) -> Allowlist {
+        let mut allowlist = Allowlist::default();
+        for rule in rules {
+            allowlist.push(rule.parse().unwrap());
+        }
+        allowlist
+    }
+
+    #[test]
+    fn test_parse_allow_rule() {
+        for rule in ["192.168.1.0/24:502", "plc.local", "*.lan:8000-8100", "[fd00::/8]:22", "*"] {
+            assert_eq!(rule.parse::<AllowRule>().unwrap().to_string(), rule);
+        }
+        assert_eq!("10.0.0.1:*".parse::<AllowRule>().unwrap().to_string(), "10.0.0.1");
+        assert_eq!("::1".parse::<AllowRule>().unwrap().to_string(), "[::1]");
+
+        assert!("10.0.0.0/33".parse::<AllowRule>().is_err());
+        assert!("10.0.0.1:9000-80".parse::<AllowRule>().is_err());
+        assert!("host:http".parse::<AllowRule>().is_err());
+        assert!("[::1:22".parse::<AllowRule>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_ip() {
+        let allowlist = allowlist(&["192.168.1.0/24:502", "[fd00::/8]"]);
+        let addrs = allowlist.resolve("192.168.1.50:502").await.unwrap();
+        assert_eq!(addrs, vec!["192.168.1.50:502".parse().unwrap()]);
+        assert!(allowlist.resolve("192.168.1.50:22").await.is_err());
+        assert!(allowlist.resolve("192.168.2.50:502").await.is_err());
+        assert!(allowlist.resolve("[fd12::1]:443").await.is_ok());
+        assert!(allowlist.resolve("192.168.1.50").await.is_err());
+
+        assert!(Allowlist::default().resolve("192.168.1.50:502").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_name() {
+        let by_name = allowlist(&["LocalHost:9000"]);
+        assert_eq!(by_name.resolve("localhost:9000").await.unwrap()[0].port(), 9000);
+        assert!(by_name.resolve("localhost:9001").await.is_err());
+
+        let by_addr = allowlist(&["127.0.0.0/8", "[::1]"]);
+        assert!(by_addr.resolve("localhost:22").await.is_ok());
+        assert!(allowlist(&["10.0.0.0/8"]).resolve("localhost:22").await.is_err());
+        assert!(allowlist(&["*.lan"]).resolve("localhost:22").await.is_err());
+    }
+}
@@ -1,38 +1,213 @@
+use crate::compression::Compressor;
+use crate::config::Lifetime;
+use crate::refusal::StreamError;
+#[cfg(unix)]
+use crate::serial::PTY_SCHEME;
+use crate::shaping::Shaper;
+use anyhow::Result;
+use async_trait::async_trait;
 use bytes::Bytes;
-use std::sync::Arc;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
-use tokio::sync::Mutex;
-use tracing::{error, info, trace};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf};
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio::time::Instant;
+use tracing::{error, info, trace, warn};
 use webrtc::data_channel::data_channel_message::DataChannelMessage;
 use webrtc::data_channel::RTCDataChannel;
 
 const BUFFER_SIZE: usize = 4096;
+/// How long closing a DataChannel waits for its data to be acknowledged
+const CLOSE_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
 
-pub fn spawn_dc_socket_bridge<S>(dc: Arc<RTCDataChannel>, socket: S)
+/// Any byte stream that can be bridged: TCP, Unix socket, ...
+pub(crate) trait Socket: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Socket for T {}
+
+pub(crate) type BoxSocket = Box<dyn Socket>;
+
+/// Carrier of one bridged stream: a dedicated DataChannel or a logical stream
+/// of a multiplexed DataChannel.
+#[async_trait]
+pub(crate) trait Tunnel: Send + Sync {
+    fn label(&self) -> &str;
+
+    async fn send(&self, data: Bytes) -> Result<()>;
+
+    /// Next chunk from the remote side, `None` once the tunnel is closed
+    async fn recv(&self) -> Option<Bytes>;
+
+    /// Called after `n` received bytes have been written to the socket
+    async fn consumed(&self, _n: usize) {}
+
+    async fn close(&self);
+
+    /// Close a stream that couldn't be served, telling the remote side why
+    async fn reject(&self, _error: &StreamError) {
+        self.close().await;
+    }
+
+    /// Why the remote side refused the stream, once it closed it doing so
+    fn refusal(&self) -> Option<StreamError> {
+        None
+    }
+}
+
+/// Serves a stream opened by the remote side, with the request it was opened with
+pub(crate) type AcceptFn = Arc<dyn Fn(Arc<dyn Tunnel>, String) + Send + Sync>;
+
+/// Per-session wrappers applied to every stream, and what the session tracks
+/// of its streams
+#[derive(Clone)]
+pub(crate) struct Layers {
+    pub shaper: Arc<Shaper>,
+    pub compressor: Option<Arc<Compressor>>,
+    pub activity: Arc<Activity>,
+    pub lifetime: Lifetime,
+}
+
+impl Layers {
+    /// Shaping sits below compression, so limits apply to the bytes on the wire
+    pub fn wrap(&self, tunnel: Arc<dyn Tunnel>) -> Arc<dyn Tunnel> {
+        let tunnel = self.shaper.wrap(tunnel);
+        match &self.compressor {
+            Some(compressor) => compressor.wrap(tunnel),
+            None => tunnel,
+        }
+    }
+}
+
+/// Local address whose accepted connections are forwarded over the peer connection
+pub(crate) struct Listener {
+    pub addr_uri: String,
+    /// Bound address, differs from `addr_uri` for port 0
+    pub local_addr: String,
+    pub handle: AbortHandle,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.handle.abort();
+
+        #[cfg(unix)]
+        if let Some(path) = self.addr_uri.strip_prefix("unix://") {
+            remove_socket(path);
+        } else if let Some(path) = self.addr_uri.strip_prefix(PTY_SCHEME) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Remove the socket at `path`, leaving anything else there alone
+#[cfg(unix)]
+pub(crate) fn remove_socket(path: &str) {
+    use std::os::unix::fs::FileTypeExt;
+
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Bridge a socket to a tunnel of the session of `layers` until either side
+/// closes or the stream runs out of its [`Lifetime`]
+pub(crate) fn spawn_bridge<S>(tunnel: Arc<dyn Tunnel>, socket: S, layers: &Layers)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    bridge(layers.wrap(tunnel), layers.activity.track(socket), layers.lifetime);
+}
+
+fn bridge<S>(tunnel: Arc<dyn Tunnel>, socket: S, lifetime: Lifetime)
 where
-    S: AsyncRead + AsyncWrite + Send + Sync + 'static,
+    S: AsyncRead + AsyncWrite + Send + 'static,
 {
+    let traffic = Arc::new(Traffic::new());
     let (socket_read, socket_write) = tokio::io::split(socket);
-    let socket_write = Arc::new(Mutex::new(socket_write));
+    let reader =
+        tokio::spawn(socket_to_tunnel(Arc::clone(&tunnel), socket_read, Arc::clone(&traffic)));
+    let writer = tokio::spawn(tunnel_to_socket(
+        Arc::clone(&tunnel),
+        socket_write,
+        reader.abort_handle(),
+        Arc::clone(&traffic),
+    ));
+    if !lifetime.is_unlimited() {
+        tokio::spawn(expire(tunnel, lifetime, traffic, reader, writer));
+    }
+}
+
+/// Close a stream once it ran out of its lifetime, dropping its socket
+async fn expire(
+    tunnel: Arc<dyn Tunnel>,
+    lifetime: Lifetime,
+    traffic: Arc<Traffic>,
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+) {
+    let (stop_reader, stop_writer) = (reader.abort_handle(), writer.abort_handle());
+    let finished = async {
+        let _ = tokio::join!(reader, writer);
+    };
+    tokio::select! {
+        _ = finished => {}
+        reason = lifetime.expired(|| Some(traffic.last())) => {
+            info!("{} {}, closing", tunnel.label(), reason);
+            stop_reader.abort();
+            stop_writer.abort();
+            tunnel.close().await;
+        }
+    }
+}
+
+pub(crate) fn spawn_dc_socket_bridge<S>(dc: Arc<RTCDataChannel>, socket: S, layers: &Layers)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (incoming_tx, incoming_rx) = mpsc::channel(1);
+    let incoming_tx = Arc::new(StdMutex::new(Some(incoming_tx)));
+    let refusal = Arc::new(OnceLock::new());
+    let tunnel = layers.wrap(Arc::new(DcTunnel {
+        dc: Arc::clone(&dc),
+        incoming: Mutex::new(incoming_rx),
+        refusal: Arc::clone(&refusal),
+    }));
+    // Counted from now on, the DataChannel still has to open
+    let socket = StdMutex::new(Some(layers.activity.track(socket)));
+    let lifetime = layers.lifetime;
 
     let dc_for_open = Arc::clone(&dc);
     dc.on_open(Box::new(move || {
         let dc = Arc::clone(&dc_for_open);
+        let tunnel = Arc::clone(&tunnel);
+        let socket = socket.lock().unwrap().take();
         Box::pin(async move {
             info!("{} opened", dc.label());
-            tokio::spawn(socket_to_dc(dc, socket_read));
+            if let Some(socket) = socket {
+                bridge(tunnel, socket, lifetime);
+            }
         })
     }));
 
-    let dc_for_msg = Arc::clone(&dc);
-    let socket_for_msg = Arc::clone(&socket_write);
+    // Awaiting the bounded channel keeps the SCTP read loop as backpressure
+    let tx_for_msg = Arc::clone(&incoming_tx);
     dc.on_message(Box::new(move |msg: DataChannelMessage| {
-        let dc = Arc::clone(&dc_for_msg);
-        let socket = Arc::clone(&socket_for_msg);
+        let tx = tx_for_msg.lock().unwrap().clone();
+        // Data is always binary, text carries the reason the proxy refused the stream
+        if msg.is_string {
+            let _ = refusal.set(StreamError::decode(&String::from_utf8_lossy(&msg.data)));
+        }
         Box::pin(async move {
-            if let Err(e) = dc_to_socket(msg.data.to_vec(), &socket).await {
-                error!("{} write error: {}", dc.label(), e);
-                let _ = dc.close().await;
+            if msg.is_string {
+                return;
+            }
+            if let Some(tx) = tx {
+                let _ = tx.send(msg.data).await;
             }
         })
     }));
@@ -46,52 +221,214 @@ where
     }));
 
     let dc_for_close = Arc::clone(&dc);
-    let socket_for_close = Arc::clone(&socket_write);
     dc.on_close(Box::new(move || {
         let dc = Arc::clone(&dc_for_close);
-        let socket = Arc::clone(&socket_for_close);
+        incoming_tx.lock().unwrap().take();
         Box::pin(async move {
             info!("{} closed", dc.label());
-            let mut guard = socket.lock().await;
-            let _ = guard.shutdown().await;
         })
     }));
 }
 
-async fn socket_to_dc<R>(dc: Arc<RTCDataChannel>, mut reader: ReadHalf<R>)
-where
-    R: AsyncRead + Send + Sync,
+struct DcTunnel {
+    dc: Arc<RTCDataChannel>,
+    incoming: Mutex<mpsc::Receiver<Bytes>>,
+    refusal: Arc<OnceLock<StreamError>>,
+}
+
+#[async_trait]
+impl Tunnel for DcTunnel {
+    fn label(&self) -> &str {
+        self.dc.label()
+    }
+
+    async fn send(&self, data: Bytes) -> Result<()> {
+        self.dc.send(&data).await?;
+        Ok(())
+    }
+
+    async fn recv(&self) -> Option<Bytes> {
+        self.incoming.lock().await.recv().await
+    }
+
+    async fn close(&self) {
+        // Closing resets the SCTP stream, which discards what the remote hasn't
+        // read yet. Data that was acknowledged has at least reached its queue.
+        let _ = tokio::time::timeout(CLOSE_FLUSH_TIMEOUT, async {
+            while self.dc.buffered_amount().await > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        let _ = self.dc.close().await;
+    }
+
+    fn refusal(&self) -> Option<StreamError> {
+        self.refusal.get().cloned()
+    }
+}
+
+async fn socket_to_tunnel<R>(
+    tunnel: Arc<dyn Tunnel>,
+    mut reader: ReadHalf<R>,
+    traffic: Arc<Traffic>,
+) where
+    R: AsyncRead + Send,
 {
     let mut buffer = vec![0u8; BUFFER_SIZE];
 
     loop {
         match reader.read(&mut buffer).await {
             Ok(0) => {
-                info!("{} socket EOF. closed", dc.label());
+                info!("{} socket EOF. closed", tunnel.label());
                 break;
             }
             Ok(n) => {
+                traffic.touch();
                 let bytes = Bytes::copy_from_slice(&buffer[..n]);
-                if let Err(e) = dc.send(&bytes).await {
-                    error!("{} send error: {}", dc.label(), e);
+                if let Err(e) = tunnel.send(bytes).await {
+                    error!("{} send error: {}", tunnel.label(), e);
                     break;
                 }
-                trace!("{} {} bytes -> DC", dc.label(), n);
+                trace!("{} {} bytes -> DC", tunnel.label(), n);
             }
             Err(e) => {
-                error!("{} socket read error: {}", dc.label(), e);
+                error!("{} socket read error: {}", tunnel.label(), e);
                 break;
             }
         }
     }
 
-    let _ = dc.close().await;
+    tunnel.close().await;
 }
 
-async fn dc_to_socket<W>(data: Vec<u8>, writer: &Arc<Mutex<WriteHalf<W>>>) -> std::io::Result<()>
-where
-    W: AsyncWrite + Send + Sync,
+async fn tunnel_to_socket<W>(
+    tunnel: Arc<dyn Tunnel>,
+    mut writer: WriteHalf<W>,
+    reader: AbortHandle,
+    traffic: Arc<Traffic>,
+) where
+    W: AsyncWrite + Send,
 {
-    let mut guard = writer.lock().await;
-    guard.write_all(&data).await
+    while let Some(data) = tunnel.recv().await {
+        traffic.touch();
+        if let Err(e) = writer.write_all(&data).await {
+            error!("{} write error: {}", tunnel.label(), e);
+            tunnel.close().await;
+            return;
+        }
+        tunnel.consumed(data.len()).await;
+    }
+
+    // A refused stream never gets an answer: drop the whole socket instead of
+    // half-closing it, so the client fails now rather than at its own timeout
+    if let Some(error) = tunnel.refusal() {
+        warn!("{} refused by remote, {}", tunnel.label(), error);
+        reader.abort();
+        return;
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// When a bridged stream last carried data
+struct Traffic {
+    start: Instant,
+    /// Milliseconds since `start`
+    last: AtomicU64,
+}
+
+impl Traffic {
+    fn new() -> Self {
+        Self { start: Instant::now(), last: AtomicU64::new(0) }
+    }
+
+    fn touch(&self) {
+        self.last.store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+}
+
+/// Streams a portal or proxy session bridges, it is idle once none is left
+pub(crate) struct Activity {
+    pub streams: AtomicUsize,
+    pub changed: Notify,
+    /// When the last stream ended
+    last_active: StdMutex<Instant>,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self {
+            streams: AtomicUsize::new(0),
+            changed: Notify::new(),
+            last_active: StdMutex::new(Instant::now()),
+        }
+    }
+}
+
+impl Activity {
+    /// Count a stream, or a connection waiting for one, until the guard is dropped
+    pub fn enter(self: &Arc<Self>) -> Active {
+        self.streams.fetch_add(1, Ordering::Relaxed);
+        self.changed.notify_waiters();
+        Active(Arc::clone(self))
+    }
+
+    /// Count `socket` as a stream until it is dropped
+    pub fn track<S>(self: &Arc<Self>, socket: S) -> Tracked<S> {
+        Tracked { inner: socket, _active: self.enter() }
+    }
+
+    /// Since when no stream is left, `None` while there are streams
+    pub fn idle_since(&self) -> Option<Instant> {
+        let last_active = *self.last_active.lock().unwrap();
+        (self.streams.load(Ordering::Relaxed) == 0).then_some(last_active)
+    }
+}
+
+pub(crate) struct Active(Arc<Activity>);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        *self.0.last_active.lock().unwrap() = Instant::now();
+        self.0.streams.fetch_sub(1, Ordering::Relaxed);
+        self.0.changed.notify_waiters();
+    }
+}
+
+/// A bridged socket, counted by its session's [`Activity`]
+pub(crate) struct Tracked<S> {
+    inner: S,
+    _active: Active,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
 }
@@ -0,0 +1,260 @@
+//! Per-chunk compression of tunneled streams.
+//!
+//! Every tunnel message starts with a one byte header telling whether the rest
+//! is compressed. Chunks below the threshold, or that don't get smaller, are
+//! sent as is, so incompressible traffic only costs the header byte.
+
+use crate::binder::Tunnel;
+use crate::config::Compression;
+use crate::refusal::StreamError;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tracing::warn;
+
+const CHUNK_RAW: u8 = 0;
+const CHUNK_COMPRESSED: u8 = 1;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Upper bound on the decompressed size of a single chunk
+const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Byte counters of a compressed session, both directions combined
+#[derive(Debug, Default)]
+pub struct CompressionStats {
+    raw_bytes: AtomicU64,
+    wire_bytes: AtomicU64,
+    compressed_chunks: AtomicU64,
+    raw_chunks: AtomicU64,
+}
+
+impl CompressionStats {
+    /// Payload bytes before compression
+    pub fn raw_bytes(&self) -> u64 {
+        self.raw_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Bytes actually carried by the DataChannels
+    pub fn wire_bytes(&self) -> u64 {
+        self.wire_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Achieved compression ratio, 1.0 until any data was transferred
+    pub fn ratio(&self) -> f64 {
+        match self.wire_bytes() {
+            0 => 1.0,
+            wire => self.raw_bytes() as f64 / wire as f64,
+        }
+    }
+
+    fn record(&self, raw: usize, wire: usize, compressed: bool) {
+        self.raw_bytes.fetch_add(raw as u64, Ordering::Relaxed);
+        self.wire_bytes.fetch_add(wire as u64, Ordering::Relaxed);
+        let chunks = if compressed { &self.compressed_chunks } else { &self.raw_chunks };
+        chunks.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for CompressionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} bytes (ratio {:.2}, {} chunks compressed, {} sent raw)",
+            self.raw_bytes(),
+            self.wire_bytes(),
+            self.ratio(),
+            self.compressed_chunks.load(Ordering::Relaxed),
+            self.raw_chunks.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// Compression agreed on for a session, shared by all of its streams
+pub(crate) struct Compressor {
+    algorithm: Compression,
+    threshold: usize,
+    stats: Arc<CompressionStats>,
+}
+
+impl Compressor {
+    /// `None` unless an algorithm was agreed on
+    pub fn new(algorithm: Compression, threshold: usize) -> Option<Arc<Self>> {
+        if algorithm == Compression::None {
+            return None;
+        }
+        Some(Arc::new(Self { algorithm, threshold, stats: Arc::default() }))
+    }
+
+    pub fn stats(&self) -> Arc<CompressionStats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Wrap a tunnel so data is compressed on the way out and restored on the way in
+    pub fn wrap(self: &Arc<Self>, tunnel: Arc<dyn Tunnel>) -> Arc<dyn Tunnel> {
+        Arc::new(CompressedTunnel {
+            inner: tunnel,
+            compressor: Arc::clone(self),
+            unconsumed: AtomicUsize::new(0),
+        })
+    }
+
+    fn encode(&self, data: &[u8]) -> Bytes {
+        if data.len() >= self.threshold {
+            let compressed = match self.algorithm {
+                Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).ok(),
+                Compression::Lz4 => Some(lz4_flex::compress_prepend_size(data)),
+                Compression::None => None,
+            };
+            if let Some(compressed) = compressed.filter(|c| c.len() < data.len()) {
+                self.stats.record(data.len(), compressed.len() + 1, true);
+                return frame(CHUNK_COMPRESSED, &compressed);
+            }
+        }
+        self.stats.record(data.len(), data.len() + 1, false);
+        frame(CHUNK_RAW, data)
+    }
+
+    fn decode(&self, mut data: Bytes) -> Result<Bytes> {
+        if data.is_empty() {
+            return Err(anyhow!("empty chunk"));
+        }
+        let wire = data.len();
+        let header = data.split_to(1)[0];
+        let raw = match header {
+            CHUNK_RAW => data,
+            CHUNK_COMPRESSED => Bytes::from(self.decompress(&data)?),
+            _ => return Err(anyhow!("unknown chunk type {}", header)),
+        };
+        self.stats.record(raw.len(), wire, header == CHUNK_COMPRESSED);
+        Ok(raw)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let size = match self.algorithm {
+            Compression::Zstd => zstd::zstd_safe::get_frame_content_size(data)
+                .ok()
+                .flatten()
+                .ok_or_else(|| anyhow!("zstd frame without content size"))?
+                as usize,
+            Compression::Lz4 => lz4_flex::block::uncompressed_size(data)?.0,
+            Compression::None => return Err(anyhow!("compression not negotiated")),
+        };
+        if size > MAX_CHUNK_SIZE {
+            return Err(anyhow!("chunk of {} bytes exceeds limit", size));
+        }
+        match self.algorithm {
+            Compression::Zstd => Ok(zstd::bulk::decompress(data, size)?),
+            _ => Ok(lz4_flex::decompress_size_prepended(data)?),
+        }
+    }
+}
+
+fn frame(header: u8, payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(1 + payload.len());
+    buf.put_u8(header);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+struct CompressedTunnel {
+    inner: Arc<dyn Tunnel>,
+    compressor: Arc<Compressor>,
+    /// Wire size of the last received chunk, credited once it was consumed
+    unconsumed: AtomicUsize,
+}
+
+#[async_trait]
+impl Tunnel for CompressedTunnel {
+    fn label(&self) -> &str {
+        self.inner.label()
+    }
+
+    async fn send(&self, data: Bytes) -> Result<()> {
+        self.inner.send(self.compressor.encode(&data)).await
+    }
+
+    async fn recv(&self) -> Option<Bytes> {
+        let data = self.inner.recv().await?;
+        self.unconsumed.fetch_add(data.len(), Ordering::Relaxed);
+        match self.compressor.decode(data) {
+            Ok(raw) => Some(raw),
+            Err(e) => {
+                warn!("{} dropped: {}", self.label(), e);
+                self.inner.close().await;
+                None
+            }
+        }
+    }
+
+    async fn consumed(&self, _n: usize) {
+        let wire = self.unconsumed.swap(0, Ordering::Relaxed);
+        self.inner.consumed(wire).await;
+    }
+
+    async fn close(&self) {
+        self.inner.close().await;
+    }
+
+    fn refusal(&self) -> Option<StreamError> {
+        self.inner.refusal()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let text = b"{\"joint\": 0.0, \"velocity\": 0.0}".repeat(64);
+        for algorithm in [Compression::Zstd, Compression::Lz4] {
+            let compressor = Compressor::new(algorithm, 256).unwrap();
+            let encoded = compressor.encode(&text);
+            assert_eq!(encoded[0], CHUNK_COMPRESSED);
+            assert!(encoded.len() < text.len() / 4);
+            assert_eq!(compressor.decode(encoded).unwrap(), &text[..]);
+        }
+    }
+
+    #[test]
+    fn test_small_and_incompressible_chunks_sent_raw() {
+        let compressor = Compressor::new(Compression::Zstd, 256).unwrap();
+        let small = compressor.encode(b"hello");
+        assert_eq!(&small[..], b"\x00hello");
+
+        let mut x = 0x9e37_79b9u32;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        let encoded = compressor.encode(&noise);
+        assert_eq!(encoded[0], CHUNK_RAW);
+        assert_eq!(compressor.decode(encoded).unwrap(), &noise[..]);
+    }
+
+    #[test]
+    fn test_stats() {
+        let compressor = Compressor::new(Compression::Lz4, 0).unwrap();
+        assert_eq!(compressor.stats().ratio(), 1.0);
+        compressor.encode(&[0u8; 4096]);
+        let stats = compressor.stats();
+        assert_eq!(stats.raw_bytes(), 4096);
+        assert!(stats.ratio() > 10.0);
+    }
+
+    #[test]
+    fn test_oversized_chunk_rejected() {
+        let compressor = Compressor::new(Compression::Lz4, 0).unwrap();
+        let bomb = frame(CHUNK_COMPRESSED, &lz4_flex::compress_prepend_size(&vec![0u8; 2 << 20]));
+        assert!(compressor.decode(bomb).is_err());
+        assert!(Compressor::new(Compression::None, 0).is_none());
+    }
+}
@@ -1,11 +1,20 @@
+use crate::reverse::ReverseRule;
+use crate::service::StreamRequest;
+use crate::shell::ShellKey;
+use crate::socks5::Socks5Config;
 use once_cell::sync::Lazy;
+use std::fmt;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::time::{sleep_until, Instant};
 use tracing::{error, trace};
 use webrtc::api::interceptor_registry::register_default_interceptors;
 use webrtc::api::media_engine::MediaEngine;
 use webrtc::api::APIBuilder;
 use webrtc::api::API;
+use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
+use webrtc::data_channel::RTCDataChannel;
 use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::interceptor::registry::Registry;
 use webrtc::peer_connection::configuration::RTCConfiguration;
@@ -53,6 +62,368 @@ impl IceServer {
     }
 }
 
+/// Reliability profile of the data channels carrying tunneled streams.
+///
+/// The profile is announced in the DCEP open message, so the proxy side
+/// automatically uses the same settings for its direction of the channel.
+/// Only [`Self::Reliable`] keeps the bridged byte streams intact. `Unordered`
+/// delivers every message but may reorder them, which corrupts a TCP, unix
+/// socket or stdio stream as silently as a lost message; partially reliable
+/// profiles may drop messages as well. They are only suitable for
+/// message-oriented protocols whose writes stand alone and that tolerate
+/// reordering or loss (e.g. teleop control loops).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChannelProfile {
+    /// Ordered and fully reliable
+    #[default]
+    Reliable,
+    /// Fully reliable, delivered out of order, which corrupts byte streams
+    Unordered,
+    /// Unordered, a message is abandoned after this many retransmits
+    MaxRetransmits(u16),
+    /// Unordered, a message is abandoned after this lifetime in milliseconds
+    MaxPacketLifetime(u16),
+}
+
+impl ChannelProfile {
+    pub fn to_init(self) -> RTCDataChannelInit {
+        let mut init = RTCDataChannelInit::default();
+        match self {
+            Self::Reliable => {}
+            Self::Unordered => init.ordered = Some(false),
+            Self::MaxRetransmits(n) => {
+                init.ordered = Some(false);
+                init.max_retransmits = Some(n);
+            }
+            Self::MaxPacketLifetime(ms) => {
+                init.ordered = Some(false);
+                init.max_packet_life_time = Some(ms);
+            }
+        }
+        init
+    }
+
+    /// Recover the profile of a channel opened by the remote peer
+    pub fn from_data_channel(dc: &RTCDataChannel) -> Self {
+        if let Some(n) = dc.max_retransmits() {
+            Self::MaxRetransmits(n)
+        } else if let Some(ms) = dc.max_packet_lifetime() {
+            Self::MaxPacketLifetime(ms)
+        } else if !dc.ordered() {
+            Self::Unordered
+        } else {
+            Self::Reliable
+        }
+    }
+
+    /// Low latency profiles also disable Nagle on the bridged TCP sockets
+    pub fn nodelay(self) -> bool {
+        self != Self::Reliable
+    }
+}
+
+impl fmt::Display for ChannelProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Reliable => write!(f, "reliable"),
+            Self::Unordered => write!(f, "unordered"),
+            Self::MaxRetransmits(n) => write!(f, "max-retransmits:{}", n),
+            Self::MaxPacketLifetime(ms) => write!(f, "max-lifetime:{}", ms),
+        }
+    }
+}
+
+impl FromStr for ChannelProfile {
+    type Err = anyhow::Error;
+
+    /// Parse `reliable`, `unordered`, `max-retransmits:<n>` or `max-lifetime:<ms>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = match s.split_once(':') {
+            Some((name, value)) => (name, Some(value)),
+            None => (s, None),
+        };
+        let value = || -> anyhow::Result<u16> {
+            let v = value.ok_or_else(|| anyhow::anyhow!("{} requires a value", name))?;
+            Ok(v.parse()?)
+        };
+        match name {
+            "reliable" => Ok(Self::Reliable),
+            "unordered" => Ok(Self::Unordered),
+            "max-retransmits" => Ok(Self::MaxRetransmits(value()?)),
+            "max-lifetime" => Ok(Self::MaxPacketLifetime(value()?)),
+            _ => Err(anyhow::anyhow!("Unknown channel profile: {}", s)),
+        }
+    }
+}
+
+/// How tunneled connections are mapped onto DataChannels
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StreamMode {
+    /// One DataChannel per connection, opened with a DCEP round trip
+    #[default]
+    Dedicated,
+    /// All connections share one long-lived, reliable DataChannel
+    Multiplexed,
+    /// Connections claim one of this many pre-negotiated DataChannels and fall
+    /// back to dedicated ones when the pool is exhausted
+    Pooled(u16),
+}
+
+impl fmt::Display for StreamMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dedicated => write!(f, "dedicated"),
+            Self::Multiplexed => write!(f, "mux"),
+            Self::Pooled(n) => write!(f, "pool:{}", n),
+        }
+    }
+}
+
+impl FromStr for StreamMode {
+    type Err = anyhow::Error;
+
+    /// Parse `dedicated`, `mux` or `pool:<n>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("pool", n)) => Ok(Self::Pooled(n.parse()?)),
+            None if s == "dedicated" => Ok(Self::Dedicated),
+            None if s == "mux" => Ok(Self::Multiplexed),
+            _ => Err(anyhow::anyhow!("Unknown stream mode: {}", s)),
+        }
+    }
+}
+
+/// Compression of tunneled data, negotiated between portal and proxy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Better ratio, for slow or metered links
+    Zstd,
+    /// Cheaper on CPU, for fast links
+    Lz4,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Zstd => write!(f, "zstd"),
+            Self::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    /// Parse `none`, `zstd` or `lz4`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "zstd" => Ok(Self::Zstd),
+            "lz4" => Ok(Self::Lz4),
+            _ => Err(anyhow::anyhow!("Unknown compression: {}", s)),
+        }
+    }
+}
+
+/// Token bucket limit on the bytes a peer sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub bytes_per_sec: u64,
+    /// Bytes that may be sent at once after being idle
+    pub burst: u64,
+}
+
+impl RateLimit {
+    /// A limit allowing a burst of one second worth of data
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self { bytes_per_sec, burst: bytes_per_sec }
+    }
+
+    pub fn with_burst(mut self, burst: u64) -> Self {
+        self.burst = burst;
+        self
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.bytes_per_sec, self.burst)
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = anyhow::Error;
+
+    /// Parse `<bytes per second>[/<burst>]`, sizes may carry a K, M or G suffix
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn size(s: &str) -> anyhow::Result<u64> {
+            let (digits, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+                Some((i, _)) => s.split_at(i),
+                None => (s, ""),
+            };
+            let multiplier = match unit.to_ascii_uppercase().as_str() {
+                "" => 1,
+                "K" => 1 << 10,
+                "M" => 1 << 20,
+                "G" => 1 << 30,
+                _ => return Err(anyhow::anyhow!("Unknown size unit: {}", unit)),
+            };
+            Ok(digits.parse::<u64>()? * multiplier)
+        }
+
+        match s.split_once('/') {
+            Some((rate, burst)) => Ok(Self::new(size(rate)?).with_burst(size(burst)?)),
+            None => Ok(Self::new(size(s)?)),
+        }
+    }
+}
+
+/// Idle timeout and maximum lifetime of a stream, portal or proxy session, both
+/// unlimited by default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Lifetime {
+    /// Time it may go without streams, or without traffic for a stream
+    pub idle_timeout: Option<Duration>,
+    /// Time it may live at all
+    pub max_lifetime: Option<Duration>,
+}
+
+impl Lifetime {
+    pub fn is_unlimited(&self) -> bool {
+        self.idle_timeout.is_none() && self.max_lifetime.is_none()
+    }
+
+    /// Wait until what started now ran out of its lifetime and tell why.
+    /// `idle_since` is when it went idle, `None` while it is busy.
+    pub(crate) async fn expired(&self, idle_since: impl Fn() -> Option<Instant>) -> String {
+        let end = self.max_lifetime.map(|max| Instant::now() + max);
+        loop {
+            let now = Instant::now();
+            if end.is_some_and(|end| end <= now) {
+                let max = self.max_lifetime.unwrap_or_default();
+                return format!("reached its maximum lifetime of {}s", max.as_secs());
+            }
+            let mut next = end;
+            if let Some(idle_timeout) = self.idle_timeout {
+                let idle_end = idle_since().unwrap_or(now) + idle_timeout;
+                if idle_end <= now {
+                    return format!("idle for {}s", idle_timeout.as_secs());
+                }
+                next = Some(next.map_or(idle_end, |end| end.min(idle_end)));
+            }
+            match next {
+                Some(next) => sleep_until(next).await,
+                None => return std::future::pending().await,
+            }
+        }
+    }
+}
+
+/// Per-portal options following a portal address as a query string, e.g.
+/// `127.0.0.1:9000?service=gps&profile=unordered&compress=zstd` or
+/// `127.0.0.1:1502?dest=192.168.1.50:502`. `127.0.0.1:1080?socks5` runs a SOCKS5
+/// server instead, `?socks5=<username>:<password>` with authentication, and
+/// `127.0.0.1:3128?http-proxy` an HTTP forward proxy.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AddrOptions {
+    /// Service of the proxy the connections are forwarded to
+    pub service: Option<String>,
+    /// `host:port` on the proxy's network the connections are forwarded to
+    pub dest: Option<String>,
+    /// Let SOCKS5 clients pick the destination of each connection
+    pub socks5: Option<Socks5Config>,
+    /// Let HTTP proxy clients pick the destination of each connection
+    pub http_proxy: bool,
+    pub channel_profile: Option<ChannelProfile>,
+    pub compression: Option<Compression>,
+    pub session_rate_limit: Option<RateLimit>,
+    pub stream_rate_limit: Option<RateLimit>,
+    /// Keep the listeners and reconnect once the connection is lost
+    pub persistent: bool,
+    /// Connect on the first connection instead of right away
+    pub lazy: bool,
+}
+
+impl AddrOptions {
+    /// Split the options off a portal address
+    pub fn parse(addr_uri: &str) -> anyhow::Result<(String, Self)> {
+        let mut options = Self::default();
+        let Some((addr, query)) = addr_uri.split_once('?') else {
+            return Ok((addr_uri.to_string(), options));
+        };
+        for option in query.split('&').filter(|o| !o.is_empty()) {
+            match option.split_once('=') {
+                Some(("service", value)) => options.service = Some(value.to_string()),
+                Some(("dest", value)) => options.dest = Some(value.to_string()),
+                Some(("socks5", value)) => options.socks5 = Some(value.parse()?),
+                None if option == "socks5" => options.socks5 = Some(Socks5Config::default()),
+                None if option == "http-proxy" => options.http_proxy = true,
+                None if option == "persistent" => options.persistent = true,
+                None if option == "lazy" => options.lazy = true,
+                Some(("profile", value)) => options.channel_profile = Some(value.parse()?),
+                Some(("compress", value)) => options.compression = Some(value.parse()?),
+                Some(("rate", value)) => options.session_rate_limit = Some(value.parse()?),
+                Some(("stream-rate", value)) => options.stream_rate_limit = Some(value.parse()?),
+                _ => return Err(anyhow::anyhow!("Unknown portal option: {}", option)),
+            }
+        }
+        let targets = [
+            options.service.is_some(),
+            options.dest.is_some(),
+            options.socks5.is_some(),
+            options.http_proxy,
+        ];
+        if targets.into_iter().filter(|t| *t).count() > 1 {
+            return Err(anyhow::anyhow!(
+                "service, dest, socks5 and http-proxy are exclusive: {}",
+                addr_uri
+            ));
+        }
+        Ok((addr.to_string(), options))
+    }
+
+    /// Whether any option applies to the whole peer connection
+    pub fn has_peer_options(&self) -> bool {
+        *self
+            != Self {
+                service: self.service.clone(),
+                dest: self.dest.clone(),
+                socks5: self.socks5.clone(),
+                http_proxy: self.http_proxy,
+                ..Default::default()
+            }
+    }
+
+    /// What the listener asks the proxy for on each stream
+    pub(crate) fn request(&self) -> StreamRequest {
+        match (&self.service, &self.dest) {
+            (_, Some(dest)) => StreamRequest::dest(dest),
+            (Some(service), None) => StreamRequest::service(service),
+            (None, None) => StreamRequest::default(),
+        }
+    }
+
+    pub fn apply(&self, config: &mut PeerConfig) {
+        if let Some(profile) = self.channel_profile {
+            config.channel_profile = profile;
+        }
+        if let Some(compression) = self.compression {
+            config.compression = compression;
+        }
+        if self.session_rate_limit.is_some() {
+            config.session_rate_limit = self.session_rate_limit;
+        }
+        if self.stream_rate_limit.is_some() {
+            config.stream_rate_limit = self.stream_rate_limit;
+        }
+        config.persistent |= self.persistent;
+        config.lazy |= self.lazy;
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PeerConfig {
     pub ice_servers: Vec<IceServer>,
@@ -60,6 +431,47 @@ pub struct PeerConfig {
     pub connect_timeout: Duration,
     pub datachannel_timeout: Duration,
     pub ice_gathering_timeout: Duration,
+    pub channel_profile: ChannelProfile,
+    pub stream_mode: StreamMode,
+    pub compression: Compression,
+    /// Chunks smaller than this many bytes are sent uncompressed
+    pub compression_threshold: usize,
+    /// Limit on all data a portal or proxy sends to its peer
+    pub session_rate_limit: Option<RateLimit>,
+    /// Limit on the data sent by each single stream
+    pub stream_rate_limit: Option<RateLimit>,
+    /// Proxy side: open the listeners portals ask for to forward back to them
+    pub reverse_forwarding: bool,
+    /// Proxy side: where portals may ask for those listeners, loopback TCP
+    /// addresses by default
+    pub reverse_allow: Vec<ReverseRule>,
+    /// Proxy side: streams each portal may have open at once, dedicated
+    /// DataChannels and streams of multiplexed and pooled ones alike, further
+    /// ones are refused. Also caps the channel pool.
+    pub max_channels: Option<usize>,
+    /// Portal side: key presented to the proxy's shell by `shell://` listeners
+    pub shell_key: Option<ShellKey>,
+    /// Portal side: keep the listeners of a lost connection and reconnect
+    pub persistent: bool,
+    /// Portal side: bind the listeners right away, connect on their first
+    /// connection and disconnect once idle
+    pub lazy: bool,
+    /// How long a lazy portal keeps its connection without streams
+    pub lazy_idle_timeout: Duration,
+    /// How long a connection to a lazy portal waits for it to connect
+    pub lazy_connect_timeout: Duration,
+    /// How long each stream may go without traffic, and live at all
+    pub stream_lifetime: Lifetime,
+    /// How long a portal or proxy session may go without streams, and live at
+    /// all, before it is closed
+    pub session_lifetime: Lifetime,
+    /// How long a disconnected connection may take to recover with an ICE
+    /// restart before it is closed, zero closes it right away
+    pub ice_restart_grace: Duration,
+    /// Interval of the pings measuring the link, zero disables them
+    pub keepalive_interval: Duration,
+    /// A link left this long without answering a ping counts as disconnected
+    pub keepalive_timeout: Duration,
 }
 
 impl Default for PeerConfig {
@@ -70,6 +482,25 @@ impl Default for PeerConfig {
             connect_timeout: Duration::from_secs(5),
             datachannel_timeout: Duration::from_secs(5),
             ice_gathering_timeout: Duration::from_secs(5),
+            channel_profile: ChannelProfile::default(),
+            stream_mode: StreamMode::default(),
+            compression: Compression::default(),
+            compression_threshold: 256,
+            session_rate_limit: None,
+            stream_rate_limit: None,
+            reverse_forwarding: false,
+            reverse_allow: ReverseRule::loopback(),
+            max_channels: None,
+            shell_key: None,
+            persistent: false,
+            lazy: false,
+            lazy_idle_timeout: Duration::from_secs(60),
+            lazy_connect_timeout: Duration::from_secs(10),
+            stream_lifetime: Lifetime::default(),
+            session_lifetime: Lifetime::default(),
+            ice_restart_grace: Duration::from_secs(15),
+            keepalive_interval: Duration::from_secs(1),
+            keepalive_timeout: Duration::from_secs(3),
         }
     }
 }
@@ -89,3 +520,118 @@ impl PeerConfig {
         RTCConfiguration { ice_servers, ..Default::default() }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_channel_profile() {
+        assert_eq!("reliable".parse::<ChannelProfile>().unwrap(), ChannelProfile::Reliable);
+        assert_eq!("unordered".parse::<ChannelProfile>().unwrap(), ChannelProfile::Unordered);
+        assert_eq!(
+            "max-retransmits:0".parse::<ChannelProfile>().unwrap(),
+            ChannelProfile::MaxRetransmits(0)
+        );
+        assert_eq!(
+            "max-lifetime:150".parse::<ChannelProfile>().unwrap(),
+            ChannelProfile::MaxPacketLifetime(150)
+        );
+        assert!("max-lifetime".parse::<ChannelProfile>().is_err());
+        assert!("fast".parse::<ChannelProfile>().is_err());
+    }
+
+    #[test]
+    fn test_parse_stream_mode() {
+        assert_eq!("dedicated".parse::<StreamMode>().unwrap(), StreamMode::Dedicated);
+        assert_eq!("mux".parse::<StreamMode>().unwrap(), StreamMode::Multiplexed);
+        assert_eq!("pool:16".parse::<StreamMode>().unwrap(), StreamMode::Pooled(16));
+        assert!("pool".parse::<StreamMode>().is_err());
+        assert!("pooled".parse::<StreamMode>().is_err());
+    }
+
+    #[test]
+    fn test_parse_rate_limit() {
+        assert_eq!("1000".parse::<RateLimit>().unwrap(), RateLimit::new(1000));
+        assert_eq!(
+            "2M/64k".parse::<RateLimit>().unwrap(),
+            RateLimit::new(2 * 1024 * 1024).with_burst(64 * 1024)
+        );
+        assert!("2MB".parse::<RateLimit>().is_err());
+        assert!("fast".parse::<RateLimit>().is_err());
+    }
+
+    #[test]
+    fn test_parse_addr_options() {
+        let (addr, options) = AddrOptions::parse("127.0.0.1:9000?profile=unordered").unwrap();
+        assert_eq!(addr, "127.0.0.1:9000");
+        assert_eq!(options.channel_profile, Some(ChannelProfile::Unordered));
+        assert!(options.has_peer_options());
+
+        let mut config = PeerConfig::default();
+        let (_, options) =
+            AddrOptions::parse("127.0.0.1:9000?compress=lz4&rate=1M&stream-rate=256K").unwrap();
+        options.apply(&mut config);
+        assert_eq!(config.channel_profile, ChannelProfile::Reliable);
+        assert_eq!(config.compression, Compression::Lz4);
+        assert_eq!(config.session_rate_limit, Some(RateLimit::new(1 << 20)));
+        assert_eq!(config.stream_rate_limit, Some(RateLimit::new(256 << 10)));
+
+        let (addr, options) = AddrOptions::parse("unix:///tmp/a.sock?service=gps").unwrap();
+        assert_eq!(addr, "unix:///tmp/a.sock");
+        assert_eq!(options.service.as_deref(), Some("gps"));
+        assert!(!options.has_peer_options());
+
+        let (addr, options) = AddrOptions::parse("127.0.0.1:1502?dest=192.168.1.50:502").unwrap();
+        assert_eq!(addr, "127.0.0.1:1502");
+        assert_eq!(options.request().target(), "192.168.1.50:502");
+        assert!(!options.has_peer_options());
+        assert!(AddrOptions::parse("127.0.0.1:1502?service=gps&dest=10.0.0.1:22").is_err());
+
+        let (_, options) = AddrOptions::parse("127.0.0.1:1080?socks5&compress=lz4").unwrap();
+        assert_eq!(options.socks5, Some(Socks5Config::default()));
+        assert!(options.has_peer_options());
+        let (_, options) = AddrOptions::parse("127.0.0.1:1080?socks5=alice:secret").unwrap();
+        assert!(options.socks5.unwrap().credentials.is_some());
+        assert!(AddrOptions::parse("127.0.0.1:1080?socks5&dest=10.0.0.1:22").is_err());
+
+        let (_, options) = AddrOptions::parse("127.0.0.1:3128?http-proxy").unwrap();
+        assert!(options.http_proxy);
+        assert!(!options.has_peer_options());
+        assert!(AddrOptions::parse("127.0.0.1:3128?http-proxy&socks5").is_err());
+
+        let mut config = PeerConfig::default();
+        let (_, options) = AddrOptions::parse("127.0.0.1:9000?service=gps&lazy").unwrap();
+        assert!(options.has_peer_options());
+        options.apply(&mut config);
+        assert!(config.lazy);
+
+        assert!(AddrOptions::parse("127.0.0.1:9000?compress=gzip").is_err());
+        assert!(AddrOptions::parse("127.0.0.1:9000?speed=fast").is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lifetime_expiry() {
+        let lifetime = Lifetime {
+            idle_timeout: Some(Duration::from_secs(5)),
+            max_lifetime: Some(Duration::from_secs(60)),
+        };
+        let started = Instant::now();
+        let reason = lifetime.expired(|| Some(started)).await;
+        assert_eq!(reason, "idle for 5s");
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+
+        // Busy the whole time, only the maximum lifetime applies
+        let started = Instant::now();
+        let reason = lifetime.expired(|| None).await;
+        assert_eq!(reason, "reached its maximum lifetime of 60s");
+        assert_eq!(started.elapsed(), Duration::from_secs(60));
+
+        // Idle again later, the timeout counts from then
+        let started = Instant::now();
+        let idle_since = started + Duration::from_secs(12);
+        let reason = lifetime.expired(|| (Instant::now() >= idle_since).then_some(idle_since));
+        assert_eq!(reason.await, "idle for 5s");
+        assert_eq!(started.elapsed(), Duration::from_secs(17));
+    }
+}
@@ -0,0 +1,311 @@
+//! Control protocol on the DEFAULT DataChannel the portal opens with every
+//! connection.
+//!
+//! Messages are text, `<type>:<args>`. Both sides send a `ping:<seq>` every
+//! keepalive interval and answer each ping with `pong:<seq>`, which measures
+//! the round-trip time, its jitter and the share of lost pings. Once the remote
+//! answered a ping, a link left without pongs for the keepalive timeout is
+//! reported disconnected long before ICE consent freshness notices, and closed
+//! unless it recovers within the ICE restart grace period. Unknown message
+//! types are ignored, so new control messages don't break older peers.
+
+use crate::config::PeerConfig;
+use crate::peer::PeerEvent;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex as StdMutex, Weak};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{interval, Instant, MissedTickBehavior};
+use tracing::{debug, info, trace, warn};
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::peer_connection::RTCPeerConnection;
+
+pub(crate) const CONTROL_LABEL: &str = "DEFAULT";
+
+const PING: &str = "ping";
+const PONG: &str = "pong";
+
+/// Round-trip figures of a link, measured by the pings of the control channel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkStats {
+    /// Round-trip time of the last answered ping
+    pub rtt: Duration,
+    /// Smoothed round-trip time, as TCP estimates it
+    pub smoothed_rtt: Duration,
+    /// Mean deviation between consecutive round-trip times
+    pub jitter: Duration,
+    /// Share of pings left unanswered for the keepalive timeout, 0.0 to 1.0
+    pub loss: f64,
+    pub pings_sent: u64,
+    pub pongs_received: u64,
+}
+
+impl fmt::Display for LinkStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rtt {:.1}ms (smoothed {:.1}ms, jitter {:.1}ms), loss {:.1}% of {} pings",
+            self.rtt.as_secs_f64() * 1000.0,
+            self.smoothed_rtt.as_secs_f64() * 1000.0,
+            self.jitter.as_secs_f64() * 1000.0,
+            self.loss * 100.0,
+            self.pings_sent
+        )
+    }
+}
+
+/// When to ping and when to give up on a silent link
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Keepalive {
+    /// Zero disables pings
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub grace: Duration,
+}
+
+impl Keepalive {
+    pub fn from_config(config: &PeerConfig) -> Self {
+        Self {
+            interval: config.keepalive_interval,
+            timeout: config.keepalive_timeout,
+            grace: config.ice_restart_grace,
+        }
+    }
+}
+
+/// Pings in flight and what their pongs measured
+#[derive(Default)]
+struct Probe {
+    next_seq: u64,
+    /// Sent pings awaiting their pong, oldest first
+    pending: VecDeque<(u64, Instant)>,
+    sent: u64,
+    received: u64,
+    lost: u64,
+    rtt: Option<Duration>,
+    smoothed_rtt: Duration,
+    jitter: Duration,
+    last_pong: Option<Instant>,
+    /// Whether the silent link was reported disconnected
+    reported: bool,
+}
+
+impl Probe {
+    fn ping(&mut self, now: Instant) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.sent += 1;
+        self.pending.push_back((seq, now));
+        seq
+    }
+
+    /// Record the pong to `seq`, false if no such ping is pending
+    fn pong(&mut self, seq: u64, now: Instant) -> bool {
+        let Some(i) = self.pending.iter().position(|(s, _)| *s == seq) else {
+            return false;
+        };
+        let (_, sent_at) = self.pending.remove(i).unwrap();
+        let rtt = now - sent_at;
+        match self.rtt {
+            // RFC 6298 smoothing and RFC 3550 jitter
+            Some(last) => {
+                self.smoothed_rtt = (self.smoothed_rtt * 7 + rtt) / 8;
+                let delta = if rtt > last { rtt - last } else { last - rtt };
+                self.jitter = (self.jitter * 15 + delta) / 16;
+            }
+            None => self.smoothed_rtt = rtt,
+        }
+        self.rtt = Some(rtt);
+        self.received += 1;
+        self.last_pong = Some(now);
+        true
+    }
+
+    /// Count pings older than `timeout` as lost
+    fn expire(&mut self, now: Instant, timeout: Duration) {
+        while let Some((_, sent_at)) = self.pending.front() {
+            if now - *sent_at < timeout {
+                break;
+            }
+            self.pending.pop_front();
+            self.lost += 1;
+        }
+    }
+
+    fn stats(&self) -> Option<LinkStats> {
+        let answered = self.received + self.lost;
+        Some(LinkStats {
+            rtt: self.rtt?,
+            smoothed_rtt: self.smoothed_rtt,
+            jitter: self.jitter,
+            loss: if answered == 0 { 0.0 } else { self.lost as f64 / answered as f64 },
+            pings_sent: self.sent,
+            pongs_received: self.received,
+        })
+    }
+
+    /// How long the remote has been silent, `None` until it answered once
+    fn silence(&self, now: Instant) -> Option<Duration> {
+        self.last_pong.map(|t| now - t)
+    }
+}
+
+/// The control side of one peer connection
+pub(crate) struct Control {
+    dc: Arc<RTCDataChannel>,
+    probe: StdMutex<Probe>,
+}
+
+impl Control {
+    /// Serve the control protocol on `dc` and start pinging once it is open. A
+    /// silent link is reported on `event_tx`, then `pc` is closed.
+    pub fn new(
+        dc: Arc<RTCDataChannel>,
+        pc: Weak<RTCPeerConnection>,
+        keepalive: Keepalive,
+        event_tx: mpsc::UnboundedSender<PeerEvent>,
+        remote_id: &str,
+    ) -> Arc<Self> {
+        let control = Arc::new(Self { dc: Arc::clone(&dc), probe: StdMutex::default() });
+
+        let weak = Arc::downgrade(&control);
+        dc.on_message(Box::new(move |msg: DataChannelMessage| {
+            let control = weak.upgrade();
+            Box::pin(async move {
+                if let Some(control) = control {
+                    control.handle_message(&msg.data).await;
+                }
+            })
+        }));
+
+        if !keepalive.interval.is_zero() {
+            let weak = Arc::downgrade(&control);
+            let remote_id = remote_id.to_string();
+            let start = move || {
+                tokio::spawn(Self::keepalive(weak, pc, keepalive, event_tx, remote_id));
+            };
+            if dc.ready_state() == RTCDataChannelState::Open {
+                start();
+            } else {
+                let start = StdMutex::new(Some(start));
+                dc.on_open(Box::new(move || {
+                    if let Some(start) = start.lock().unwrap().take() {
+                        start();
+                    }
+                    Box::pin(async {})
+                }));
+            }
+        }
+        control
+    }
+
+    /// Round-trip figures, `None` until the remote answered a ping
+    pub fn stats(&self) -> Option<LinkStats> {
+        self.probe.lock().unwrap().stats()
+    }
+
+    async fn handle_message(&self, data: &[u8]) {
+        let text = String::from_utf8_lossy(data);
+        let (kind, args) = text.split_once(':').unwrap_or((&text, ""));
+        match kind {
+            PING => {
+                if let Err(e) = self.dc.send_text(format!("{}:{}", PONG, args)).await {
+                    trace!("{} pong failed: {}", self.dc.label(), e);
+                }
+            }
+            PONG => {
+                let Ok(seq) = args.parse() else {
+                    return;
+                };
+                let mut probe = self.probe.lock().unwrap();
+                if probe.pong(seq, Instant::now()) && probe.reported {
+                    probe.reported = false;
+                    info!("{} answering pings again", self.dc.label());
+                }
+            }
+            _ => trace!("{} ignored control message: {}", self.dc.label(), text),
+        }
+    }
+
+    /// Ping every interval until the channel or the control is gone
+    async fn keepalive(
+        control: Weak<Self>,
+        pc: Weak<RTCPeerConnection>,
+        keepalive: Keepalive,
+        event_tx: mpsc::UnboundedSender<PeerEvent>,
+        remote_id: String,
+    ) {
+        let mut ticks = interval(keepalive.interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            let Some(control) = control.upgrade() else {
+                return;
+            };
+            let now = Instant::now();
+            let (seq, silence, report) = {
+                let mut probe = control.probe.lock().unwrap();
+                probe.expire(now, keepalive.timeout);
+                let silence = probe.silence(now).filter(|s| *s >= keepalive.timeout);
+                let report = silence.is_some() && !probe.reported;
+                probe.reported |= report;
+                (probe.ping(now), silence, report)
+            };
+
+            if report && !keepalive.grace.is_zero() {
+                warn!("No pong from {} for {}ms", remote_id, keepalive.timeout.as_millis());
+                let _ = event_tx.send(PeerEvent::Disconnected { remote_id: remote_id.clone() });
+            }
+            if silence.is_some_and(|s| s >= keepalive.timeout + keepalive.grace) {
+                warn!("Link to {} is dead, closing it", remote_id);
+                if let Some(pc) = pc.upgrade() {
+                    let _ = pc.close().await;
+                }
+                return;
+            }
+
+            if let Err(e) = control.dc.send_text(format!("{}:{}", PING, seq)).await {
+                debug!("{} stopped pinging: {}", control.dc.label(), e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_probe_stats() {
+        let mut probe = Probe::default();
+        let start = Instant::now();
+        assert_eq!(probe.stats(), None);
+
+        let a = probe.ping(start);
+        let b = probe.ping(start + Duration::from_millis(100));
+        let c = probe.ping(start + Duration::from_millis(200));
+        assert!(probe.pong(a, start + Duration::from_millis(20)));
+        assert!(probe.pong(b, start + Duration::from_millis(140)));
+        assert!(!probe.pong(b, start + Duration::from_millis(150)));
+        assert!(!probe.pong(42, start + Duration::from_millis(150)));
+
+        probe.expire(start + Duration::from_millis(3200), Duration::from_secs(3));
+        assert!(!probe.pong(c, start + Duration::from_millis(3300)));
+
+        let stats = probe.stats().unwrap();
+        assert_eq!(stats.rtt, Duration::from_millis(40));
+        assert_eq!(stats.smoothed_rtt, Duration::from_millis(22) + Duration::from_micros(500));
+        assert_eq!(stats.jitter, Duration::from_micros(1250));
+        assert_eq!(stats.pings_sent, 3);
+        assert_eq!(stats.pongs_received, 2);
+        assert!((stats.loss - 1.0 / 3.0).abs() < 1e-9);
+        assert_eq!(
+            probe.silence(start + Duration::from_millis(1140)),
+            Some(Duration::from_secs(1))
+        );
+    }
+}
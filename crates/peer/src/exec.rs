@@ -0,0 +1,162 @@
+//! Commands as stream endpoints, inetd style.
+//!
+//! An `exec://<command> [<args>...]` target spawns the command for each stream
+//! and bridges its stdin and stdout, stderr goes to the proxy's own. Arguments
+//! are split on whitespace, single or double quotes keep them together and a
+//! backslash escapes the next character. The child learns about its stream from
+//! `LRC_SERVICE` and `LRC_STREAM` in its environment and is killed once the
+//! stream closed.
+
+use anyhow::{anyhow, Result};
+use std::io;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+
+pub(crate) const EXEC_SCHEME: &str = "exec://";
+
+/// The command and arguments of an `exec://` target
+pub(crate) fn parse(addr_uri: &str) -> Result<Vec<String>> {
+    let command =
+        addr_uri.strip_prefix(EXEC_SCHEME).ok_or_else(|| anyhow!("Not a command: {}", addr_uri))?;
+    split(command)
+}
+
+/// Split a command line into the command and its arguments
+pub(crate) fn split(command: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', Some('\'')) => arg.get_or_insert_with(String::new).push(c),
+            ('\\', _) => {
+                let escaped =
+                    chars.next().ok_or_else(|| anyhow!("Trailing '\\' in {}", command))?;
+                arg.get_or_insert_with(String::new).push(escaped);
+            }
+            (c, Some(q)) if c == q => quote = None,
+            (c, Some(_)) => arg.get_or_insert_with(String::new).push(c),
+            ('\'' | '"', None) => {
+                quote = Some(c);
+                arg.get_or_insert_with(String::new);
+            }
+            (c, None) if c.is_whitespace() => args.extend(arg.take()),
+            (c, None) => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(anyhow!("Unclosed quote in {}", command));
+    }
+    args.extend(arg);
+    if args.is_empty() {
+        return Err(anyhow!("No command in '{}'", command));
+    }
+    Ok(args)
+}
+
+/// A running command for one stream
+pub(crate) struct Process {
+    child: Child,
+    /// Dropped on shutdown, which the command reads as end of input
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout,
+}
+
+impl Process {
+    pub fn spawn(addr_uri: &str, service: &str, stream: &str) -> Result<Self> {
+        let args = parse(addr_uri)?;
+        let mut child = Command::new(&args[0])
+            .args(&args[1..])
+            .env("LRC_SERVICE", service)
+            .env("LRC_STREAM", stream)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow!("Failed to run {}: {}", args[0], e))?;
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take().ok_or_else(|| anyhow!("No stdout of {}", args[0]))?;
+        Ok(Self { child, stdin, stdout })
+    }
+}
+
+impl AsyncRead for Process {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Process {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.stdin.as_mut() {
+            Some(stdin) => Pin::new(stdin).poll_write(cx, buf),
+            None => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.stdin.as_mut() {
+            Some(stdin) => Pin::new(stdin).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    /// The stream closed, nobody is left to read the output
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.stdin = None;
+        let _ = self.child.start_kill();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse("exec:///usr/bin/tool").unwrap(), ["/usr/bin/tool"]);
+        assert_eq!(
+            parse("exec://journalctl  -u 'robot driver' -n\\ 10 \"\"").unwrap(),
+            ["journalctl", "-u", "robot driver", "-n 10", ""]
+        );
+        assert_eq!(
+            parse(r#"exec://sh -c 'echo \n' "a\"b""#).unwrap(),
+            ["sh", "-c", "echo \\n", "a\"b"]
+        );
+
+        assert!(parse("exec://").is_err());
+        assert!(parse("exec://sh -c 'echo").is_err());
+        assert!(parse("exec://sh \\").is_err());
+        assert!(parse("/usr/bin/tool").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_process_bridges_stdio() {
+        let mut process =
+            Process::spawn("exec://sh -c 'echo $LRC_SERVICE; exec cat'", "diag", "s1").unwrap();
+        process.write_all(b"ping\n").await.unwrap();
+        let mut output = [0u8; 10];
+        process.read_exact(&mut output).await.unwrap();
+        assert_eq!(&output, b"diag\nping\n");
+
+        process.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        process.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+}
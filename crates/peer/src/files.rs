@@ -0,0 +1,521 @@
+//! File transfer between portals and proxies.
+//!
+//! A proxy with file roots configured serves the reserved `@files` service.
+//! Remote paths are `<root>/<relative path>`, roots are read-only unless
+//! configured writable. Each stream carries one transfer: requests and replies
+//! are JSON lines, file data follows them raw.
+//!
+//! - pull: the portal sends `{"op":"get","path":..,"offset":O,"sha256":..}` with
+//!   the hash of the `O` bytes it already has. The proxy answers with the file
+//!   size and where it resumes, `O` if the hashes match and 0 otherwise, then
+//!   sends the rest of the file and the hash of the whole file.
+//! - push: the portal sends `{"op":"put","path":..,"size":S}`. The proxy answers
+//!   with the size and hash of the part it kept from an interrupted push, the
+//!   portal picks where to resume the same way, sends the rest of the file and
+//!   its hash. The proxy checks it and moves the file in place.
+//!
+//! Errors are answered as `{"error":..}`. Partial files are kept next to the
+//! target as `<name>.part` on the portal and `.<name>.part` on the proxy.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite,
+    AsyncWriteExt, BufReader, DuplexStream,
+};
+use tracing::{debug, info};
+
+pub(crate) const FILES_SERVICE: &str = "@files";
+
+const BUFFER_SIZE: usize = 64 * 1024;
+const MAX_LINE: u64 = 64 * 1024;
+
+/// A directory a proxy shares as `<name>/...`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRoot {
+    pub name: String,
+    pub path: PathBuf,
+    /// Whether portals may push files into it
+    pub writable: bool,
+}
+
+/// `<name>=<path>[:ro|:rw]`, read-only by default
+impl FromStr for FileRoot {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, path) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid file root, expected <name>=<path>[:ro|:rw]: {}", s))?;
+        let (path, writable) = match path.rsplit_once(':') {
+            Some((path, "rw")) => (path, true),
+            Some((path, "ro")) => (path, false),
+            _ => (path, false),
+        };
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            return Err(anyhow!("Invalid file root name: {}", name));
+        }
+        if path.is_empty() {
+            return Err(anyhow!("File root without path: {}", s));
+        }
+        Ok(Self { name: name.to_string(), path: PathBuf::from(path), writable })
+    }
+}
+
+impl fmt::Display for FileRoot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = if self.writable { "rw" } else { "ro" };
+        write!(f, "{}={}:{}", self.name, self.path.display(), mode)
+    }
+}
+
+impl FileRoot {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if !self.path.is_dir() {
+            return Err(anyhow!("File root {} is not a directory", self.path.display()));
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of a pull or push
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transfer {
+    /// Size of the file
+    pub size: u64,
+    /// Bytes kept from an earlier, interrupted transfer
+    pub resumed: u64,
+}
+
+impl fmt::Display for Transfer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes", self.size)?;
+        if self.resumed > 0 {
+            write!(f, " (resumed at {})", self.resumed)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Op {
+    Get,
+    Put,
+}
+
+/// Requests and replies, with the fields each step needs
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Message {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    op: Option<Op>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    offset: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Message {
+    fn error(e: &anyhow::Error) -> Self {
+        Self { error: Some(e.to_string()), ..Default::default() }
+    }
+}
+
+async fn send<W: AsyncWrite + Unpin>(writer: &mut W, message: &Message) -> Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// The next message, errors the proxy answered with included
+async fn receive<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Message> {
+    let mut line = String::new();
+    (&mut *reader).take(MAX_LINE).read_line(&mut line).await?;
+    if !line.ends_with('\n') {
+        return Err(anyhow!("File transfer stream closed"));
+    }
+    let message: Message = serde_json::from_str(&line)?;
+    match message.error {
+        Some(e) => Err(anyhow!(e)),
+        None => Ok(message),
+    }
+}
+
+fn hex(hasher: &Sha256) -> String {
+    hasher.clone().finalize().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hash of the first `len` bytes of `file`, which is left positioned after them
+async fn hash_prefix(file: &mut File, len: u64) -> Result<Sha256> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    let mut left = len;
+    while left > 0 {
+        let n = file.read(&mut buf[..BUFFER_SIZE.min(left as usize)]).await?;
+        if n == 0 {
+            return Err(anyhow!("File shorter than {} bytes", len));
+        }
+        hasher.update(&buf[..n]);
+        left -= n as u64;
+    }
+    Ok(hasher)
+}
+
+/// Copy exactly `len` bytes, hashing them along the way
+async fn copy_hashed<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    len: u64,
+    hasher: &mut Sha256,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    let mut left = len;
+    while left > 0 {
+        let n = reader.read(&mut buf[..BUFFER_SIZE.min(left as usize)]).await?;
+        if n == 0 {
+            return Err(anyhow!("Transfer ended {} bytes early", left));
+        }
+        hasher.update(&buf[..n]);
+        writer.write_all(&buf[..n]).await?;
+        left -= n as u64;
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Where partial data of `path` is kept, `prefix` marks it hidden
+fn part_path(path: &Path, prefix: &str) -> Result<PathBuf> {
+    let name = path.file_name().ok_or_else(|| anyhow!("No file name in {}", path.display()))?;
+    Ok(path.with_file_name(format!("{}{}.part", prefix, name.to_string_lossy())))
+}
+
+/// Open the partial file of a transfer, returns it positioned after its first
+/// `resume` bytes or truncated if it can't be resumed
+async fn open_part(path: &Path, resume: u64) -> Result<File> {
+    let mut file =
+        OpenOptions::new().create(true).write(true).truncate(resume == 0).open(path).await?;
+    file.set_len(resume).await?;
+    file.seek(std::io::SeekFrom::Start(resume)).await?;
+    Ok(file)
+}
+
+/// Size and hash of what an earlier transfer left in `part`, nothing if that is
+/// more than `max` bytes
+async fn existing_part(part: &Path, max: u64) -> Result<(u64, Sha256)> {
+    let len = match fs::metadata(part).await {
+        Ok(metadata) if metadata.len() <= max => metadata.len(),
+        _ => return Ok((0, Sha256::new())),
+    };
+    let mut file = File::open(part).await?;
+    Ok((len, hash_prefix(&mut file, len).await?))
+}
+
+/// Start serving one transfer, the returned end carries its stream
+pub(crate) fn open(roots: &[FileRoot], stream: &str) -> DuplexStream {
+    let (socket, session) = tokio::io::duplex(BUFFER_SIZE);
+    let roots = roots.to_vec();
+    let stream = stream.to_string();
+    tokio::spawn(async move {
+        let (reader, mut writer) = tokio::io::split(session);
+        let mut reader = BufReader::new(reader);
+        if let Err(e) = serve(&roots, &mut reader, &mut writer).await {
+            debug!("File transfer {} failed: {}", stream, e);
+            let _ = send(&mut writer, &Message::error(&e)).await;
+        }
+        let _ = writer.shutdown().await;
+    });
+    socket
+}
+
+async fn serve<R, W>(roots: &[FileRoot], reader: &mut R, writer: &mut W) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let request = receive(reader).await?;
+    let path = request.path.as_deref().ok_or_else(|| anyhow!("Request without path"))?;
+    match request.op {
+        Some(Op::Get) => {
+            let path = resolve(roots, path, false).await?;
+            serve_get(&path, request, writer).await
+        }
+        Some(Op::Put) => {
+            let size = request.size.ok_or_else(|| anyhow!("Push without size"))?;
+            let path = resolve(roots, path, true).await?;
+            serve_put(&path, size, reader, writer).await
+        }
+        None => Err(anyhow!("Request without op")),
+    }
+}
+
+/// Local path of `<root>/<relative path>`, which must stay inside the root
+async fn resolve(roots: &[FileRoot], path: &str, write: bool) -> Result<PathBuf> {
+    let (name, relative) = path.split_once('/').unwrap_or((path, ""));
+    let root = roots
+        .iter()
+        .find(|r| r.name == name)
+        .ok_or_else(|| anyhow!("Unknown file root: {}", name))?;
+    if write && !root.writable {
+        return Err(anyhow!("File root {} is read-only", name));
+    }
+    let relative = Path::new(relative);
+    if relative.as_os_str().is_empty()
+        || !relative.components().all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(anyhow!("Invalid path: {}", path));
+    }
+
+    // Symbolic links may still point out of the root
+    let full = root.path.join(relative);
+    let parent = full.parent().ok_or_else(|| anyhow!("Invalid path: {}", path))?;
+    let root_dir = fs::canonicalize(&root.path).await?;
+    let checked =
+        if write { fs::canonicalize(parent).await } else { fs::canonicalize(&full).await }
+            .map_err(|e| anyhow!("{}: {}", path, e))?;
+    if !checked.starts_with(&root_dir) {
+        return Err(anyhow!("Path leaves its file root: {}", path));
+    }
+    Ok(full)
+}
+
+async fn serve_get<W: AsyncWrite + Unpin>(
+    path: &Path,
+    request: Message,
+    writer: &mut W,
+) -> Result<()> {
+    let mut file = File::open(path).await?;
+    let size = file.metadata().await?.len();
+
+    let mut offset = request.offset.unwrap_or(0).min(size);
+    let mut hasher = hash_prefix(&mut file, offset).await?;
+    if request.sha256.as_deref() != Some(hex(&hasher).as_str()) {
+        offset = 0;
+        hasher = Sha256::new();
+        file.rewind().await?;
+    }
+    send(writer, &Message { size: Some(size), offset: Some(offset), ..Default::default() }).await?;
+
+    copy_hashed(&mut file, writer, size - offset, &mut hasher).await?;
+    send(writer, &Message { sha256: Some(hex(&hasher)), ..Default::default() }).await?;
+    info!("Sent {}: {}", path.display(), Transfer { size, resumed: offset });
+    Ok(())
+}
+
+async fn serve_put<R, W>(path: &Path, size: u64, reader: &mut R, writer: &mut W) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let part = part_path(path, ".")?;
+    let (kept, hasher) = existing_part(&part, size).await?;
+    let reply = Message { offset: Some(kept), sha256: Some(hex(&hasher)), ..Default::default() };
+    send(writer, &reply).await?;
+
+    let offset = receive(reader).await?.offset.unwrap_or(0);
+    let mut hasher = match offset {
+        0 => Sha256::new(),
+        o if o == kept => hasher,
+        o => return Err(anyhow!("Can't resume at {}, {} bytes kept", o, kept)),
+    };
+    let mut file = open_part(&part, offset).await?;
+    copy_hashed(reader, &mut file, size - offset, &mut hasher).await?;
+    file.sync_all().await?;
+
+    let expected = receive(reader).await?.sha256;
+    if expected.as_deref() != Some(hex(&hasher).as_str()) {
+        let _ = fs::remove_file(&part).await;
+        return Err(anyhow!("Checksum mismatch for {}", path.display()));
+    }
+    fs::rename(&part, path).await?;
+    send(writer, &Message { size: Some(size), ..Default::default() }).await?;
+    info!("Received {}: {}", path.display(), Transfer { size, resumed: offset });
+    Ok(())
+}
+
+/// Copy `remote_path` of the proxy to `local_path` over `stream`
+pub(crate) async fn pull<S>(stream: S, remote_path: &str, local_path: &Path) -> Result<Transfer>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+
+    let part = part_path(local_path, "")?;
+    let (kept, hasher) = existing_part(&part, u64::MAX).await?;
+    let request = Message {
+        op: Some(Op::Get),
+        path: Some(remote_path.to_string()),
+        offset: Some(kept),
+        sha256: Some(hex(&hasher)),
+        ..Default::default()
+    };
+    send(&mut writer, &request).await?;
+
+    let reply = receive(&mut reader).await?;
+    let size = reply.size.ok_or_else(|| anyhow!("Reply without size"))?;
+    let offset = reply.offset.unwrap_or(0);
+    let mut hasher = match offset {
+        0 => Sha256::new(),
+        o if o == kept => hasher,
+        o => return Err(anyhow!("Proxy resumed at {}, {} bytes kept", o, kept)),
+    };
+    debug!("Pulling {} from {} of {} bytes", remote_path, offset, size);
+
+    let mut file = open_part(&part, offset).await?;
+    copy_hashed(&mut reader, &mut file, size - offset, &mut hasher).await?;
+    file.sync_all().await?;
+    let expected = receive(&mut reader).await?.sha256;
+    if expected.as_deref() != Some(hex(&hasher).as_str()) {
+        let _ = fs::remove_file(&part).await;
+        return Err(anyhow!("Checksum mismatch for {}", remote_path));
+    }
+    fs::rename(&part, local_path).await?;
+    Ok(Transfer { size, resumed: offset })
+}
+
+/// Copy `local_path` to `remote_path` of the proxy over `stream`
+pub(crate) async fn push<S>(stream: S, local_path: &Path, remote_path: &str) -> Result<Transfer>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+
+    let mut file = File::open(local_path).await?;
+    let size = file.metadata().await?.len();
+    let request = Message {
+        op: Some(Op::Put),
+        path: Some(remote_path.to_string()),
+        size: Some(size),
+        ..Default::default()
+    };
+    send(&mut writer, &request).await?;
+
+    let reply = receive(&mut reader).await?;
+    let kept = reply.offset.unwrap_or(0).min(size);
+    let mut hasher = hash_prefix(&mut file, kept).await?;
+    let offset = if reply.sha256.as_deref() == Some(hex(&hasher).as_str()) {
+        kept
+    } else {
+        hasher = Sha256::new();
+        file.rewind().await?;
+        0
+    };
+    debug!("Pushing {} from {} of {} bytes", remote_path, offset, size);
+    send(&mut writer, &Message { offset: Some(offset), ..Default::default() }).await?;
+
+    copy_hashed(&mut file, &mut writer, size - offset, &mut hasher).await?;
+    send(&mut writer, &Message { sha256: Some(hex(&hasher)), ..Default::default() }).await?;
+    receive(&mut reader).await?;
+    Ok(Transfer { size, resumed: offset })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lrc-files-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_parse_file_root() {
+        let root: FileRoot = "logs=/var/log/robot".parse().unwrap();
+        assert_eq!(root.path, PathBuf::from("/var/log/robot"));
+        assert!(!root.writable);
+        let root: FileRoot = "maps=C:\\maps:rw".parse().unwrap();
+        assert_eq!(root.path, PathBuf::from("C:\\maps"));
+        assert!(root.writable);
+        assert_eq!(root.to_string(), "maps=C:\\maps:rw");
+
+        assert!("/var/log".parse::<FileRoot>().is_err());
+        assert!("=/var/log".parse::<FileRoot>().is_err());
+        assert!("a/b=/var/log".parse::<FileRoot>().is_err());
+        assert!("logs=".parse::<FileRoot>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_stays_in_root() {
+        let dir = temp_dir("resolve");
+        std::fs::write(dir.join("a.log"), b"a").unwrap();
+        let roots = [FileRoot { name: "logs".into(), path: dir.clone(), writable: false }];
+
+        assert_eq!(resolve(&roots, "logs/a.log", false).await.unwrap(), dir.join("a.log"));
+        assert!(resolve(&roots, "logs/a.log", true)
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("read-only"));
+        assert!(resolve(&roots, "logs/../etc/passwd", false).await.is_err());
+        assert!(resolve(&roots, "logs//etc/passwd", false).await.is_err());
+        assert!(resolve(&roots, "logs", false).await.is_err());
+        assert!(resolve(&roots, "other/a.log", false).await.is_err());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("/etc/passwd", dir.join("escape")).unwrap();
+            assert!(resolve(&roots, "logs/escape", false).await.is_err());
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pull_and_push_resume() {
+        let remote = temp_dir("remote");
+        let local = temp_dir("local");
+        let roots = vec![FileRoot { name: "data".into(), path: remote.clone(), writable: true }];
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(remote.join("map.bin"), &content).unwrap();
+
+        // An interrupted pull left the first bytes behind
+        std::fs::write(local.join("map.bin.part"), &content[..70_000]).unwrap();
+        let transfer =
+            pull(open(&roots, "t1"), "data/map.bin", &local.join("map.bin")).await.unwrap();
+        assert_eq!(transfer, Transfer { size: 200_000, resumed: 70_000 });
+        assert_eq!(std::fs::read(local.join("map.bin")).unwrap(), content);
+        assert!(!local.join("map.bin.part").exists());
+
+        // A stale part of another file is replaced
+        std::fs::write(local.join("map.bin.part"), b"stale").unwrap();
+        let transfer =
+            pull(open(&roots, "t2"), "data/map.bin", &local.join("map.bin")).await.unwrap();
+        assert_eq!(transfer.resumed, 0);
+        assert_eq!(std::fs::read(local.join("map.bin")).unwrap(), content);
+
+        std::fs::write(remote.join(".copy.bin.part"), &content[..1234]).unwrap();
+        let transfer =
+            push(open(&roots, "t3"), &local.join("map.bin"), "data/copy.bin").await.unwrap();
+        assert_eq!(transfer, Transfer { size: 200_000, resumed: 1234 });
+        assert_eq!(std::fs::read(remote.join("copy.bin")).unwrap(), content);
+        assert!(!remote.join(".copy.bin.part").exists());
+
+        let e = pull(open(&roots, "t4"), "data/missing.bin", &local.join("missing.bin")).await;
+        assert!(e.is_err());
+
+        let roots = vec![FileRoot { name: "data".into(), path: remote.clone(), writable: false }];
+        let e = push(open(&roots, "t5"), &local.join("map.bin"), "data/other.bin").await;
+        assert!(e.unwrap_err().to_string().contains("read-only"));
+
+        std::fs::remove_dir_all(remote).unwrap();
+        std::fs::remove_dir_all(local).unwrap();
+    }
+}
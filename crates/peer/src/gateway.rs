@@ -0,0 +1,309 @@
+//! HTTP gateway in front of many robots.
+//!
+//! Requests are routed by path, `/robots/<robot_id>/<service>/...`, or by host,
+//! `<robot_id>.<domain>` when a host domain is set. The first request for a
+//! robot service creates its portal through the [`PortalManager`], later ones
+//! reuse it. The request head is rewritten for the service and the connection
+//! is then bridged as is, which lets WebSocket upgrades through. Every other
+//! client connection carries a single request.
+//!
+//! Robot ids come from clients, so a robot service whose portal couldn't be
+//! created is answered from that failure for [`FAILURE_TTL`], and at most
+//! [`MAX_CREATING`] robots have their portals created at once. Clients have
+//! [`HEAD_TIMEOUT`] to send their request head.
+
+use crate::http_proxy::{read_head, HEAD_TIMEOUT, HOP_HEADERS};
+use crate::portal_manager::PortalManager;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::io::{copy_bidirectional, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time::{timeout, Instant};
+use tracing::{debug, error, info, warn};
+
+const PATH_PREFIX: &str = "/robots/";
+
+/// How long a failed portal creation is answered from the failure
+const FAILURE_TTL: Duration = Duration::from_secs(10);
+/// Robots whose portals are created at once, further ones are refused
+const MAX_CREATING: usize = 64;
+
+pub struct Gateway {
+    manager: Arc<PortalManager>,
+    routes: Routes,
+    /// Serializes portal creation per robot, entries go once nobody waits
+    creating: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    /// Robot services whose portal creation failed lately, with when and why
+    failed: StdMutex<HashMap<(String, String), (Instant, String)>>,
+}
+
+#[derive(Default)]
+struct Routes {
+    /// Domain whose subdomains name robots, with the service they are routed to
+    host_domain: Option<(String, String)>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Route {
+    robot_id: String,
+    service: String,
+    /// Request target for the service
+    path: String,
+    /// Path prefix stripped from the request
+    prefix: Option<String>,
+}
+
+impl Gateway {
+    pub fn new(manager: Arc<PortalManager>) -> Self {
+        Self {
+            manager,
+            routes: Routes::default(),
+            creating: Mutex::new(HashMap::new()),
+            failed: StdMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Also route requests for `<robot_id>.<domain>` hosts, to `service` of the robot
+    /// (the proxy's default target if empty)
+    pub fn host_domain(mut self, domain: impl Into<String>, service: impl Into<String>) -> Self {
+        let domain = domain.into().trim_start_matches('.').to_ascii_lowercase();
+        self.routes.host_domain = Some((domain, service.into()));
+        self
+    }
+
+    /// Serve HTTP clients accepted on `listener` until it fails
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        info!("Gateway listening on {}", listener.local_addr()?);
+        loop {
+            let (socket, addr) = listener.accept().await?;
+            let gateway = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = gateway.handle(socket).await {
+                    debug!("Gateway request from {} failed: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, mut socket: TcpStream) -> Result<()> {
+        let (head, body) = timeout(HEAD_TIMEOUT, read_head(&mut socket))
+            .await
+            .map_err(|_| anyhow!("HTTP request head timed out ({}s)", HEAD_TIMEOUT.as_secs()))??;
+        let mut lines = head.split("\r\n").filter(|l| !l.is_empty());
+        let request_line = lines.next().unwrap_or_default();
+        let headers: Vec<&str> = lines.collect();
+        let parts: Vec<&str> = request_line.split(' ').collect();
+        let [method, target, version] = parts[..] else {
+            respond(&mut socket, "400 Bad Request", "Malformed request line").await;
+            return Err(anyhow!("Malformed request line: {}", request_line));
+        };
+
+        let host = header(&headers, "host");
+        let Some(route) = self.routes.route(target, host) else {
+            respond(&mut socket, "404 Not Found", "No robot route").await;
+            return Err(anyhow!("No route for {} (host {:?})", target, host));
+        };
+
+        let local_addr = match self.portal_addr(&route.robot_id, &route.service).await {
+            Ok(addr) => addr,
+            Err(e) => {
+                warn!("Gateway to {}/{} failed: {}", route.robot_id, route.service, e);
+                respond(&mut socket, "502 Bad Gateway", &e.to_string()).await;
+                return Err(e);
+            }
+        };
+        let mut upstream = TcpStream::connect(&local_addr).await?;
+
+        let upgrade = header(&headers, "connection")
+            .is_some_and(|c| c.split(',').any(|t| t.trim().eq_ignore_ascii_case("upgrade")));
+        let mut forwarded = format!("{} {} {}\r\n", method, route.path, version);
+        for line in &headers {
+            let name = line.split(':').next().unwrap_or_default().trim();
+            let hop = HOP_HEADERS.iter().any(|h| name.eq_ignore_ascii_case(h));
+            if !hop || (upgrade && name.eq_ignore_ascii_case("connection")) {
+                forwarded.push_str(line);
+                forwarded.push_str("\r\n");
+            }
+        }
+        if let Some(prefix) = &route.prefix {
+            forwarded.push_str(&format!("X-Forwarded-Prefix: {}\r\n", prefix));
+        }
+        if !upgrade {
+            forwarded.push_str("Connection: close\r\n");
+        }
+        forwarded.push_str("\r\n");
+
+        debug!("{} {} -> {}/{}{}", method, target, route.robot_id, route.service, route.path);
+        upstream.write_all(forwarded.as_bytes()).await?;
+        upstream.write_all(&body).await?;
+        copy_bidirectional(&mut socket, &mut upstream).await?;
+        Ok(())
+    }
+
+    /// Local address of the portal to `service` of `robot_id`, created on first use
+    async fn portal_addr(&self, robot_id: &str, service: &str) -> Result<String> {
+        self.check_failure(robot_id, service)?;
+        let lock = {
+            let mut creating = self.creating.lock().await;
+            if creating.len() >= MAX_CREATING && !creating.contains_key(robot_id) {
+                return Err(anyhow!("Too many portals being created"));
+            }
+            Arc::clone(creating.entry(robot_id.to_string()).or_default())
+        };
+
+        let result = {
+            let _guard = lock.lock().await;
+            // The request before may just have failed
+            match self.check_failure(robot_id, service) {
+                Ok(()) => self.create_portal(robot_id, service).await,
+                Err(e) => Err(e),
+            }
+        };
+
+        let mut creating = self.creating.lock().await;
+        // References are only taken with the map locked, so none are on the way
+        if Arc::strong_count(&lock) == 2 {
+            creating.remove(robot_id);
+        }
+        result
+    }
+
+    async fn create_portal(&self, robot_id: &str, service: &str) -> Result<String> {
+        let addr_uri = format!("127.0.0.1:0?service={}", service);
+        let result = match self.manager.create_portal(robot_id, addr_uri).await {
+            Ok(portal) => portal.local_addr(service).ok_or_else(|| {
+                error!("Portal to {} has no listener for '{}'", robot_id, service);
+                anyhow!("Portal to {} not listening", robot_id)
+            }),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = &result {
+            let failure = (Instant::now(), e.to_string());
+            let key = (robot_id.to_string(), service.to_string());
+            self.failed.lock().unwrap().insert(key, failure);
+        }
+        result
+    }
+
+    /// The failure of a recent portal creation for `service` of `robot_id`, if any
+    fn check_failure(&self, robot_id: &str, service: &str) -> Result<()> {
+        let now = Instant::now();
+        let mut failed = self.failed.lock().unwrap();
+        failed.retain(|_, (at, _)| now.duration_since(*at) < FAILURE_TTL);
+        match failed.get(&(robot_id.to_string(), service.to_string())) {
+            Some((_, error)) => Err(anyhow!("{} (cached)", error)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Routes {
+    fn route(&self, target: &str, host: Option<&str>) -> Option<Route> {
+        if let Some(rest) = target.strip_prefix(PATH_PREFIX) {
+            let end = rest.find(['/', '?']).unwrap_or(rest.len());
+            let (robot_id, rest) = rest.split_at(end);
+            let rest = rest.strip_prefix('/')?;
+            let end = rest.find(['/', '?']).unwrap_or(rest.len());
+            let (service, path) = rest.split_at(end);
+            if robot_id.is_empty() || !valid_name(robot_id) || !valid_name(service) {
+                return None;
+            }
+            let path = match path {
+                "" => "/".to_string(),
+                p if p.starts_with('?') => format!("/{}", p),
+                p => p.to_string(),
+            };
+            return Some(Route {
+                robot_id: robot_id.to_string(),
+                service: service.to_string(),
+                path,
+                prefix: Some(format!("{}{}/{}", PATH_PREFIX, robot_id, service)),
+            });
+        }
+
+        let (domain, service) = self.host_domain.as_ref()?;
+        let host = host?.to_ascii_lowercase();
+        let host = host.rsplit_once(':').map_or(host.as_str(), |(host, _)| host);
+        let robot_id = host.strip_suffix(domain.as_str())?.strip_suffix('.')?;
+        if robot_id.is_empty() || robot_id.contains('.') || !valid_name(robot_id) {
+            return None;
+        }
+        Some(Route {
+            robot_id: robot_id.to_string(),
+            service: service.clone(),
+            path: target.to_string(),
+            prefix: None,
+        })
+    }
+}
+
+/// Robot ids and service names end up in portal options, keep them plain
+fn valid_name(name: &str) -> bool {
+    name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn header<'a>(headers: &[&'a str], name: &str) -> Option<&'a str> {
+    headers.iter().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+async fn respond(socket: &mut TcpStream, status: &str, message: &str) {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        message.len(),
+        message
+    );
+    let _ = socket.write_all(response.as_bytes()).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(robot_id: &str, service: &str, path: &str, prefix: Option<&str>) -> Option<Route> {
+        Some(Route {
+            robot_id: robot_id.into(),
+            service: service.into(),
+            path: path.into(),
+            prefix: prefix.map(String::from),
+        })
+    }
+
+    #[test]
+    fn test_route_by_path() {
+        let routes = Routes::default();
+        let web = |path: &str| route("r1", "web", path, Some("/robots/r1/web"));
+        assert_eq!(
+            routes.route("/robots/r1/web/ui/index.html?x=1", None),
+            web("/ui/index.html?x=1")
+        );
+        assert_eq!(routes.route("/robots/r1/web", None), web("/"));
+        assert_eq!(routes.route("/robots/r1/web?x=1", None), web("/?x=1"));
+        assert_eq!(routes.route("/robots/r1//x", None), route("r1", "", "/x", Some("/robots/r1/")));
+
+        assert!(routes.route("/robots/r1", None).is_none());
+        assert!(routes.route("/robots//web/", None).is_none());
+        assert!(routes.route("/robots/r1/w&b/", None).is_none());
+        assert!(routes.route("/status", None).is_none());
+    }
+
+    #[test]
+    fn test_route_by_host() {
+        let routes = Routes { host_domain: Some(("gw.local".into(), "web".into())) };
+        assert_eq!(
+            routes.route("/status", Some("R1.gw.local:8080")),
+            route("r1", "web", "/status", None)
+        );
+        assert!(routes.route("/status", Some("gw.local")).is_none());
+        assert!(routes.route("/status", Some("a.r1.gw.local")).is_none());
+        assert!(routes.route("/status", Some("r1.other.local")).is_none());
+        assert!(routes.route("/status", None).is_none());
+        assert!(Routes::default().route("/status", Some("r1.gw.local")).is_none());
+    }
+}
@@ -0,0 +1,273 @@
+//! HTTP/1.1 forward proxy side of portal listeners.
+//!
+//! `CONNECT host:port` opens a tunnel (HTTPS and anything else), a plain
+//! request with an absolute URI such as `GET http://host/path` is rewritten
+//! to origin form and sent on. Either way the destination is forwarded to the
+//! proxy as a dynamic forwarding request, which checks it against its
+//! allowlist. Each client connection carries a single plain request, the
+//! forwarded request asks the server to close the connection after it.
+
+use anyhow::{anyhow, Result};
+use bytes::{Buf, Bytes};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::timeout;
+
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// How long a client has to send its request head
+pub(crate) const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Hop-by-hop headers meant for the proxy, dropped from forwarded requests
+pub(crate) const HOP_HEADERS: [&str; 4] =
+    ["connection", "keep-alive", "proxy-connection", "proxy-authorization"];
+
+const ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+const BAD_REQUEST: &[u8] =
+    b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+/// Read a client's request head, returns the `host:port` it asked for and the
+/// socket to bridge, which replays what the target has to see first.
+pub(crate) async fn accept<S>(mut socket: S) -> Result<(String, Prefixed<S>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (head, body) = timeout(HEAD_TIMEOUT, read_head(&mut socket))
+        .await
+        .map_err(|_| anyhow!("HTTP request head timed out ({}s)", HEAD_TIMEOUT.as_secs()))??;
+    match parse(&head) {
+        Ok(Request::Connect(dest)) => {
+            socket.write_all(ESTABLISHED).await?;
+            Ok((dest, Prefixed::new(body, socket)))
+        }
+        Ok(Request::Forward { dest, head }) => {
+            let prefix = [head.as_bytes(), &body].concat();
+            Ok((dest, Prefixed::new(prefix, socket)))
+        }
+        Err(e) => {
+            socket.write_all(BAD_REQUEST).await?;
+            Err(e)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Request {
+    Connect(String),
+    /// The rewritten request head for the target
+    Forward {
+        dest: String,
+        head: String,
+    },
+}
+
+/// Split the request head, including its blank line, from the bytes after it
+pub(crate) async fn read_head<S>(socket: &mut S) -> Result<(String, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let body = buf.split_off(end + 4);
+            let head = String::from_utf8(buf).map_err(|_| anyhow!("Request head not UTF-8"))?;
+            return Ok((head, body));
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            return Err(anyhow!("Request head larger than {} bytes", MAX_HEAD_SIZE));
+        }
+        if socket.read_buf(&mut buf).await? == 0 {
+            return Err(anyhow!("Connection closed before the request head ended"));
+        }
+    }
+}
+
+fn parse(head: &str) -> Result<Request> {
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(anyhow!("Malformed request line: {}", request_line));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(anyhow!("Unsupported HTTP version: {}", version));
+    }
+
+    if method.eq_ignore_ascii_case("CONNECT") {
+        return match split_port(target) {
+            Some(_) => Ok(Request::Connect(target.to_string())),
+            None => Err(anyhow!("CONNECT target without port: {}", target)),
+        };
+    }
+
+    let rest = target
+        .get(..7)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("http://"))
+        .map(|_| &target[7..])
+        .ok_or_else(|| anyhow!("Forward proxying needs an absolute http URI: {}", target))?;
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(end);
+    let path = match path {
+        "" => "/".to_string(),
+        p if p.starts_with('?') => format!("/{}", p),
+        p => p.to_string(),
+    };
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    if authority.is_empty() {
+        return Err(anyhow!("Request URI without host: {}", target));
+    }
+    let dest = match split_port(authority) {
+        Some(_) => authority.to_string(),
+        None => format!("{}:80", authority),
+    };
+
+    let mut forwarded = format!("{} {} {}\r\n", method, path, version);
+    for line in lines.filter(|l| !l.is_empty()) {
+        let name = line.split(':').next().unwrap_or_default().trim();
+        if !HOP_HEADERS.iter().any(|h| name.eq_ignore_ascii_case(h)) {
+            forwarded.push_str(line);
+            forwarded.push_str("\r\n");
+        }
+    }
+    forwarded.push_str("Connection: close\r\n\r\n");
+    Ok(Request::Forward { dest, head: forwarded })
+}
+
+/// The port of `host:port`, `None` for a bare host or bracketed IPv6 address
+fn split_port(authority: &str) -> Option<u16> {
+    let (host, port) = authority.rsplit_once(':')?;
+    if host.is_empty() || (host.contains(':') && !host.ends_with(']')) {
+        return None;
+    }
+    port.parse().ok()
+}
+
+/// A socket whose reads start with bytes already taken from it or produced
+/// in its place
+pub(crate) struct Prefixed<S> {
+    prefix: Bytes,
+    inner: S,
+}
+
+impl<S> Prefixed<S> {
+    fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self { prefix: Bytes::from(prefix), inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.prefix.has_remaining() {
+            let n = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..n]);
+            self.prefix.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[test]
+    fn test_parse_connect() {
+        let request = parse("CONNECT 192.168.1.50:443 HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert_eq!(request, Request::Connect("192.168.1.50:443".into()));
+        assert!(parse("CONNECT [::1]:22 HTTP/1.1\r\n\r\n").is_ok());
+        assert!(parse("CONNECT plc.local HTTP/1.1\r\n\r\n").is_err());
+        assert!(parse("CONNECT plc.local:443 HTTP/2\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_forward() {
+        let head = "GET http://user@plc.local/status?x=1 HTTP/1.1\r\nHost: plc.local\r\n\
+                    Proxy-Connection: keep-alive\r\nAccept: */*\r\n\r\n";
+        let Request::Forward { dest, head } = parse(head).unwrap() else { panic!() };
+        assert_eq!(dest, "plc.local:80");
+        assert_eq!(
+            head,
+            "GET /status?x=1 HTTP/1.1\r\nHost: plc.local\r\nAccept: */*\r\nConnection: close\r\n\r\n"
+        );
+
+        let Request::Forward { dest, head } =
+            parse("GET HTTP://[::1]:8080 HTTP/1.0\r\n\r\n").unwrap()
+        else {
+            panic!()
+        };
+        assert_eq!(dest, "[::1]:8080");
+        assert!(head.starts_with("GET / HTTP/1.0\r\n"));
+
+        let Request::Forward { dest, head } =
+            parse("GET http://plc.local:8080?x=1 HTTP/1.1\r\n\r\n").unwrap()
+        else {
+            panic!()
+        };
+        assert_eq!(dest, "plc.local:8080");
+        assert!(head.starts_with("GET /?x=1 HTTP/1.1\r\n"));
+
+        assert!(parse("GET /status HTTP/1.1\r\n\r\n").is_err());
+        assert!(parse("GET https://plc.local/ HTTP/1.1\r\n\r\n").is_err());
+        assert!(parse("GET http:///status HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_accept_replays_body() {
+        let (mut client, server) = duplex(1024);
+        client.write_all(b"POST http://plc.local:8080/set HTTP/1.1\r\n\r\nvalue=1").await.unwrap();
+        let (dest, mut socket) = accept(server).await.unwrap();
+        assert_eq!(dest, "plc.local:8080");
+
+        drop(client);
+        let mut forwarded = String::new();
+        socket.read_to_string(&mut forwarded).await.unwrap();
+        assert_eq!(forwarded, "POST /set HTTP/1.1\r\nConnection: close\r\n\r\nvalue=1");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_accept_timeout() {
+        let (mut client, server) = duplex(1024);
+        client.write_all(b"GET http://plc.local/ HTTP/1.1\r\n").await.unwrap();
+        let error = accept(server).await.err().unwrap();
+        assert!(error.to_string().contains("timed out"));
+    }
+
+    #[tokio::test]
+    async fn test_accept_rejects_malformed() {
+        let (mut client, server) = duplex(1024);
+        client.write_all(b"hello\r\n\r\n").await.unwrap();
+        assert!(accept(server).await.is_err());
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, BAD_REQUEST);
+    }
+}
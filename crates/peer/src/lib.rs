@@ -1,9 +1,42 @@
+mod allowlist;
 mod binder;
+mod compression;
+mod control;
+mod exec;
+mod files;
+mod http_proxy;
+mod mux;
+mod negotiation;
+mod path;
+mod peer;
+mod pool;
 mod portal;
 mod proxy;
+mod refusal;
+mod reverse;
+mod serial;
+mod service;
+mod shaping;
+mod shell;
+mod socks5;
+mod stdio;
 
 pub mod config;
+pub mod gateway;
 pub mod portal_manager;
 pub mod proxy_manager;
 
-pub use config::PeerConfig;
+pub use allowlist::{AllowRule, Allowlist};
+pub use compression::CompressionStats;
+pub use config::{
+    AddrOptions, ChannelProfile, Compression, Lifetime, PeerConfig, RateLimit, StreamMode,
+};
+pub use control::LinkStats;
+pub use files::{FileRoot, Transfer};
+pub use path::{Candidate, CandidateKind, ConnectionPath};
+pub use peer::{Peer, PeerEvent};
+pub use pool::PoolStats;
+pub use reverse::ReverseRule;
+pub use service::ServiceMap;
+pub use shell::{ShellConfig, ShellKey};
+pub use socks5::Socks5Config;
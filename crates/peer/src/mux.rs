@@ -0,0 +1,451 @@
+//! Many logical streams over one long-lived DataChannel.
+//!
+//! Every frame is a single DataChannel message: `[type: u8][stream id: u32 BE][payload]`.
+//! Opening a stream is purely local: the OPEN frame is queued in front of the
+//! first data, so a new connection costs no network round trip. Each direction
+//! of a stream has a credit window replenished by WINDOW frames, and the sender
+//! round-robins between streams that have data pending. A CLOSE frame may
+//! carry the reason a stream was refused.
+//!
+//! A peer breaking the protocol only loses the stream concerned: data beyond
+//! the credit granted closes it, and an OPEN with an id of the wrong parity or
+//! not above the last one is refused.
+
+use crate::binder::{AcceptFn, Tunnel};
+use crate::refusal::{StreamError, StreamErrorKind};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::stream::{self, BoxStream, SelectAll, StreamExt};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, OnceLock, Weak};
+use tokio::sync::{mpsc, Mutex, Notify, Semaphore};
+use tracing::{debug, error, info, trace, warn};
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::RTCDataChannel;
+
+pub(crate) const MUX_LABEL: &str = "MUX";
+
+const INITIAL_WINDOW: usize = 256 * 1024;
+const WINDOW_UPDATE_THRESHOLD: usize = INITIAL_WINDOW / 4;
+const BUFFERED_HIGH: usize = 1024 * 1024;
+const BUFFERED_LOW: usize = 256 * 1024;
+
+const FRAME_OPEN: u8 = 1;
+const FRAME_DATA: u8 = 2;
+const FRAME_WINDOW: u8 = 3;
+const FRAME_CLOSE: u8 = 4;
+
+enum Command {
+    Control(Bytes),
+    Queue(BoxStream<'static, Bytes>),
+}
+
+struct StreamEntry {
+    incoming: mpsc::UnboundedSender<Bytes>,
+    /// Bytes the peer may still send, what it has sent beyond is never queued
+    credit: Arc<AtomicUsize>,
+    window: Arc<Semaphore>,
+    closed: Arc<AtomicBool>,
+    refusal: Arc<OnceLock<StreamError>>,
+}
+
+pub(crate) struct Mux {
+    dc: Arc<RTCDataChannel>,
+    streams: StdMutex<HashMap<u32, StreamEntry>>,
+    next_id: AtomicU32,
+    /// Parity of the ids the other side opens streams with
+    remote_parity: u32,
+    /// Highest id the other side opened a stream with
+    last_remote_id: AtomicU32,
+    command_tx: mpsc::UnboundedSender<Command>,
+    accept: OnceLock<AcceptFn>,
+}
+
+impl Mux {
+    /// Attach a multiplexer to `dc`. The opening side of the channel uses odd
+    /// stream ids, the accepting side even ones, so both may open streams.
+    pub fn new(dc: Arc<RTCDataChannel>, opener: bool, accept: Option<AcceptFn>) -> Arc<Self> {
+        let (command_tx, command_rx) = mpsc::unbounded_channel();
+        let mux = Arc::new(Self {
+            dc: Arc::clone(&dc),
+            streams: StdMutex::new(HashMap::new()),
+            next_id: AtomicU32::new(if opener { 1 } else { 2 }),
+            remote_parity: if opener { 0 } else { 1 },
+            last_remote_id: AtomicU32::new(0),
+            command_tx,
+            accept: OnceLock::new(),
+        });
+        if let Some(accept) = accept {
+            let _ = mux.accept.set(accept);
+        }
+
+        let command_rx = StdMutex::new(Some(command_rx));
+        let dc_for_open = Arc::clone(&dc);
+        dc.on_open(Box::new(move || {
+            let dc = Arc::clone(&dc_for_open);
+            let command_rx = command_rx.lock().unwrap().take();
+            Box::pin(async move {
+                info!("{} opened", dc.label());
+                if let Some(command_rx) = command_rx {
+                    tokio::spawn(Self::run_scheduler(dc, command_rx));
+                }
+            })
+        }));
+
+        let weak = Arc::downgrade(&mux);
+        dc.on_message(Box::new(move |msg: DataChannelMessage| {
+            if let Some(mux) = weak.upgrade() {
+                mux.handle_frame(msg.data);
+            }
+            Box::pin(async {})
+        }));
+
+        let weak = Arc::downgrade(&mux);
+        dc.on_close(Box::new(move || {
+            if let Some(mux) = weak.upgrade() {
+                mux.close_all();
+            }
+            Box::pin(async {})
+        }));
+
+        mux
+    }
+
+    /// Accept streams the other side opens from now on, ignored if already set
+    pub fn set_accept(&self, accept: AcceptFn) {
+        let _ = self.accept.set(accept);
+    }
+
+    /// Open a new logical stream, usable immediately
+    pub fn open_stream(self: &Arc<Self>, request: &str) -> Arc<MuxStream> {
+        let id = self.next_id.fetch_add(2, Ordering::Relaxed);
+        let stream = self.register(id);
+        let _ = stream.queue.try_send(encode(FRAME_OPEN, id, request.as_bytes()));
+        debug!("{} opened", stream.label);
+        stream
+    }
+
+    fn register(self: &Arc<Self>, id: u32) -> Arc<MuxStream> {
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let (queue_tx, queue_rx) = mpsc::channel(1);
+        let credit = Arc::new(AtomicUsize::new(INITIAL_WINDOW));
+        let window = Arc::new(Semaphore::new(INITIAL_WINDOW));
+        let closed = Arc::new(AtomicBool::new(false));
+        let refusal = Arc::new(OnceLock::new());
+
+        self.streams.lock().unwrap().insert(
+            id,
+            StreamEntry {
+                incoming: incoming_tx,
+                credit: Arc::clone(&credit),
+                window: Arc::clone(&window),
+                closed: Arc::clone(&closed),
+                refusal: Arc::clone(&refusal),
+            },
+        );
+
+        let queue =
+            stream::unfold(
+                queue_rx,
+                |mut rx| async move { rx.recv().await.map(|frame| (frame, rx)) },
+            );
+        let _ = self.command_tx.send(Command::Queue(queue.boxed()));
+
+        Arc::new(MuxStream {
+            mux: Arc::downgrade(self),
+            id,
+            label: format!("{}#{}", self.dc.label(), id),
+            window,
+            queue: queue_tx,
+            incoming: Mutex::new(incoming_rx),
+            credit,
+            unacked: AtomicUsize::new(0),
+            closed,
+            refusal,
+        })
+    }
+
+    fn handle_frame(self: &Arc<Self>, data: Bytes) {
+        let Some((frame_type, id, payload)) = decode(data) else {
+            warn!("{} malformed frame dropped", self.dc.label());
+            return;
+        };
+
+        match frame_type {
+            FRAME_OPEN => {
+                if id % 2 != self.remote_parity
+                    || self.last_remote_id.fetch_max(id, Ordering::Relaxed) >= id
+                {
+                    let message = format!("Invalid stream id {}", id);
+                    self.abort(id, None, StreamError::new(StreamErrorKind::Failed, message));
+                    return;
+                }
+                let request = String::from_utf8_lossy(&payload).into_owned();
+                match self.accept.get() {
+                    Some(accept) => {
+                        let stream = self.register(id);
+                        trace!("{} accepted", stream.label);
+                        accept(stream, request);
+                    }
+                    None => {
+                        warn!("{} unexpected OPEN for stream {}", self.dc.label(), id);
+                        let _ =
+                            self.command_tx.send(Command::Control(encode(FRAME_CLOSE, id, &[])));
+                    }
+                }
+            }
+            FRAME_DATA => {
+                let mut streams = self.streams.lock().unwrap();
+                let Some(entry) = streams.get(&id) else {
+                    return;
+                };
+                let len = payload.len();
+                let spend = |credit: usize| credit.checked_sub(len);
+                if entry.credit.fetch_update(Ordering::Relaxed, Ordering::Relaxed, spend).is_ok() {
+                    let _ = entry.incoming.send(payload);
+                } else {
+                    let entry = streams.remove(&id);
+                    drop(streams);
+                    let message = "Flow control window exceeded";
+                    self.abort(id, entry, StreamError::new(StreamErrorKind::Failed, message));
+                }
+            }
+            FRAME_WINDOW => {
+                if payload.len() == 4 {
+                    let credit =
+                        u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                    if let Some(entry) = self.streams.lock().unwrap().get(&id) {
+                        entry.window.add_permits(credit as usize);
+                    }
+                }
+            }
+            FRAME_CLOSE => {
+                if let Some(entry) = self.streams.lock().unwrap().remove(&id) {
+                    // Set before the incoming sender drops, which ends the stream
+                    if !payload.is_empty() {
+                        let reason = String::from_utf8_lossy(&payload);
+                        let _ = entry.refusal.set(StreamError::decode(&reason));
+                    }
+                    entry.closed.store(true, Ordering::Relaxed);
+                    entry.window.close();
+                    trace!("{}#{} closed by remote", self.dc.label(), id);
+                }
+            }
+            _ => warn!("{} unknown frame type {}", self.dc.label(), frame_type),
+        }
+    }
+
+    /// Close a stream the other side broke the protocol on, and tell it why
+    fn abort(&self, id: u32, entry: Option<StreamEntry>, error: StreamError) {
+        warn!("{}#{} closed, {}", self.dc.label(), id, error);
+        if let Some(entry) = entry {
+            entry.closed.store(true, Ordering::Relaxed);
+            entry.window.close();
+        }
+        let reason = error.encode();
+        let _ = self.command_tx.send(Command::Control(encode(FRAME_CLOSE, id, reason.as_bytes())));
+    }
+
+    fn close_all(&self) {
+        let streams = std::mem::take(&mut *self.streams.lock().unwrap());
+        for entry in streams.into_values() {
+            entry.closed.store(true, Ordering::Relaxed);
+            entry.window.close();
+        }
+        debug!("{} closed", self.dc.label());
+    }
+
+    fn remove(&self, id: u32) {
+        self.streams.lock().unwrap().remove(&id);
+    }
+
+    /// Single writer of the DataChannel. Control frames go first, data frames
+    /// are taken one per stream in turn, and sending pauses while the SCTP
+    /// buffer is above the high watermark.
+    async fn run_scheduler(
+        dc: Arc<RTCDataChannel>,
+        mut command_rx: mpsc::UnboundedReceiver<Command>,
+    ) {
+        let drained = Arc::new(Notify::new());
+        dc.set_buffered_amount_low_threshold(BUFFERED_LOW).await;
+        let notify = Arc::clone(&drained);
+        dc.on_buffered_amount_low(Box::new(move || {
+            notify.notify_one();
+            Box::pin(async {})
+        }))
+        .await;
+
+        let mut queues: SelectAll<BoxStream<'static, Bytes>> = SelectAll::new();
+        loop {
+            let frame = tokio::select! {
+                biased;
+                command = command_rx.recv() => match command {
+                    Some(Command::Control(frame)) => frame,
+                    Some(Command::Queue(queue)) => {
+                        queues.push(queue);
+                        continue;
+                    }
+                    None => break,
+                },
+                Some(frame) = queues.next(), if !queues.is_empty() => frame,
+            };
+
+            while dc.buffered_amount().await > BUFFERED_HIGH {
+                drained.notified().await;
+            }
+            if let Err(e) = dc.send(&frame).await {
+                error!("{} send error: {}", dc.label(), e);
+                break;
+            }
+        }
+        debug!("{} scheduler exited", dc.label());
+    }
+}
+
+/// One logical stream of a [`Mux`]
+pub(crate) struct MuxStream {
+    mux: Weak<Mux>,
+    id: u32,
+    label: String,
+    window: Arc<Semaphore>,
+    queue: mpsc::Sender<Bytes>,
+    incoming: Mutex<mpsc::UnboundedReceiver<Bytes>>,
+    /// Shared with the stream's entry, which spends it on incoming data
+    credit: Arc<AtomicUsize>,
+    unacked: AtomicUsize,
+    closed: Arc<AtomicBool>,
+    refusal: Arc<OnceLock<StreamError>>,
+}
+
+#[async_trait]
+impl Tunnel for MuxStream {
+    fn label(&self) -> &str {
+        &self.label
+    }
+
+    async fn send(&self, data: Bytes) -> Result<()> {
+        let permit = self
+            .window
+            .acquire_many(data.len() as u32)
+            .await
+            .map_err(|_| anyhow!("stream closed"))?;
+        permit.forget();
+        self.queue
+            .send(encode(FRAME_DATA, self.id, &data))
+            .await
+            .map_err(|_| anyhow!("multiplexer closed"))
+    }
+
+    async fn recv(&self) -> Option<Bytes> {
+        self.incoming.lock().await.recv().await
+    }
+
+    async fn consumed(&self, n: usize) {
+        let unacked = self.unacked.fetch_add(n, Ordering::Relaxed) + n;
+        if unacked < WINDOW_UPDATE_THRESHOLD {
+            return;
+        }
+        self.unacked.fetch_sub(unacked, Ordering::Relaxed);
+        self.credit.fetch_add(unacked, Ordering::Relaxed);
+        if let Some(mux) = self.mux.upgrade() {
+            let credit = (unacked as u32).to_be_bytes();
+            let _ = mux.command_tx.send(Command::Control(encode(FRAME_WINDOW, self.id, &credit)));
+        }
+    }
+
+    async fn close(&self) {
+        self.close_with(b"").await;
+    }
+
+    /// The reason travels as the payload of the CLOSE frame
+    async fn reject(&self, error: &StreamError) {
+        self.close_with(error.encode().as_bytes()).await;
+    }
+
+    fn refusal(&self) -> Option<StreamError> {
+        self.refusal.get().cloned()
+    }
+}
+
+impl MuxStream {
+    async fn close_with(&self, reason: &[u8]) {
+        if self.closed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        self.window.close();
+        let _ = self.queue.send(encode(FRAME_CLOSE, self.id, reason)).await;
+        if let Some(mux) = self.mux.upgrade() {
+            mux.remove(self.id);
+        }
+        info!("{} closed", self.label);
+    }
+}
+
+fn encode(frame_type: u8, id: u32, payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(5 + payload.len());
+    buf.put_u8(frame_type);
+    buf.put_u32(id);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+fn decode(mut data: Bytes) -> Option<(u8, u32, Bytes)> {
+    if data.len() < 5 {
+        return None;
+    }
+    let header = data.split_to(5);
+    let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+    Some((header[0], id, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let frame = encode(FRAME_DATA, 7, b"hello");
+        assert_eq!(frame.len(), 10);
+        let (frame_type, id, payload) = decode(frame).unwrap();
+        assert_eq!(frame_type, FRAME_DATA);
+        assert_eq!(id, 7);
+        assert_eq!(&payload[..], b"hello");
+
+        assert!(decode(Bytes::from_static(&[FRAME_CLOSE, 0, 0])).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_remote_misbehaviour() {
+        let (accepted_tx, mut accepted_rx) = mpsc::unbounded_channel();
+        let accept: AcceptFn = Arc::new(move |stream, _| {
+            let _ = accepted_tx.send(stream);
+        });
+        let mux = Mux::new(Arc::new(RTCDataChannel::default()), true, Some(accept));
+
+        // The opening side's peer numbers its streams evenly, upwards
+        mux.handle_frame(encode(FRAME_OPEN, 4, b""));
+        let stream = accepted_rx.try_recv().unwrap();
+        for id in [4, 2, 5] {
+            mux.handle_frame(encode(FRAME_OPEN, id, b""));
+            assert!(accepted_rx.try_recv().is_err());
+        }
+        mux.handle_frame(encode(FRAME_OPEN, 6, b""));
+        assert!(accepted_rx.try_recv().is_ok());
+
+        // Data within the window is passed on, beyond it closes the stream
+        let chunk = vec![0u8; INITIAL_WINDOW / 2];
+        mux.handle_frame(encode(FRAME_DATA, 4, &chunk));
+        assert_eq!(stream.recv().await.unwrap().len(), chunk.len());
+        stream.consumed(chunk.len()).await;
+        mux.handle_frame(encode(FRAME_DATA, 4, &chunk));
+        mux.handle_frame(encode(FRAME_DATA, 4, &chunk));
+        assert!(mux.streams.lock().unwrap().contains_key(&4));
+        mux.handle_frame(encode(FRAME_DATA, 4, b"x"));
+        assert!(!mux.streams.lock().unwrap().contains_key(&4));
+        assert!(stream.recv().await.is_some());
+        assert!(stream.recv().await.is_some());
+        assert!(stream.recv().await.is_none());
+    }
+}
@@ -0,0 +1,127 @@
+//! Tunnel options agreed on during the SDP exchange.
+//!
+//! The portal appends session level `a=x-lrc-*` attributes to its offer and the
+//! proxy echoes the ones it accepts in its answer. Peers that don't know an
+//! attribute simply ignore it, so both ends fall back to the defaults.
+//!
+//! An offer renegotiating an established session for an ICE restart is marked
+//! with `a=x-lrc-ice-restart`, the proxy applies it to its existing connection
+//! instead of replacing it.
+
+use crate::config::{Compression, PeerConfig, StreamMode};
+use crate::pool::POOL_BASE_ID;
+use anyhow::Result;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+const ATTR_POOL: &str = "x-lrc-pool";
+const ATTR_COMPRESS: &str = "x-lrc-compress";
+const ATTR_REVERSE: &str = "x-lrc-reverse";
+const ATTR_ICE_RESTART: &str = "x-lrc-ice-restart";
+
+/// Upper bound on the pre-negotiated channels a proxy creates for one portal
+const MAX_POOL_SIZE: u16 = 256;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct SessionOptions {
+    /// Pre-negotiated channel ids as `(first id, count)`
+    pub pool: Option<(u16, u16)>,
+    pub compression: Compression,
+    /// Proxy side addresses to listen on for reverse forwarding, the proxy
+    /// answers with those it actually listens on
+    pub reverse: Vec<String>,
+    /// The offer restarts ICE on the current connection, other options stay as
+    /// they were agreed on
+    pub ice_restart: bool,
+}
+
+impl SessionOptions {
+    /// Options a portal asks for
+    pub fn from_config(config: &PeerConfig) -> Self {
+        let pool = match config.stream_mode {
+            StreamMode::Pooled(count) if count > 0 => Some((POOL_BASE_ID, count)),
+            _ => None,
+        };
+        Self { pool, compression: config.compression, ..Default::default() }
+    }
+
+    /// Options of an offer restarting ICE
+    pub fn ice_restart() -> Self {
+        Self { ice_restart: true, ..Default::default() }
+    }
+
+    pub fn parse(desc: &RTCSessionDescription) -> Self {
+        let Ok(sdp) = desc.unmarshal() else {
+            return Self::default();
+        };
+        let pool = sdp.attribute(ATTR_POOL).and_then(|value| {
+            let (base, count) = value.split_once(' ')?;
+            Some((base.parse().ok()?, count.parse().ok()?))
+        });
+        // Unknown algorithms are ignored, which leaves the session uncompressed
+        let compression =
+            sdp.attribute(ATTR_COMPRESS).and_then(|value| value.parse().ok()).unwrap_or_default();
+        // Addresses are space separated, they never contain spaces themselves
+        let reverse = sdp
+            .attribute(ATTR_REVERSE)
+            .map(|value| value.split_whitespace().map(String::from).collect())
+            .unwrap_or_default();
+        let ice_restart = sdp.has_attribute(ATTR_ICE_RESTART);
+        Self { pool, compression, reverse, ice_restart }
+    }
+
+    /// Whether the SDP of an offer restarts ICE on an established session
+    pub fn is_ice_restart(sdp: &str) -> bool {
+        RTCSessionDescription::offer(sdp.to_string())
+            .is_ok_and(|desc| Self::parse(&desc).ice_restart)
+    }
+
+    /// Options a proxy agrees to, given what the portal asked for. Reverse
+    /// forwarding is left to the proxy, which knows which listeners it opened.
+    /// A pool starting below [`POOL_BASE_ID`] would collide with DCEP ids and
+    /// is declined, as is one left empty once it ends below `u16::MAX`.
+    pub fn accept(&self) -> Self {
+        let pool = self
+            .pool
+            .filter(|&(base, _)| base >= POOL_BASE_ID)
+            .map(|(base, count)| (base, count.min(MAX_POOL_SIZE).min(u16::MAX - base)))
+            .filter(|&(_, count)| count > 0);
+        Self { pool, compression: self.compression, ..Default::default() }
+    }
+
+    /// SDP of `desc` with the options appended as session attributes
+    pub fn annotate(&self, desc: &RTCSessionDescription) -> Result<String> {
+        let mut sdp = desc.unmarshal()?;
+        if let Some((base, count)) = self.pool {
+            sdp = sdp.with_value_attribute(ATTR_POOL.to_string(), format!("{} {}", base, count));
+        }
+        if self.compression != Compression::None {
+            sdp = sdp.with_value_attribute(ATTR_COMPRESS.to_string(), self.compression.to_string());
+        }
+        if !self.reverse.is_empty() {
+            sdp = sdp.with_value_attribute(ATTR_REVERSE.to_string(), self.reverse.join(" "));
+        }
+        if self.ice_restart {
+            sdp = sdp.with_property_attribute(ATTR_ICE_RESTART.to_string());
+        }
+        Ok(sdp.marshal())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept_pool(pool: (u16, u16)) -> Option<(u16, u16)> {
+        SessionOptions { pool: Some(pool), ..Default::default() }.accept().pool
+    }
+
+    #[test]
+    fn test_accept_pool() {
+        assert_eq!(accept_pool((POOL_BASE_ID, 8)), Some((POOL_BASE_ID, 8)));
+        assert_eq!(accept_pool((POOL_BASE_ID, 1000)), Some((POOL_BASE_ID, MAX_POOL_SIZE)));
+        assert_eq!(accept_pool((0, 8)), None);
+        assert_eq!(accept_pool((POOL_BASE_ID - 1, 8)), None);
+        assert_eq!(accept_pool((u16::MAX - 4, 8)), Some((u16::MAX - 4, 4)));
+        assert_eq!(accept_pool((u16::MAX, 8)), None);
+    }
+}
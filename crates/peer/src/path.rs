@@ -0,0 +1,202 @@
+//! The network path a peer connection took, read from its WebRTC stats.
+//!
+//! ICE settles on one candidate pair per connection. Its candidate types tell
+//! whether the peers talk directly (`host`), through a NAT mapping (`srflx`,
+//! `prflx`) or through a TURN server (`relay`), which drives both latency and
+//! TURN traffic.
+
+use std::fmt;
+use std::time::Duration;
+use tracing::{debug, info};
+use webrtc::ice::candidate::{CandidatePairState, CandidateType};
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::stats::{ICECandidateStats, StatsReportType};
+
+/// How a candidate reaches its peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CandidateKind {
+    /// An address of the peer itself
+    Host,
+    /// Its public address as seen by a STUN server
+    ServerReflexive,
+    /// Its public address as seen by the other peer
+    PeerReflexive,
+    /// An address allocated on a TURN server
+    Relay,
+    Unknown,
+}
+
+impl From<CandidateType> for CandidateKind {
+    fn from(candidate_type: CandidateType) -> Self {
+        match candidate_type {
+            CandidateType::Host => Self::Host,
+            CandidateType::ServerReflexive => Self::ServerReflexive,
+            CandidateType::PeerReflexive => Self::PeerReflexive,
+            CandidateType::Relay => Self::Relay,
+            CandidateType::Unspecified => Self::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for CandidateKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Host => write!(f, "host"),
+            Self::ServerReflexive => write!(f, "srflx"),
+            Self::PeerReflexive => write!(f, "prflx"),
+            Self::Relay => write!(f, "relay"),
+            Self::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// One end of the selected candidate pair
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub kind: CandidateKind,
+    /// `ip:port` of the candidate
+    pub address: String,
+    /// Protocol to the TURN server, `udp`, `tcp` or `tls`, for local relay candidates
+    pub relay_protocol: Option<String>,
+}
+
+impl Candidate {
+    fn from_stats(stats: &ICECandidateStats) -> Self {
+        let address = if stats.ip.contains(':') {
+            format!("[{}]:{}", stats.ip, stats.port)
+        } else {
+            format!("{}:{}", stats.ip, stats.port)
+        };
+        let kind = CandidateKind::from(stats.candidate_type);
+        let relay_protocol = Some(stats.relay_protocol.clone())
+            .filter(|p| kind == CandidateKind::Relay && !p.is_empty());
+        Self { kind, address, relay_protocol }
+    }
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.kind, self.address)?;
+        if let Some(protocol) = &self.relay_protocol {
+            write!(f, " (turn over {})", protocol)?;
+        }
+        Ok(())
+    }
+}
+
+/// The selected candidate pair of a peer connection and its traffic
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionPath {
+    pub local: Candidate,
+    pub remote: Candidate,
+    /// `udp` or `tcp` between the two candidates
+    pub protocol: String,
+    /// Smoothed round-trip time measured by the control channel, `None` before
+    /// the first ping was answered
+    pub rtt: Option<Duration>,
+    /// Bytes sent over ICE since the connection started, on any path
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl ConnectionPath {
+    /// Read the selected candidate pair of `pc` and its traffic, `None` until ICE
+    /// selected one
+    pub(crate) async fn of(pc: &RTCPeerConnection) -> Option<Self> {
+        let report = pc.get_stats().await;
+        let pair = report
+            .reports
+            .values()
+            .filter_map(|r| match r {
+                StatsReportType::CandidatePair(p)
+                    if p.nominated && p.state == CandidatePairState::Succeeded =>
+                {
+                    Some(p)
+                }
+                _ => None,
+            })
+            // After an ICE restart the pair still in use receives the latest packets
+            .max_by_key(|p| p.last_packet_received_timestamp)?;
+
+        let (local, remote) = match (
+            report.reports.get(&pair.local_candidate_id)?,
+            report.reports.get(&pair.remote_candidate_id)?,
+        ) {
+            (StatsReportType::LocalCandidate(l), StatsReportType::RemoteCandidate(r)) => (l, r),
+            _ => return None,
+        };
+        // The candidate pair counters stay at zero, the ICE transport counts the traffic
+        let (bytes_sent, bytes_received) = report
+            .reports
+            .values()
+            .find_map(|r| match r {
+                StatsReportType::Transport(t) => Some((t.bytes_sent, t.bytes_received)),
+                _ => None,
+            })
+            .unwrap_or_default();
+        let protocol = if local.network_type.is_tcp() { "tcp" } else { "udp" };
+        Some(Self {
+            local: Candidate::from_stats(local),
+            remote: Candidate::from_stats(remote),
+            protocol: protocol.to_string(),
+            rtt: None,
+            bytes_sent: bytes_sent as u64,
+            bytes_received: bytes_received as u64,
+        })
+    }
+
+    /// Whether the traffic goes through a TURN server on either side
+    pub fn is_relayed(&self) -> bool {
+        self.local.kind == CandidateKind::Relay || self.remote.kind == CandidateKind::Relay
+    }
+}
+
+impl fmt::Display for ConnectionPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} <-> {} over {}", self.local, self.remote, self.protocol)?;
+        if let Some(rtt) = self.rtt {
+            write!(f, ", rtt {:.1}ms", rtt.as_secs_f64() * 1000.0)?;
+        }
+        write!(f, ", {} bytes sent, {} received", self.bytes_sent, self.bytes_received)
+    }
+}
+
+/// Log the path a connection to `remote_id` took, relayed ones stand out
+pub(crate) fn log_connected(remote_id: &str, path: Option<ConnectionPath>) {
+    match path {
+        Some(path) if path.is_relayed() => info!("{} connected through TURN: {}", remote_id, path),
+        Some(path) => info!("{} connected: {}", remote_id, path),
+        None => debug!("{} connected", remote_id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_relayed_path() {
+        let path = ConnectionPath {
+            local: Candidate {
+                kind: CandidateKind::Relay,
+                address: "203.0.113.7:49152".to_string(),
+                relay_protocol: Some("udp".to_string()),
+            },
+            remote: Candidate {
+                kind: CandidateKind::ServerReflexive,
+                address: "[2001:db8::1]:5000".to_string(),
+                relay_protocol: None,
+            },
+            protocol: "udp".to_string(),
+            rtt: Some(Duration::from_micros(42_500)),
+            bytes_sent: 1200,
+            bytes_received: 800,
+        };
+        assert!(path.is_relayed());
+        assert_eq!(
+            path.to_string(),
+            "relay 203.0.113.7:49152 (turn over udp) <-> srflx [2001:db8::1]:5000 over udp, \
+             rtt 42.5ms, 1200 bytes sent, 800 received"
+        );
+    }
+}
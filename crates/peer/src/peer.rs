@@ -0,0 +1,373 @@
+//! What portals and proxies have in common.
+//!
+//! Both hold one peer connection to one remote, negotiated over signaling: the
+//! portal offers, the proxy answers, and both trickle their ICE candidates. The
+//! [`Connection`] sets up the peer connection and reports its candidates and
+//! state changes to the manager as [`PeerEvent`]s, the [`Peer`] trait gives
+//! managers one view of both roles.
+//!
+//! A disconnected peer connection is given [`PeerConfig::ice_restart_grace`] to
+//! recover: the portal offers again with new ICE credentials over the same
+//! signaling, the proxy answers, and the DataChannels carry on over the new
+//! path. Only once the grace period is over the connection is closed.
+//!
+//! Each connection also carries a [`Control`] channel, whose pings measure the
+//! link and notice a dead one sooner than ICE does.
+//!
+//! A session that runs out of its [`PeerConfig::session_lifetime`], idle or
+//! not, is reported as [`PeerEvent::Expired`] for its manager to tear it down.
+
+use crate::binder::Activity;
+use crate::compression::CompressionStats;
+use crate::config::{Lifetime, PeerConfig, RTC_API};
+use crate::control::{Control, Keepalive, LinkStats, CONTROL_LABEL};
+use crate::path::ConnectionPath;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use signal::{SignalPayload, SignalType};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::AbortHandle;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, trace, warn};
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::RTCPeerConnection;
+
+/// What a portal or proxy reports to its manager
+#[derive(Debug, Clone)]
+pub enum PeerEvent {
+    /// An offer, answer or candidate to send to the remote
+    Signal {
+        remote_id: String,
+        payload: SignalPayload,
+    },
+    Connected {
+        remote_id: String,
+    },
+    /// The connection was interrupted and may still recover with an ICE restart
+    Disconnected {
+        remote_id: String,
+    },
+    Closed {
+        remote_id: String,
+    },
+    /// The session ran out of its lifetime, `reason` tells which limit it hit
+    Expired {
+        remote_id: String,
+        reason: String,
+    },
+}
+
+/// The peer connection of a portal or proxy, closed once dropped
+pub struct Connection {
+    pub local_id: String,
+    pub remote_id: String,
+    pub pc: Arc<RTCPeerConnection>,
+    connected: Arc<Notify>,
+    connect_timeout: Duration,
+    event_tx: mpsc::UnboundedSender<PeerEvent>,
+    keepalive: Keepalive,
+    control: OnceLock<Arc<Control>>,
+    /// Why the proxy refused the offer
+    rejection: OnceLock<String>,
+}
+
+impl Connection {
+    pub(crate) async fn new(
+        local_id: String,
+        remote_id: String,
+        config: &PeerConfig,
+        event_tx: mpsc::UnboundedSender<PeerEvent>,
+    ) -> Result<Self> {
+        let pc = Arc::new(RTC_API.new_peer_connection(config.to_rtc_configuration()).await?);
+        let connected = Arc::new(Notify::new());
+        Self::setup_ice_candidate_callback(&pc, event_tx.clone(), &local_id, &remote_id);
+        Self::setup_connection_state_callback(
+            &pc,
+            Arc::clone(&connected),
+            event_tx.clone(),
+            &remote_id,
+            config.ice_restart_grace,
+        );
+        Ok(Self {
+            local_id,
+            remote_id,
+            pc,
+            connected,
+            connect_timeout: config.connect_timeout,
+            event_tx,
+            keepalive: Keepalive::from_config(config),
+            control: OnceLock::new(),
+            rejection: OnceLock::new(),
+        })
+    }
+
+    /// Open the control channel, done by the portal before its offer
+    pub(crate) async fn open_control(&self) -> Result<()> {
+        let dc = self.pc.create_data_channel(CONTROL_LABEL, None).await?;
+        self.accept_control(dc);
+        Ok(())
+    }
+
+    /// Serve the control channel the portal opened, or this side's own one
+    pub(crate) fn accept_control(&self, dc: Arc<RTCDataChannel>) {
+        let control = Control::new(
+            dc,
+            Arc::downgrade(&self.pc),
+            self.keepalive,
+            self.event_tx.clone(),
+            &self.remote_id,
+        );
+        if self.control.set(control).is_err() {
+            warn!("Second control channel from {} ignored", self.remote_id);
+        }
+    }
+
+    /// Wait until the peer connection is up, false if it isn't within `deadline`
+    /// or was rejected
+    pub(crate) async fn connected_within(&self, deadline: Duration) -> bool {
+        timeout(deadline, self.connected.notified()).await.is_ok() && self.rejection.get().is_none()
+    }
+
+    /// Stop waiting for a connection the proxy refused
+    pub(crate) fn reject(&self, reason: String) {
+        if self.rejection.set(reason).is_ok() {
+            self.connected.notify_one();
+        }
+    }
+
+    /// Round-trip figures of the link, `None` until the remote answered a ping
+    pub fn link_stats(&self) -> Option<LinkStats> {
+        self.control.get()?.stats()
+    }
+
+    /// The candidate pair ICE selected, `None` until connected
+    pub async fn path(&self) -> Option<ConnectionPath> {
+        let mut path = ConnectionPath::of(&self.pc).await?;
+        // ICE doesn't measure round trips of the selected pair, the pings do
+        path.rtt = self.link_stats().map(|s| s.smoothed_rtt);
+        Some(path)
+    }
+
+    /// Send a signaling message from this side to the remote
+    pub(crate) fn send_signal(&self, signal_type: SignalType, payload: String) -> Result<()> {
+        let payload = SignalPayload { from_id: self.local_id.clone(), payload, signal_type };
+        self.event_tx.send(PeerEvent::Signal { remote_id: self.remote_id.clone(), payload })?;
+        Ok(())
+    }
+
+    fn setup_ice_candidate_callback(
+        pc: &RTCPeerConnection,
+        event_tx: mpsc::UnboundedSender<PeerEvent>,
+        local_id: &str,
+        remote_id: &str,
+    ) {
+        let (local_id, remote_id) = (local_id.to_string(), remote_id.to_string());
+        pc.on_ice_candidate(Box::new(move |c| {
+            let event_tx = event_tx.clone();
+            let local_id = local_id.clone();
+            let remote_id = remote_id.clone();
+            Box::pin(async move {
+                if let Some(candidate) = c {
+                    if let Ok(json) = candidate.to_json() {
+                        let payload = SignalPayload {
+                            from_id: local_id,
+                            payload: json.candidate,
+                            signal_type: SignalType::Candidate,
+                        };
+                        let _ = event_tx.send(PeerEvent::Signal { remote_id, payload });
+                    }
+                }
+            })
+        }));
+    }
+
+    /// Report state changes, a disconnected connection is closed unless it
+    /// recovered within `grace`. The peer connection is held weakly, it keeps
+    /// its callbacks.
+    fn setup_connection_state_callback(
+        pc: &Arc<RTCPeerConnection>,
+        notify: Arc<Notify>,
+        event_tx: mpsc::UnboundedSender<PeerEvent>,
+        remote_id: &str,
+        grace: Duration,
+    ) {
+        let remote_id = remote_id.to_string();
+        let weak_pc = Arc::downgrade(pc);
+        // Tells a grace timer whether the connection was lost again since
+        let disconnects = Arc::new(AtomicU64::new(0));
+        pc.on_peer_connection_state_change(Box::new(move |state| {
+            let notify = notify.clone();
+            let event_tx = event_tx.clone();
+            let rid = remote_id.clone();
+            let disconnects = Arc::clone(&disconnects);
+            let weak_pc = weak_pc.clone();
+            Box::pin(async move {
+                trace!("PeerConnection state for {}: {:?}", rid, state);
+                match state {
+                    RTCPeerConnectionState::Connected => {
+                        notify.notify_one();
+                        let _ = event_tx.send(PeerEvent::Connected { remote_id: rid });
+                    }
+                    RTCPeerConnectionState::Disconnected if !grace.is_zero() => {
+                        debug!(
+                            "PeerConnection disconnected for {}, {}s to recover",
+                            rid,
+                            grace.as_secs()
+                        );
+                        let _ = event_tx.send(PeerEvent::Disconnected { remote_id: rid.clone() });
+                        let disconnect = disconnects.fetch_add(1, Ordering::SeqCst) + 1;
+                        tokio::spawn(async move {
+                            sleep(grace).await;
+                            if disconnects.load(Ordering::SeqCst) == disconnect {
+                                Self::close_unless_recovered(weak_pc, grace, &rid).await;
+                            }
+                        });
+                    }
+                    RTCPeerConnectionState::Disconnected => {
+                        debug!("PeerConnection disconnected for {}", rid);
+                        let _ = event_tx.send(PeerEvent::Closed { remote_id: rid });
+                    }
+                    RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed => {
+                        let _ = event_tx.send(PeerEvent::Closed { remote_id: rid.clone() });
+                        if state == RTCPeerConnectionState::Failed {
+                            warn!("PeerConnection failed for {}", rid);
+                        }
+                    }
+                    _ => {}
+                }
+            })
+        }));
+    }
+
+    /// Close a peer connection that is still not connected at the end of its
+    /// grace period, which reports it closed
+    async fn close_unless_recovered(pc: Weak<RTCPeerConnection>, grace: Duration, remote_id: &str) {
+        let Some(pc) = pc.upgrade() else {
+            return;
+        };
+        if matches!(
+            pc.connection_state(),
+            RTCPeerConnectionState::Connected
+                | RTCPeerConnectionState::Failed
+                | RTCPeerConnectionState::Closed
+        ) {
+            return;
+        }
+        warn!("PeerConnection to {} did not recover within {}s", remote_id, grace.as_secs());
+        if let Err(e) = pc.close().await {
+            warn!("Failed to close PeerConnection for {}: {}", remote_id, e);
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let pc = self.pc.clone();
+        let remote_id = self.remote_id.clone();
+        tokio::spawn(async move {
+            if let Err(e) = pc.close().await {
+                warn!("Failed to close PeerConnection for {}: {}", remote_id, e);
+            }
+        });
+    }
+}
+
+/// One side of a peer connection, a portal or a proxy
+#[async_trait]
+pub trait Peer: Send + Sync {
+    fn local_id(&self) -> &str;
+
+    fn remote_id(&self) -> &str;
+
+    /// The current peer connection
+    fn connection(&self) -> Arc<Connection>;
+
+    /// Handle the remote's session description, candidates are handled by
+    /// [`Peer::handle_signal_message`]
+    async fn handle_description(&self, msg: SignalPayload) -> Result<()>;
+
+    /// Compression counters, `None` unless compression was negotiated
+    fn compression_stats(&self) -> Option<Arc<CompressionStats>>;
+
+    /// Round-trip time, jitter and loss of the link, `None` until measured
+    fn link_stats(&self) -> Option<LinkStats> {
+        self.connection().link_stats()
+    }
+
+    /// Candidate types and addresses of the connection, `None` until connected
+    async fn connection_path(&self) -> Option<ConnectionPath> {
+        self.connection().path().await
+    }
+
+    async fn handle_signal_message(&self, msg: SignalPayload) -> Result<()> {
+        match msg.signal_type {
+            SignalType::Candidate => {
+                let candidate =
+                    RTCIceCandidateInit { candidate: msg.payload, ..Default::default() };
+                self.connection().pc.add_ice_candidate(candidate).await?;
+                trace!("ICE candidate added for {}", self.remote_id());
+                Ok(())
+            }
+            _ => self.handle_description(msg).await,
+        }
+    }
+
+    async fn wait_connected(&self) -> Result<()> {
+        let connection = self.connection();
+        let connected = connection.connected.notified();
+        match timeout(connection.connect_timeout, connected).await {
+            Ok(_) => match connection.rejection.get() {
+                Some(reason) => {
+                    Err(anyhow!("{} rejected the connection: {}", connection.remote_id, reason))
+                }
+                None => {
+                    debug!("Connected to {}", connection.remote_id);
+                    Ok(())
+                }
+            },
+            Err(_) => Err(anyhow!(
+                "Timeout waiting for connection to {} ({}s)",
+                connection.remote_id,
+                connection.connect_timeout.as_secs()
+            )),
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connection().pc.connection_state() == RTCPeerConnectionState::Connected
+    }
+
+    /// False once the connection was lost or closed
+    fn is_active(&self) -> bool {
+        !matches!(
+            self.connection().pc.connection_state(),
+            RTCPeerConnectionState::Failed
+                | RTCPeerConnectionState::Closed
+                | RTCPeerConnectionState::Disconnected
+        )
+    }
+}
+
+/// Report the session with `remote_id` as expired once it ran out of `lifetime`,
+/// idle while `activity` counts no stream. `None` for an unlimited lifetime.
+pub(crate) fn spawn_expiry(
+    remote_id: &str,
+    lifetime: Lifetime,
+    activity: Arc<Activity>,
+    event_tx: mpsc::UnboundedSender<PeerEvent>,
+) -> Option<AbortHandle> {
+    if lifetime.is_unlimited() {
+        return None;
+    }
+    let remote_id = remote_id.to_string();
+    let task = tokio::spawn(async move {
+        let reason = lifetime.expired(|| activity.idle_since()).await;
+        let _ = event_tx.send(PeerEvent::Expired { remote_id, reason });
+    });
+    Some(task.abort_handle())
+}
@@ -0,0 +1,329 @@
+//! Pre-negotiated DataChannels (`negotiated: true`) shared by both peers.
+//!
+//! The id range is agreed on in the SDP exchange and both sides create the
+//! channels up front, so claiming one for a new connection needs no DCEP
+//! handshake. A channel carries one stream at a time: the portal starts a use
+//! with an `open:<request>` text message, data flows as binary messages, and a
+//! `close` text message from each side ends the use and frees the channel. A
+//! refused use is ended with `close:<reason>` instead.
+
+use crate::binder::{AcceptFn, Tunnel};
+use crate::refusal::StreamError;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, OnceLock};
+use tokio::sync::{mpsc, Mutex};
+use tracing::{debug, trace, warn};
+use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::peer_connection::RTCPeerConnection;
+
+/// First pre-negotiated channel id, far above the ids DCEP hands out
+pub(crate) const POOL_BASE_ID: u16 = 1024;
+
+const OPEN_PREFIX: &str = "open:";
+const CLOSE: &str = "close";
+
+pub(crate) struct Pool {
+    channels: Vec<Arc<PooledChannel>>,
+    claims: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Occupancy of a channel pool and how often it served a stream
+#[derive(Debug, Clone, Default)]
+pub struct PoolStats {
+    pub channels: usize,
+    /// Channels whose DataChannel is open
+    pub open: usize,
+    /// Open channels carrying a stream or still closing one
+    pub busy: usize,
+    /// Streams started on a pooled channel
+    pub claims: u64,
+    /// Streams that found no idle channel and fell back to a dedicated one
+    pub misses: u64,
+}
+
+impl fmt::Display for PoolStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} channels open, {} busy, {} streams pooled, {} fell back",
+            self.open, self.channels, self.busy, self.claims, self.misses
+        )
+    }
+}
+
+impl Pool {
+    /// Create the `count` channels starting at `base`. The portal side claims
+    /// them, the proxy side passes `accept` to serve incoming uses.
+    pub async fn create(
+        pc: &RTCPeerConnection,
+        base: u16,
+        count: u16,
+        accept: Option<AcceptFn>,
+    ) -> Result<Arc<Self>> {
+        let mut dcs = Vec::with_capacity(count as usize);
+        for id in base..base.saturating_add(count) {
+            let init = RTCDataChannelInit { negotiated: Some(id), ..Default::default() };
+            dcs.push(pc.create_data_channel(&format!("POOL#{}", id), Some(init)).await?);
+        }
+
+        let channels = dcs.into_iter().map(|dc| PooledChannel::new(dc, accept.clone())).collect();
+        debug!("Channel pool created: {}..{}", base, base.saturating_add(count));
+        Ok(Arc::new(Self { channels, claims: AtomicU64::new(0), misses: AtomicU64::new(0) }))
+    }
+
+    /// Claim an idle, open channel and start a stream on it
+    pub async fn claim(&self, request: &str) -> Option<Arc<dyn Tunnel>> {
+        let claimed = self
+            .channels
+            .iter()
+            .filter(|c| c.dc.ready_state() == RTCDataChannelState::Open)
+            .find_map(|c| c.try_start().map(|stream| (c, stream)));
+        let Some((channel, stream)) = claimed else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        self.claims.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = channel.dc.send_text(format!("{}{}", OPEN_PREFIX, request)).await {
+            warn!("{} open failed: {}", channel.dc.label(), e);
+            return None;
+        }
+        Some(stream)
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let open: Vec<_> = self
+            .channels
+            .iter()
+            .filter(|c| c.dc.ready_state() == RTCDataChannelState::Open)
+            .collect();
+        PoolStats {
+            channels: self.channels.len(),
+            open: open.len(),
+            busy: open.iter().filter(|c| !c.is_idle()).count(),
+            claims: self.claims.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Use {
+    generation: u64,
+    incoming: Option<mpsc::Sender<Bytes>>,
+    local_closed: bool,
+    remote_closed: bool,
+    refusal: Arc<OnceLock<StreamError>>,
+}
+
+impl Use {
+    /// Both sides closed it, so the channel may carry a new one
+    fn is_idle(&self) -> bool {
+        self.local_closed && self.remote_closed
+    }
+}
+
+struct PooledChannel {
+    dc: Arc<RTCDataChannel>,
+    generation: AtomicU64,
+    current: StdMutex<Use>,
+    /// Keeps data of a finished use from trailing its close message
+    send_lock: Mutex<()>,
+}
+
+impl PooledChannel {
+    fn new(dc: Arc<RTCDataChannel>, accept: Option<AcceptFn>) -> Arc<Self> {
+        let channel = Arc::new(Self {
+            dc: Arc::clone(&dc),
+            generation: AtomicU64::new(0),
+            current: StdMutex::new(Use {
+                local_closed: true,
+                remote_closed: true,
+                ..Default::default()
+            }),
+            send_lock: Mutex::new(()),
+        });
+
+        let weak = Arc::downgrade(&channel);
+        dc.on_message(Box::new(move |msg: DataChannelMessage| {
+            let channel = weak.upgrade();
+            let accept = accept.clone();
+            Box::pin(async move {
+                let Some(channel) = channel else { return };
+                if !msg.is_string {
+                    let incoming = channel.current.lock().unwrap().incoming.clone();
+                    if let Some(tx) = incoming {
+                        let _ = tx.send(msg.data).await;
+                    }
+                    return;
+                }
+
+                let text = String::from_utf8_lossy(&msg.data);
+                if let Some(request) = text.strip_prefix(OPEN_PREFIX) {
+                    match accept {
+                        Some(accept) => {
+                            let stream = channel.start(&mut channel.current.lock().unwrap());
+                            accept(stream, request.to_string())
+                        }
+                        None => warn!("{} unexpected open", channel.dc.label()),
+                    }
+                } else if let Some(reason) = text.strip_prefix(CLOSE) {
+                    if let Some(reason) = reason.strip_prefix(':') {
+                        let refusal = Arc::clone(&channel.current.lock().unwrap().refusal);
+                        let _ = refusal.set(StreamError::decode(reason));
+                    }
+                    channel.remote_close().await;
+                }
+            })
+        }));
+
+        channel
+    }
+
+    /// Start a use unless the previous one is still closing
+    fn try_start(self: &Arc<Self>) -> Option<Arc<dyn Tunnel>> {
+        let mut current = self.current.lock().unwrap();
+        if !current.is_idle() {
+            return None;
+        }
+        Some(self.start(&mut current))
+    }
+
+    fn is_idle(&self) -> bool {
+        self.current.lock().unwrap().is_idle()
+    }
+
+    /// Install a new use in `current`, which the caller holds locked
+    fn start(self: &Arc<Self>, current: &mut Use) -> Arc<dyn Tunnel> {
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        let (tx, rx) = mpsc::channel(1);
+        let refusal = Arc::new(OnceLock::new());
+        *current = Use {
+            generation,
+            incoming: Some(tx),
+            refusal: Arc::clone(&refusal),
+            ..Default::default()
+        };
+        trace!("{} use {} started", self.dc.label(), generation);
+        Arc::new(PooledStream {
+            channel: Arc::clone(self),
+            generation,
+            label: format!("{}.{}", self.dc.label(), generation),
+            incoming: Mutex::new(rx),
+            refusal,
+        })
+    }
+
+    async fn local_close(self: &Arc<Self>, generation: u64, error: Option<&StreamError>) {
+        let _guard = self.send_lock.lock().await;
+        {
+            let mut current = self.current.lock().unwrap();
+            if current.generation != generation || current.local_closed {
+                return;
+            }
+            current.local_closed = true;
+        }
+        let message = match error {
+            None => CLOSE.to_string(),
+            Some(error) => format!("{}:{}", CLOSE, error.encode()),
+        };
+        let _ = self.dc.send_text(message).await;
+    }
+
+    /// The remote ended the current use: stop delivering to the socket and
+    /// answer with our own close unless it already crossed on the wire
+    async fn remote_close(self: &Arc<Self>) {
+        let _guard = self.send_lock.lock().await;
+        let reply = {
+            let mut current = self.current.lock().unwrap();
+            if current.remote_closed {
+                return;
+            }
+            current.remote_closed = true;
+            current.incoming = None;
+            !std::mem::replace(&mut current.local_closed, true)
+        };
+        if reply {
+            let _ = self.dc.send_text(CLOSE).await;
+        }
+        trace!("{} idle", self.dc.label());
+    }
+
+    fn is_current(&self, generation: u64) -> bool {
+        let current = self.current.lock().unwrap();
+        current.generation == generation && !current.local_closed && !current.remote_closed
+    }
+}
+
+struct PooledStream {
+    channel: Arc<PooledChannel>,
+    generation: u64,
+    label: String,
+    incoming: Mutex<mpsc::Receiver<Bytes>>,
+    refusal: Arc<OnceLock<StreamError>>,
+}
+
+#[async_trait]
+impl Tunnel for PooledStream {
+    fn label(&self) -> &str {
+        &self.label
+    }
+
+    async fn send(&self, data: Bytes) -> Result<()> {
+        let _guard = self.channel.send_lock.lock().await;
+        if !self.channel.is_current(self.generation) {
+            return Err(anyhow!("stream closed"));
+        }
+        self.channel.dc.send(&data).await?;
+        Ok(())
+    }
+
+    async fn recv(&self) -> Option<Bytes> {
+        self.incoming.lock().await.recv().await
+    }
+
+    async fn close(&self) {
+        self.channel.local_close(self.generation, None).await;
+    }
+
+    async fn reject(&self, error: &StreamError) {
+        self.channel.local_close(self.generation, Some(error)).await;
+    }
+
+    fn refusal(&self) -> Option<StreamError> {
+        self.refusal.get().cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+
+    #[test]
+    fn test_try_start_claims_once() {
+        for _ in 0..50 {
+            let channel = PooledChannel::new(Arc::new(RTCDataChannel::default()), None);
+            let barrier = Arc::new(Barrier::new(8));
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    let channel = Arc::clone(&channel);
+                    let barrier = Arc::clone(&barrier);
+                    std::thread::spawn(move || {
+                        barrier.wait();
+                        channel.try_start().is_some()
+                    })
+                })
+                .collect();
+            let claimed = handles.into_iter().map(|h| h.join().unwrap()).filter(|&c| c).count();
+            assert_eq!(claimed, 1);
+        }
+    }
+}
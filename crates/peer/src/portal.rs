@@ -1,38 +1,138 @@
-use crate::binder::spawn_dc_socket_bridge;
-use crate::config::{PeerConfig, RTC_API};
-use anyhow::Result;
+use crate::binder::{spawn_bridge, spawn_dc_socket_bridge, Activity, Layers, Listener};
+use crate::compression::{CompressionStats, Compressor};
+use crate::config::{AddrOptions, ChannelProfile, PeerConfig, RateLimit, StreamMode};
+use crate::files::{self, Transfer, FILES_SERVICE};
+use crate::http_proxy;
+use crate::mux::{Mux, MUX_LABEL};
+use crate::negotiation::SessionOptions;
+use crate::peer::{self, Connection, Peer, PeerEvent};
+use crate::pool::{Pool, PoolStats};
+use crate::refusal::StreamError;
+#[cfg(unix)]
+use crate::serial::Pty;
+use crate::serial::PTY_SCHEME;
+use crate::service::{acceptor, serve_data_channel, ServiceMap, StreamRequest};
+use crate::shaping::Shaper;
+use crate::shell::{self, SHELL_ADDR, SHELL_SERVICE};
+use crate::socks5::{self, Socks5Config};
+use crate::stdio::{Stdio, STDIO_ADDR};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
 use chrono::Utc;
 use signal::{SignalPayload, SignalType};
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, OnceLock, Weak};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
 use tokio::net::TcpListener;
 #[cfg(unix)]
 use tokio::net::UnixListener;
-use tokio::sync::{mpsc, Notify};
+use tokio::sync::{mpsc, watch, Mutex, Notify};
 use tokio::task::AbortHandle;
-use tokio::time::timeout;
-use tracing::{debug, error, trace, warn};
+use tokio::time::{timeout, timeout_at, Instant};
+use tracing::{debug, error, info, trace, warn};
 use webrtc::data_channel::RTCDataChannel;
-use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::peer_connection::offer_answer_options::RTCOfferOptions;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc::peer_connection::RTCPeerConnection;
 
-#[derive(Debug, Clone)]
-pub enum PortalEvent {
-    Candidate { remote_id: String, payload: SignalPayload },
-    Offer { remote_id: String, payload: SignalPayload },
-    Connected { remote_id: String },
-    Closed { remote_id: String },
-}
+/// Buffer between the portal's own streams and their users
+const DUPLEX_BUFFER_SIZE: usize = 64 * 1024;
+
+/// How long a persistent portal holds a new connection while reconnecting
+const RELINK_WAIT: Duration = Duration::from_secs(10);
 
 pub struct Portal {
-    pub local_id: String,
-    pub remote_id: String,
+    /// Address of the listener the portal was created with
     pub addr_uri: String,
     pub config: PeerConfig,
-    pc: Arc<RTCPeerConnection>,
-    connected_notify: Arc<Notify>,
-    listener_handle: AbortHandle,
+    /// Listeners by their target, a service name or a `host:port` destination,
+    /// all sharing the peer connection
+    listeners: StdMutex<HashMap<String, Listener>>,
+    dialer: Arc<Dialer>,
+    shaper: Arc<Shaper>,
+    /// Local targets of reverse forwards, by the proxy side address
+    reverse: Arc<ServiceMap>,
+    /// Disconnects a lazy portal once idle
+    idle_timer: Option<AbortHandle>,
+    /// Reports the portal as expired once it ran out of its session lifetime
+    expiry: Option<AbortHandle>,
+}
+
+/// What the connections of a listener are forwarded to
+enum Forward {
+    /// The same encoded request for every connection
+    Request(String),
+    /// Each SOCKS5 client names its own destination
+    Socks5(Arc<Socks5Config>),
+    /// Each HTTP proxy client names its own destination
+    HttpProxy,
+}
+
+impl Forward {
+    /// The listener's key in the portal and what it forwards to
+    fn from_options(addr_uri: &str, options: &AddrOptions) -> (String, Self) {
+        if addr_uri == SHELL_ADDR {
+            let request = StreamRequest::service(SHELL_SERVICE).encode();
+            return (SHELL_SERVICE.to_string(), Self::Request(request));
+        }
+        match &options.socks5 {
+            Some(config) => ("socks5".to_string(), Self::Socks5(Arc::new(config.clone()))),
+            None if options.http_proxy => ("http-proxy".to_string(), Self::HttpProxy),
+            None => {
+                let request = options.request();
+                (request.target().to_string(), Self::Request(request.encode()))
+            }
+        }
+    }
+}
+
+/// Maps each accepted local connection onto a stream of the peer connection
+struct Dialer {
+    local_id: String,
+    remote_id: String,
+    profile: ChannelProfile,
+    /// The current peer connection
+    link: StdMutex<Arc<Link>>,
+    /// Notified when a reconnection replaced the link
+    relinked: Notify,
+    /// Hold connections while the link is down instead of refusing them
+    persistent: bool,
+    /// What new links are opened with
+    config: PeerConfig,
+    event_tx: mpsc::UnboundedSender<PeerEvent>,
+    reverse: Arc<ServiceMap>,
+    /// Held while a lazy portal connects, so concurrent connections share one link
+    dialing: Mutex<()>,
+    /// Whether the remote is online, a lazy portal waits for it before offering
+    online: watch::Sender<bool>,
+    activity: Arc<Activity>,
+    /// Notified when the stream of a `stdio://` or `shell://` listener ended
+    stdio_closed: Arc<Notify>,
+    /// Exit status of the command behind a `shell://` listener
+    shell_status: OnceLock<i32>,
+}
+
+/// One peer connection of a portal, a persistent portal replaces it with a new
+/// one when it is lost
+struct Link {
+    connection: Arc<Connection>,
+    /// Whether the offer was sent, a lazy portal holds its first link unoffered
+    offered: AtomicBool,
+    mux: Option<Arc<Mux>>,
+    /// Set once the answer arrived, connections wait for it
+    session: OnceLock<Session>,
+    session_ready: Notify,
+}
+
+/// What the proxy agreed on in its answer
+struct Session {
+    pool: Option<Arc<Pool>>,
+    layers: Layers,
 }
 
 impl Portal {
@@ -40,138 +140,307 @@ impl Portal {
         local_id: String,
         remote_id: String,
         addr_uri: String,
-        config: PeerConfig,
-        event_tx: mpsc::UnboundedSender<PortalEvent>,
+        mut config: PeerConfig,
+        event_tx: mpsc::UnboundedSender<PeerEvent>,
+        reverse: Arc<ServiceMap>,
     ) -> Result<Arc<Self>> {
-        let rtc_config = config.to_rtc_configuration();
-        let pc = Arc::new(RTC_API.new_peer_connection(rtc_config).await?);
-        let connected_notify = Arc::new(Notify::new());
-
-        Self::setup_ice_candidate_callback(
-            &pc,
-            event_tx.clone(),
-            local_id.clone(),
-            remote_id.clone(),
-        );
-        Self::setup_connection_state_callback(
-            &pc,
-            connected_notify.clone(),
-            event_tx.clone(),
-            remote_id.clone(),
-        );
-
-        let dc = pc.create_data_channel("DEFAULT", None).await?;
-        dc.on_open(Box::new(|| Box::pin(async {})));
-
-        let offer = pc.create_offer(None).await?;
-        pc.set_local_description(offer.clone()).await?;
-        let payload = SignalPayload {
-            from_id: local_id.clone(),
-            payload: offer.sdp,
-            signal_type: SignalType::Offer,
-        };
-        event_tx.send(PortalEvent::Offer { remote_id: remote_id.clone(), payload })?;
-
-        let listener_handle =
-            Self::start_listener(&addr_uri, pc.clone(), local_id.clone(), remote_id.clone())
-                .await?;
+        let (addr_uri, options) = AddrOptions::parse(&addr_uri)?;
+        options.apply(&mut config);
+        if config.channel_profile != ChannelProfile::Reliable {
+            if config.stream_mode == StreamMode::Dedicated {
+                warn!(
+                    "Channel profile {} may reorder or drop data, streams to {} arrive corrupted \
+                     unless each write is a message of its own",
+                    config.channel_profile, remote_id
+                );
+            } else {
+                warn!(
+                    "{} streams are always reliable, ignoring channel profile",
+                    config.stream_mode
+                );
+            }
+        }
 
-        let portal = Arc::new(Self {
+        let link = Link::new(&local_id, &remote_id, &config, event_tx.clone(), &reverse).await?;
+        if !config.lazy {
+            link.offer(&config, &reverse).await?;
+        }
+        let dialer = Arc::new(Dialer {
             local_id,
             remote_id,
+            profile: config.channel_profile,
+            link: StdMutex::new(link),
+            relinked: Notify::new(),
+            persistent: config.persistent,
+            config: config.clone(),
+            event_tx,
+            reverse: Arc::clone(&reverse),
+            dialing: Mutex::new(()),
+            online: watch::Sender::new(!config.lazy),
+            activity: Arc::default(),
+            stdio_closed: Arc::new(Notify::new()),
+            shell_status: OnceLock::new(),
+        });
+        let idle_timer = config.lazy.then(|| {
+            let task = Dialer::disconnect_when_idle(
+                Arc::downgrade(&dialer),
+                Arc::clone(&dialer.activity),
+                config.lazy_idle_timeout,
+            );
+            tokio::spawn(task).abort_handle()
+        });
+        let expiry = peer::spawn_expiry(
+            &dialer.remote_id,
+            config.session_lifetime,
+            Arc::clone(&dialer.activity),
+            dialer.event_tx.clone(),
+        );
+        let mut listeners = HashMap::new();
+        if !addr_uri.is_empty() {
+            let (target, forward) = Forward::from_options(&addr_uri, &options);
+            let listener =
+                Self::start_listener(&addr_uri, &target, forward, Arc::clone(&dialer)).await?;
+            listeners.insert(target, listener);
+        }
+        let shaper = Shaper::new(None, config.session_rate_limit, config.stream_rate_limit);
+
+        let portal = Arc::new(Self {
             addr_uri,
             config,
-            pc,
-            connected_notify,
-            listener_handle,
+            listeners: StdMutex::new(listeners),
+            dialer,
+            shaper,
+            reverse,
+            idle_timer,
+            expiry,
         });
 
-        debug!("Portal created for {}", portal.remote_id);
+        debug!(
+            "Portal created for {} ({}, {}, compression: {})",
+            portal.remote_id(),
+            portal.config.stream_mode,
+            portal.config.channel_profile,
+            portal.config.compression
+        );
         Ok(portal)
     }
 
-    pub async fn wait_connected(&self) -> Result<()> {
-        match timeout(self.config.connect_timeout, self.connected_notify.notified()).await {
-            Ok(_) => Ok(()),
-            Err(_) => Err(anyhow::anyhow!(
-                "Timeout waiting for connection to {} ({}s)",
-                self.remote_id,
-                self.config.connect_timeout.as_secs()
-            )),
-        }
-    }
-
     pub async fn create_data_channel(&self) -> Result<Arc<RTCDataChannel>> {
-        let label = format!("{}-{}", self.local_id, Utc::now().timestamp_millis());
-        let dc = self.pc.create_data_channel(&label, None).await?;
+        let label = format!("{}-{}", self.local_id(), Utc::now().timestamp_millis());
+        let init = self.config.channel_profile.to_init();
+        let dc = self.connection().pc.create_data_channel(&label, Some(init)).await?;
         Ok(dc)
     }
 
-    pub async fn handle_signal_message(&self, msg: SignalPayload) -> Result<()> {
-        match msg.signal_type {
-            SignalType::Answer => {
-                let answer = RTCSessionDescription::answer(msg.payload)?;
-                self.pc.set_remote_description(answer).await?;
-                trace!("Answer set for {}", self.remote_id);
-            }
-            SignalType::Candidate => {
-                let candidate =
-                    RTCIceCandidateInit { candidate: msg.payload, ..Default::default() };
-                self.pc.add_ice_candidate(candidate).await?;
-                trace!("ICE candidate added for {}", self.remote_id);
-            }
-            _ => warn!("Unexpected message type: {:?}", msg.signal_type),
+    /// Replace a lost peer connection with a new one, sending a new offer. The
+    /// listeners stay, connections held by a persistent portal move over once
+    /// the new connection is up.
+    pub(crate) async fn reconnect(&self) -> Result<()> {
+        self.dialer.relink().await?;
+        debug!("Portal to {} reconnecting", self.remote_id());
+        Ok(())
+    }
+
+    /// Offer new ICE credentials on the current connection, e.g. after a network
+    /// change. Streams stay open if the connection recovers within
+    /// [`PeerConfig::ice_restart_grace`].
+    pub async fn restart_ice(&self) -> Result<()> {
+        let link = self.dialer.link();
+        if link.session.get().is_none() {
+            return Err(anyhow!("Connection to {} not negotiated yet", self.remote_id()));
         }
+        let connection = &link.connection;
+        let options = RTCOfferOptions { ice_restart: true, ..Default::default() };
+        let offer = connection.pc.create_offer(Some(options)).await?;
+        connection.pc.set_local_description(offer.clone()).await?;
+        connection
+            .send_signal(SignalType::Offer, SessionOptions::ice_restart().annotate(&offer)?)?;
+        info!("Restarting ICE with {}", self.remote_id());
         Ok(())
     }
 
+    /// Whether the portal keeps its listeners and reconnects once the connection is lost
+    pub fn is_persistent(&self) -> bool {
+        self.dialer.persistent
+    }
+
+    /// Whether the portal connects on its first connection and disconnects once idle
+    pub fn is_lazy(&self) -> bool {
+        self.config.lazy
+    }
+
+    /// Report the remote's status, which a lazy portal waits for before connecting
+    pub(crate) fn set_online(&self, online: bool) {
+        self.dialer.online.send_replace(online);
+    }
+
+    /// Forward connections to `addr_uri` over this portal's peer connection.
+    /// `stdio://` forwards the process' stdin and stdout instead, `shell://`
+    /// attaches the process' terminal to the proxy's shell.
+    ///
+    /// The target is taken from a `?service=<name>` or `?dest=<host:port>` option,
+    /// or picked by the client per connection with `?socks5` or `?http-proxy`.
+    /// Returns the bound address.
+    pub async fn add_listener(&self, addr_uri: &str) -> Result<String> {
+        let (addr_uri, options) = AddrOptions::parse(addr_uri)?;
+        if options.has_peer_options() {
+            warn!("Options of {} apply to the whole portal, ignored", addr_uri);
+        }
+        let (target, forward) = Forward::from_options(&addr_uri, &options);
+        if let Some(local_addr) = self.local_addr(&target) {
+            debug!(
+                "Target '{}' of {} already listening on {}",
+                target,
+                self.remote_id(),
+                local_addr
+            );
+            return Ok(local_addr);
+        }
+
+        let listener =
+            Self::start_listener(&addr_uri, &target, forward, Arc::clone(&self.dialer)).await?;
+        let local_addr = listener.local_addr.clone();
+        let mut listeners = self.listeners.lock().unwrap();
+        listeners.insert(target, listener);
+        debug!("Listener added to {}, total: {}", self.remote_id(), listeners.len());
+        Ok(local_addr)
+    }
+
+    /// Stop forwarding to `target`, returns false if it had no listener
+    pub fn remove_listener(&self, target: &str) -> bool {
+        let mut listeners = self.listeners.lock().unwrap();
+        let removed = listeners.remove(target).is_some();
+        if removed {
+            debug!("Listener removed from {}, total: {}", self.remote_id(), listeners.len());
+        }
+        removed
+    }
+
+    pub fn listener_count(&self) -> usize {
+        self.listeners.lock().unwrap().len()
+    }
+
+    /// Bound address of the listener forwarding to `target`: a service name, a
+    /// `host:port` destination, `socks5` or `http-proxy`
+    pub fn local_addr(&self, target: &str) -> Option<String> {
+        self.listeners.lock().unwrap().get(target).map(|l| l.local_addr.clone())
+    }
+
     pub async fn close(&self) -> Result<()> {
-        self.listener_handle.abort();
-        self.pc.close().await?;
-        debug!("Closed PeerConnection for {}", self.remote_id);
+        self.listeners.lock().unwrap().clear();
+        self.connection().pc.close().await?;
+        if let Some(stats) = self.compression_stats() {
+            info!("Compression for {}: {}", self.remote_id(), stats);
+        }
+        if let Some(stats) = self.pool_stats() {
+            info!("Channel pool for {}: {}", self.remote_id(), stats);
+        }
+        if let Some(stats) = self.link_stats() {
+            info!("Link to {}: {}", self.remote_id(), stats);
+        }
+        debug!("Closed PeerConnection for {}", self.remote_id());
         Ok(())
     }
 
-    pub fn is_connected(&self) -> bool {
-        self.pc.connection_state() == RTCPeerConnectionState::Connected
+    /// Limit all data this portal sends, `None` lifts the limit
+    pub fn set_session_rate_limit(&self, limit: Option<RateLimit>) {
+        self.shaper.set_session_limit(limit);
+    }
+
+    /// Limit the data each stream of this portal sends, running streams included
+    pub fn set_stream_rate_limit(&self, limit: Option<RateLimit>) {
+        self.shaper.set_stream_limit(limit);
+    }
+
+    /// Wait until the stream of the `stdio://` or `shell://` listener ended
+    pub async fn stdio_closed(&self) {
+        self.dialer.stdio_closed.notified().await;
+    }
+
+    /// Copy `remote_path`, `<root>/<path>` on the proxy's file service, to
+    /// `local_path`, resuming an interrupted copy
+    pub async fn pull(&self, remote_path: &str, local_path: &Path) -> Result<Transfer> {
+        let stream = self.dialer.open_reliable(FILES_SERVICE).await?;
+        files::pull(stream, remote_path, local_path).await
+    }
+
+    /// Copy `local_path` to `remote_path`, `<root>/<path>` on the proxy's file
+    /// service, resuming an interrupted copy
+    pub async fn push(&self, local_path: &Path, remote_path: &str) -> Result<Transfer> {
+        let stream = self.dialer.open_reliable(FILES_SERVICE).await?;
+        files::push(stream, local_path, remote_path).await
+    }
+
+    /// Exit status of the shell of the `shell://` listener, once it ended
+    pub fn shell_status(&self) -> Option<i32> {
+        self.dialer.shell_status.get().copied()
+    }
+
+    /// Occupancy of the current link's channel pool, `None` unless one was
+    /// agreed on
+    pub fn pool_stats(&self) -> Option<PoolStats> {
+        let link = self.dialer.link();
+        let session = link.session.get()?;
+        session.pool.as_ref().map(|pool| pool.stats())
     }
 }
 
 impl Portal {
     async fn start_listener(
         addr_uri: &str,
-        pc: Arc<RTCPeerConnection>,
-        local_id: String,
-        remote_id: String,
-    ) -> Result<AbortHandle> {
-        let abort_handle = if addr_uri.starts_with("unix://") {
+        target: &str,
+        forward: Forward,
+        dialer: Arc<Dialer>,
+    ) -> Result<Listener> {
+        let (handle, local_addr) = if addr_uri == STDIO_ADDR {
+            debug!("Portal bridging stdio for '{}'", target);
+            let handle = tokio::spawn(Self::bridge_stdio(dialer, forward));
+            (handle.abort_handle(), addr_uri.to_string())
+        } else if addr_uri == SHELL_ADDR {
+            #[cfg(unix)]
+            {
+                debug!("Portal attaching the terminal to a shell");
+                let handle = tokio::spawn(Self::bridge_shell(dialer, forward));
+                (handle.abort_handle(), addr_uri.to_string())
+            }
+            #[cfg(not(unix))]
+            return Err(anyhow::anyhow!("Shell not supported on this platform"));
+        } else if let Some(path) = addr_uri.strip_prefix(PTY_SCHEME) {
+            #[cfg(unix)]
+            {
+                let pty = Pty::link(path)?;
+                debug!("Portal pseudo-terminal at {} for '{}'", path, target);
+                let handle = tokio::spawn(Self::bridge_pty(pty, dialer, forward));
+                (handle.abort_handle(), addr_uri.to_string())
+            }
+            #[cfg(not(unix))]
+            return Err(anyhow::anyhow!(
+                "Pseudo-terminals not supported on this platform: {}",
+                path
+            ));
+        } else if addr_uri.starts_with("unix://") {
             #[cfg(unix)]
             {
                 let socket_path = addr_uri.trim_start_matches("unix://");
                 let _ = std::fs::remove_file(socket_path);
                 let listener = UnixListener::bind(socket_path)?;
-                debug!("Portal listening on unix://{}", socket_path);
-                let handle =
-                    tokio::spawn(Self::accept_loop_unix(listener, pc, local_id, remote_id));
-                handle.abort_handle()
+                debug!("Portal listening on unix://{} for '{}'", socket_path, target);
+                let handle = tokio::spawn(Self::accept_loop_unix(listener, dialer, forward));
+                (handle.abort_handle(), addr_uri.to_string())
             }
             #[cfg(not(unix))]
             return Err(anyhow::anyhow!("Unix socket not supported"));
         } else {
             let listener = TcpListener::bind(addr_uri).await?;
-            debug!("Portal listening on {}", addr_uri);
-            let handle = tokio::spawn(Self::accept_loop_tcp(listener, pc, local_id, remote_id));
-            handle.abort_handle()
+            let local_addr = listener.local_addr()?.to_string();
+            debug!("Portal listening on {} for '{}'", local_addr, target);
+            let handle = tokio::spawn(Self::accept_loop_tcp(listener, dialer, forward));
+            (handle.abort_handle(), local_addr)
         };
-        Ok(abort_handle)
+        Ok(Listener { addr_uri: addr_uri.to_string(), local_addr, handle })
     }
 
-    async fn accept_loop_tcp(
-        listener: TcpListener,
-        pc: Arc<RTCPeerConnection>,
-        local_id: String,
-        remote_id: String,
-    ) {
+    async fn accept_loop_tcp(listener: TcpListener, dialer: Arc<Dialer>, forward: Forward) {
         loop {
             let (socket, addr) = match listener.accept().await {
                 Ok(s) => s,
@@ -180,20 +449,18 @@ impl Portal {
                     continue;
                 }
             };
-            debug!("New TCP connection from {} for {}", addr, remote_id);
-            if !Self::handle_new_connection(&pc, &local_id, socket).await {
+            debug!("New TCP connection from {} for {}", addr, dialer.remote_id);
+            if dialer.profile.nodelay() {
+                let _ = socket.set_nodelay(true);
+            }
+            if !dialer.forward(socket, &forward) {
                 break;
             }
         }
     }
 
     #[cfg(unix)]
-    async fn accept_loop_unix(
-        listener: UnixListener,
-        pc: Arc<RTCPeerConnection>,
-        local_id: String,
-        remote_id: String,
-    ) {
+    async fn accept_loop_unix(listener: UnixListener, dialer: Arc<Dialer>, forward: Forward) {
         loop {
             let (socket, _) = match listener.accept().await {
                 Ok(s) => s,
@@ -202,113 +469,463 @@ impl Portal {
                     continue;
                 }
             };
-            debug!("New Unix socket connection for {}", remote_id);
-            if !Self::handle_new_connection(&pc, &local_id, socket).await {
+            debug!("New Unix socket connection for {}", dialer.remote_id);
+            if !dialer.forward(socket, &forward) {
                 break;
             }
         }
     }
 
-    async fn handle_new_connection<S>(pc: &RTCPeerConnection, local_id: &str, socket: S) -> bool
+    /// The process' stdin and stdout as the listener's only connection
+    async fn bridge_stdio(dialer: Arc<Dialer>, forward: Forward) {
+        let stdio = Stdio::new(Arc::clone(&dialer.stdio_closed));
+        dialer.forward(stdio, &forward);
+    }
+
+    /// The process' terminal attached to the proxy's shell
+    #[cfg(unix)]
+    async fn bridge_shell(dialer: Arc<Dialer>, forward: Forward) {
+        let (socket, terminal) = tokio::io::duplex(shell::BUFFER_SIZE);
+        dialer.forward(socket, &forward);
+        match shell::attach(terminal, dialer.config.shell_key.as_ref()).await {
+            Ok(Some(status)) => {
+                let _ = dialer.shell_status.set(status);
+            }
+            Ok(None) => warn!("Shell on {} closed without exit status", dialer.remote_id),
+            Err(e) => warn!("Shell on {} failed: {}", dialer.remote_id, e),
+        }
+        dialer.stdio_closed.notify_one();
+    }
+
+    /// The pseudo-terminal as the listener's only connection, the slave side
+    /// stays open until the listener is removed
+    #[cfg(unix)]
+    async fn bridge_pty(pty: Pty, dialer: Arc<Dialer>, forward: Forward) {
+        dialer.forward(pty.master, &forward);
+        let _slave = pty.slave;
+        std::future::pending::<()>().await;
+    }
+}
+
+impl Dialer {
+    fn link(&self) -> Arc<Link> {
+        Arc::clone(&self.link.lock().unwrap())
+    }
+
+    /// Replace the link with a new one, connections held by [`Dialer::ready`]
+    /// move over
+    async fn relink(&self) -> Result<Arc<Link>> {
+        let link = Link::new(
+            &self.local_id,
+            &self.remote_id,
+            &self.config,
+            self.event_tx.clone(),
+            &self.reverse,
+        )
+        .await?;
+        link.offer(&self.config, &self.reverse).await?;
+        *self.link.lock().unwrap() = Arc::clone(&link);
+        self.relinked.notify_waiters();
+        Ok(link)
+    }
+
+    /// The link to open streams on, once its session options are known. While
+    /// the link is down a persistent portal waits for the next one, up to
+    /// [`RELINK_WAIT`], and a lazy one connects; `None` if there is no link to use.
+    async fn ready(&self) -> Option<Arc<Link>> {
+        if self.config.lazy {
+            return self.dial().await;
+        }
+        loop {
+            let relinked = self.relinked.notified();
+            tokio::pin!(relinked);
+            relinked.as_mut().enable();
+
+            let link = self.link();
+            if link.is_down() {
+                if !self.persistent {
+                    return None;
+                }
+                debug!("Connection to {} is down, holding a connection", self.remote_id);
+                timeout(RELINK_WAIT, relinked).await.ok()?;
+                continue;
+            }
+            tokio::select! {
+                _ = link.wait_session() => return Some(link),
+                _ = &mut relinked => {}
+            }
+        }
+    }
+
+    /// The link of a lazy portal, connected on demand: the first connection
+    /// offers it and later ones replace it once it is down. `None` unless the
+    /// remote is online and the link up within [`PeerConfig::lazy_connect_timeout`].
+    async fn dial(&self) -> Option<Arc<Link>> {
+        // A link being connected isn't idle
+        let _active = self.activity.enter();
+        let connect_timeout = self.config.lazy_connect_timeout;
+        let deadline = Instant::now() + connect_timeout;
+        let _dialing = timeout_at(deadline, self.dialing.lock()).await.ok()?;
+        let link = self.link();
+        if link.offered.load(Ordering::Relaxed) && !link.is_down() {
+            timeout_at(deadline, link.wait_session()).await.ok()?;
+            return Some(link);
+        }
+
+        let mut online = self.online.subscribe();
+        if timeout_at(deadline, online.wait_for(|online| *online)).await.is_err() {
+            warn!("{} not online within {}s", self.remote_id, connect_timeout.as_secs());
+            return None;
+        }
+        info!("Connecting to {} for a new connection", self.remote_id);
+        let link = match link.is_down() {
+            true => self.relink().await,
+            false => link.offer(&self.config, &self.reverse).await.map(|_| link),
+        };
+        let link = match link {
+            Ok(link) => link,
+            Err(e) => {
+                warn!("Connecting to {} failed: {}", self.remote_id, e);
+                return None;
+            }
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if !link.connection.connected_within(remaining).await {
+            warn!("Connection to {} not up within {}s", self.remote_id, connect_timeout.as_secs());
+            let _ = link.connection.pc.close().await;
+            return None;
+        }
+        link.wait_session().await;
+        Some(link)
+    }
+
+    /// Close the link of a lazy portal once it carried no stream for `idle_timeout`
+    async fn disconnect_when_idle(
+        dialer: Weak<Self>,
+        activity: Arc<Activity>,
+        idle_timeout: Duration,
+    ) {
+        loop {
+            let changed = activity.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            if activity.streams.load(Ordering::Relaxed) > 0 {
+                changed.await;
+                continue;
+            }
+            if timeout(idle_timeout, changed.as_mut()).await.is_ok() {
+                continue;
+            }
+
+            let Some(dialer) = dialer.upgrade() else {
+                return;
+            };
+            let link = dialer.link();
+            if link.offered.load(Ordering::Relaxed) && !link.is_down() {
+                info!(
+                    "No stream to {} for {}s, disconnecting",
+                    dialer.remote_id,
+                    idle_timeout.as_secs()
+                );
+                let _ = link.connection.pc.close().await;
+            }
+            drop(dialer);
+            changed.await;
+        }
+    }
+
+    /// Forward an accepted connection off the accept loop, as waiting for the
+    /// link would hold up other clients. Returns false once the peer connection
+    /// is gone for good.
+    fn forward<S>(self: &Arc<Self>, socket: S, forward: &Forward) -> bool
     where
-        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Sync + 'static,
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin + 'static,
     {
-        let state = pc.connection_state();
-        if state == RTCPeerConnectionState::Closed || state == RTCPeerConnectionState::Failed {
-            warn!("PeerConnection closed/failed, stopping accept loop");
-            return false;
+        match forward {
+            Forward::Request(request) => {
+                // Persistent and lazy portals bring the link back when needed
+                if !self.persistent && !self.config.lazy && self.link().is_down() {
+                    warn!("PeerConnection closed/failed, stopping accept loop");
+                    return false;
+                }
+                let dialer = Arc::clone(self);
+                let request = request.clone();
+                tokio::spawn(async move {
+                    let Some(link) = dialer.ready().await else {
+                        warn!("Connection to {} down, refusing a connection", dialer.remote_id);
+                        return;
+                    };
+                    dialer.connect(&link, socket, &request).await;
+                });
+            }
+            Forward::Socks5(config) => {
+                let config = Arc::clone(config);
+                self.spawn_dynamic("SOCKS5", async move {
+                    let mut socket = socket;
+                    let dest = socks5::handshake(&mut socket, &config).await?;
+                    Ok((dest, socket))
+                });
+            }
+            Forward::HttpProxy => self.spawn_dynamic("HTTP proxy", http_proxy::accept(socket)),
+        }
+        true
+    }
+
+    /// Forward to the destination a client names in `handshake`, which waits on
+    /// the client and so runs off the accept loop
+    fn spawn_dynamic<F, S>(self: &Arc<Self>, kind: &'static str, handshake: F)
+    where
+        F: Future<Output = Result<(String, S)>> + Send + 'static,
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let dialer = Arc::clone(self);
+        tokio::spawn(async move {
+            match handshake.await {
+                Ok((dest, socket)) => {
+                    debug!("{} connect to {} via {}", kind, dest, dialer.remote_id);
+                    let Some(link) = dialer.ready().await else {
+                        warn!(
+                            "Connection to {} is down, dropping {} client",
+                            dialer.remote_id, kind
+                        );
+                        return;
+                    };
+                    let request = StreamRequest::dest(dest).encode();
+                    dialer.connect(&link, socket, &request).await;
+                }
+                Err(e) => warn!("{} handshake failed: {}", kind, e),
+            }
+        });
+    }
+
+    /// A stream to `service` over a new reliable DataChannel, which keeps bulk
+    /// transfers off multiplexed and pooled channels
+    async fn open_reliable(&self, service: &str) -> Result<DuplexStream> {
+        let link = self
+            .ready()
+            .await
+            .ok_or_else(|| anyhow!("Connection to {} is down", self.remote_id))?;
+        let session = link.wait_session().await;
+        let label = format!("{}-{}", self.local_id, Utc::now().timestamp_millis());
+        let mut init = ChannelProfile::Reliable.to_init();
+        init.protocol = Some(StreamRequest::service(service).encode());
+        let dc = link.connection.pc.create_data_channel(&label, Some(init)).await?;
+        let (socket, stream) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+        spawn_dc_socket_bridge(dc, socket, &session.layers);
+        Ok(stream)
+    }
+
+    /// Bridge `socket` to a new stream opened with `request`
+    async fn connect<S>(&self, link: &Link, socket: S, request: &str)
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        // Waiting for the answer counts as a stream already
+        let _active = self.activity.enter();
+        let session = link.wait_session().await;
+        let layers = &session.layers;
+        if let Some(mux) = &link.mux {
+            spawn_bridge(mux.open_stream(request), socket, layers);
+            return;
+        }
+        if let Some(pool) = &session.pool {
+            match pool.claim(request).await {
+                Some(stream) => {
+                    spawn_bridge(stream, socket, layers);
+                    return;
+                }
+                None => trace!("Channel pool exhausted, opening a dedicated DataChannel"),
+            }
         }
 
-        let label = format!("{}-{}", local_id, Utc::now().timestamp_millis());
-        let dc = match pc.create_data_channel(&label, None).await {
+        let label = format!("{}-{}", self.local_id, Utc::now().timestamp_millis());
+        let mut init = self.profile.to_init();
+        if !request.is_empty() {
+            init.protocol = Some(request.to_string());
+        }
+        let dc = match link.connection.pc.create_data_channel(&label, Some(init)).await {
             Ok(dc) => dc,
             Err(e) => {
                 error!("create_data_channel failed: {:?}", e);
-                return true;
+                return;
             }
         };
 
-        spawn_dc_socket_bridge(dc, socket);
-        true
+        spawn_dc_socket_bridge(dc, socket, layers);
     }
+}
 
-    fn setup_ice_candidate_callback(
-        pc: &RTCPeerConnection,
-        event_tx: mpsc::UnboundedSender<PortalEvent>,
-        local_id: String,
-        remote_id: String,
-    ) {
-        pc.on_ice_candidate(Box::new(move |c| {
-            let event_tx = event_tx.clone();
-            let local_id = local_id.clone();
-            let remote_id = remote_id.clone();
-            Box::pin(async move {
-                if let Some(candidate) = c {
-                    if let Ok(json) = candidate.to_json() {
-                        let payload = SignalPayload {
-                            from_id: local_id,
-                            payload: json.candidate,
-                            signal_type: SignalType::Candidate,
-                        };
-                        let _ = event_tx.send(PortalEvent::Candidate { remote_id, payload });
-                    }
-                }
-            })
-        }));
+impl Link {
+    /// Create a peer connection to `remote_id`, which waits for [`Link::offer`]
+    async fn new(
+        local_id: &str,
+        remote_id: &str,
+        config: &PeerConfig,
+        event_tx: mpsc::UnboundedSender<PeerEvent>,
+        reverse: &Arc<ServiceMap>,
+    ) -> Result<Arc<Self>> {
+        let connection = Arc::new(
+            Connection::new(local_id.to_string(), remote_id.to_string(), config, event_tx.clone())
+                .await?,
+        );
+        let pc = &connection.pc;
+
+        connection.open_control().await?;
+
+        let mux = match config.stream_mode {
+            StreamMode::Multiplexed => {
+                let dc = pc.create_data_channel(MUX_LABEL, None).await?;
+                Some(Mux::new(dc, true, None))
+            }
+            _ => None,
+        };
+
+        let link = Arc::new(Self {
+            connection: Arc::clone(&connection),
+            offered: AtomicBool::new(false),
+            mux,
+            session: OnceLock::new(),
+            session_ready: Notify::new(),
+        });
+        Self::setup_data_channel_callback(pc, Arc::clone(reverse), Arc::downgrade(&link));
+        Ok(link)
+    }
+
+    /// Send the offer, with the session options asked for
+    async fn offer(&self, config: &PeerConfig, reverse: &ServiceMap) -> Result<()> {
+        let pc = &self.connection.pc;
+        let offer = pc.create_offer(None).await?;
+        pc.set_local_description(offer.clone()).await?;
+        let mut session_options = SessionOptions::from_config(config);
+        session_options.reverse = reverse.names().map(String::from).collect();
+        self.connection.send_signal(SignalType::Offer, session_options.annotate(&offer)?)?;
+        self.offered.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Connections are only accepted once the session options are known
+    async fn wait_session(&self) -> &Session {
+        loop {
+            let ready = self.session_ready.notified();
+            if let Some(session) = self.session.get() {
+                return session;
+            }
+            ready.await;
+        }
     }
 
-    fn setup_connection_state_callback(
+    fn is_down(&self) -> bool {
+        matches!(
+            self.connection.pc.connection_state(),
+            RTCPeerConnectionState::Closed | RTCPeerConnectionState::Failed
+        )
+    }
+
+    /// Channels the proxy opens carry reverse forwarded connections. The link is
+    /// held weakly, the peer connection keeps its callbacks.
+    fn setup_data_channel_callback(
         pc: &RTCPeerConnection,
-        notify: Arc<Notify>,
-        event_tx: mpsc::UnboundedSender<PortalEvent>,
-        remote_id: String,
+        reverse: Arc<ServiceMap>,
+        link: Weak<Link>,
     ) {
-        pc.on_peer_connection_state_change(Box::new(move |state| {
-            let notify = notify.clone();
-            let event_tx = event_tx.clone();
-            let rid = remote_id.clone();
+        pc.on_data_channel(Box::new(move |dc| {
+            let reverse = Arc::clone(&reverse);
+            let link = link.upgrade();
             Box::pin(async move {
-                trace!("PeerConnection state for {}: {:?}", rid, state);
-                match state {
-                    RTCPeerConnectionState::Connected => {
-                        notify.notify_one();
-                        let _ = event_tx.send(PortalEvent::Connected { remote_id: rid });
-                    }
-                    RTCPeerConnectionState::Disconnected => {
-                        debug!("PeerConnection disconnected for {}", rid);
-                        let _ = event_tx.send(PortalEvent::Closed { remote_id: rid });
-                    }
-                    RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed => {
-                        let _ = event_tx.send(PortalEvent::Closed { remote_id: rid.clone() });
-                        if state == RTCPeerConnectionState::Failed {
-                            warn!("PeerConnection failed for {}", rid);
-                        }
-                    }
-                    _ => {}
-                }
+                let Some(session) = link.as_ref().and_then(|l| l.session.get()) else {
+                    warn!("{} opened before the answer, ignored", dc.label());
+                    return;
+                };
+                debug!(
+                    "New reverse DataChannel: {} ({})",
+                    dc.label(),
+                    ChannelProfile::from_data_channel(&dc)
+                );
+                serve_data_channel(dc, &reverse, &session.layers).await;
             })
         }));
     }
 }
 
-impl Drop for Portal {
-    fn drop(&mut self) {
-        self.listener_handle.abort();
+#[async_trait]
+impl Peer for Portal {
+    fn local_id(&self) -> &str {
+        &self.dialer.local_id
+    }
 
-        #[cfg(unix)]
-        if self.addr_uri.starts_with("unix://") {
-            let socket_path = self.addr_uri.trim_start_matches("unix://");
-            let _ = std::fs::remove_file(socket_path);
-        }
+    fn remote_id(&self) -> &str {
+        &self.dialer.remote_id
+    }
 
-        let pc = self.pc.clone();
-        let remote_id = self.remote_id.clone();
-        tokio::spawn(async move {
-            if let Err(e) = pc.close().await {
-                warn!("Failed to close PeerConnection for {}: {}", remote_id, e);
+    fn connection(&self) -> Arc<Connection> {
+        Arc::clone(&self.dialer.link().connection)
+    }
+
+    /// The proxy's answer, with the session options it agreed on
+    async fn handle_description(&self, msg: SignalPayload) -> Result<()> {
+        if msg.signal_type == SignalType::Reject {
+            let reason = StreamError::decode(&msg.payload);
+            warn!("{} rejected the connection, {}", self.remote_id(), reason);
+            self.dialer.link().connection.reject(reason.to_string());
+            return Ok(());
+        }
+        if msg.signal_type != SignalType::Answer {
+            warn!("Unexpected message type: {:?}", msg.signal_type);
+            return Ok(());
+        }
+        let answer = RTCSessionDescription::answer(msg.payload)?;
+        let link = self.dialer.link();
+        if link.session.get().is_some() {
+            // Answer to an ICE restart, the session stays as it was
+            link.connection.pc.set_remote_description(answer).await?;
+            trace!("ICE restart answer set for {}", self.remote_id());
+            return Ok(());
+        }
+        let agreed = SessionOptions::parse(&answer);
+        let compressor = Compressor::new(agreed.compression, self.config.compression_threshold);
+        if agreed.compression != self.config.compression {
+            warn!("{} declined {} compression", self.remote_id(), self.config.compression);
+        }
+        let layers = Layers {
+            shaper: Arc::clone(&self.shaper),
+            compressor,
+            activity: Arc::clone(&self.dialer.activity),
+            lifetime: self.config.stream_lifetime,
+        };
+        for addr in self.reverse.names() {
+            if !agreed.reverse.iter().any(|a| a == addr) {
+                warn!("{} declined reverse forwarding from {}", self.remote_id(), addr);
             }
-        });
+        }
+        // Before the connection comes up, the proxy may open streams right away
+        if let Some(mux) = &link.mux {
+            mux.set_accept(acceptor(Arc::clone(&self.reverse), layers.clone(), None));
+        }
+        let pc = &link.connection.pc;
+        pc.set_remote_description(answer).await?;
+        trace!("Answer set for {}", self.remote_id());
+
+        let pool = match agreed.pool {
+            Some((base, count)) => Some(Pool::create(pc, base, count, None).await?),
+            None => None,
+        };
+        let _ = link.session.set(Session { pool, layers });
+        link.session_ready.notify_waiters();
+        Ok(())
+    }
 
-        debug!("Portal dropped for {}", self.remote_id);
+    fn compression_stats(&self) -> Option<Arc<CompressionStats>> {
+        let link = self.dialer.link();
+        let session = link.session.get()?;
+        session.layers.compressor.as_ref().map(|c| c.stats())
+    }
+}
+
+impl Drop for Portal {
+    fn drop(&mut self) {
+        for timer in [&self.idle_timer, &self.expiry].into_iter().flatten() {
+            timer.abort();
+        }
+        debug!("Portal dropped for {}", self.remote_id());
     }
 }
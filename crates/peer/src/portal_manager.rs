@@ -1,21 +1,36 @@
-use crate::config::PeerConfig;
-use crate::portal::{Portal, PortalEvent};
+use crate::config::{AddrOptions, PeerConfig, RateLimit};
+use crate::control::LinkStats;
+use crate::files::Transfer;
+use crate::path::{self, ConnectionPath};
+use crate::peer::{Peer, PeerEvent};
+use crate::portal::Portal;
+use crate::service::ServiceMap;
 use anyhow::{anyhow, Result};
 use signal::{MqttConfig, Signal, SignalEvent, SignalRole};
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::{mpsc, Notify, RwLock};
-use tokio::task::JoinHandle;
-use tokio::time::timeout;
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio::time::{sleep, timeout};
 use tracing::{debug, error, info, trace, warn};
 
+/// Delay before retrying a failed reconnection, doubled up to [`RECONNECT_MAX_DELAY`]
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
 pub struct PortalManager {
     pub local_id: String,
     pub signal: Arc<Signal>,
     pub config: PeerConfig,
     portals: Arc<RwLock<HashMap<String, Arc<Portal>>>>,
     online_notifiers: Arc<RwLock<HashMap<String, Arc<Notify>>>>,
-    portal_event_tx: mpsc::UnboundedSender<PortalEvent>,
+    portal_event_tx: mpsc::UnboundedSender<PeerEvent>,
+    /// Local targets of reverse forwards, by the proxy side address
+    reverse: Arc<ServiceMap>,
+    /// Reconnections of persistent portals, by remote
+    reconnects: RwLock<HashMap<String, AbortHandle>>,
 }
 
 /// Builder for PortalManager
@@ -24,6 +39,7 @@ pub struct PortalManagerBuilder {
     local_id: Option<String>,
     mqtt_config: Option<MqttConfig>,
     peer_config: Option<PeerConfig>,
+    reverse: ServiceMap,
 }
 
 impl PortalManagerBuilder {
@@ -42,11 +58,26 @@ impl PortalManagerBuilder {
         self
     }
 
+    /// Ask each proxy to listen on `remote_addr` and forward its connections back
+    /// to `local_target`, a `host:port` or `unix://` address on this side. The
+    /// proxy has to allow reverse forwarding.
+    pub fn reverse_forward(
+        mut self,
+        remote_addr: impl Into<String>,
+        local_target: impl Into<String>,
+    ) -> Self {
+        self.reverse.insert(remote_addr, local_target);
+        self
+    }
+
     /// Build and start the PortalManager
     pub async fn run(self) -> Result<(Arc<PortalManager>, JoinHandle<()>)> {
         let local_id = self.local_id.ok_or_else(|| anyhow!("local_id is required"))?;
         let mqtt_config = self.mqtt_config.ok_or_else(|| anyhow!("mqtt config is required"))?;
         let peer_config = self.peer_config.unwrap_or_default();
+        if let Some(addr) = self.reverse.names().find(|a| a.contains(char::is_whitespace)) {
+            return Err(anyhow!("Invalid reverse forwarding address: {}", addr));
+        }
 
         let (signal, signal_event_rx) =
             Signal::new(local_id.clone(), SignalRole::Caller, mqtt_config).await?;
@@ -63,6 +94,8 @@ impl PortalManagerBuilder {
             portals,
             online_notifiers,
             portal_event_tx,
+            reverse: Arc::new(self.reverse),
+            reconnects: RwLock::new(HashMap::new()),
         });
 
         let m = Arc::clone(&manager);
@@ -80,15 +113,33 @@ impl PortalManager {
         PortalManagerBuilder::default()
     }
 
+    /// Create a portal to `remote_id`, or add a listener to the existing one.
+    ///
+    /// A `?service=<name>` option on `addr_uri` picks the service of the proxy and
+    /// `?dest=<host:port>` a destination on its network, all listeners of one
+    /// remote share its peer connection. An empty `addr_uri` connects without
+    /// a listener. A portal created with `?persistent`, or with
+    /// [`PeerConfig::persistent`], keeps its listeners once connected and
+    /// reconnects whenever the connection is lost. One created with `?lazy`, or
+    /// with [`PeerConfig::lazy`], returns once its listener is bound and connects
+    /// on the listener's first connection. A portal that runs out of its
+    /// [`PeerConfig::session_lifetime`] is removed.
     pub async fn create_portal(&self, remote_id: &str, addr_uri: String) -> Result<Arc<Portal>> {
         debug!("Creating portal for remote: {}, addr: {}", remote_id, addr_uri);
 
         if let Some(portal) = self.portals.read().await.get(remote_id).map(Arc::clone) {
             debug!("Portal to {} already exists, reusing", remote_id);
+            if !addr_uri.is_empty() {
+                portal.add_listener(&addr_uri).await?;
+            }
             return Ok(portal);
         }
 
-        self.wait_remote_online(remote_id).await?;
+        let (_, options) = AddrOptions::parse(&addr_uri)?;
+        let lazy = self.config.lazy || options.lazy;
+        if !lazy {
+            self.wait_remote_online(remote_id).await?;
+        }
 
         let portal = Portal::new(
             self.local_id.clone(),
@@ -96,6 +147,7 @@ impl PortalManager {
             addr_uri,
             self.config.clone(),
             self.portal_event_tx.clone(),
+            Arc::clone(&self.reverse),
         )
         .await?;
 
@@ -104,6 +156,11 @@ impl PortalManager {
             portals.insert(remote_id.to_string(), Arc::clone(&portal));
             info!("Portal added: {}, total: {}", remote_id, portals.len());
         }
+        if lazy {
+            // Its status, retained by the broker, arrives right away if online
+            self.signal.subscribe_remote_status(remote_id, SignalRole::Callee).await?;
+            return Ok(portal);
+        }
 
         if let Err(e) = portal.wait_connected().await {
             let mut portals = self.portals.write().await;
@@ -115,9 +172,51 @@ impl PortalManager {
         Ok(portal)
     }
 
+    /// Copy `remote_path`, `<root>/<path>` on the file service of `remote_id`, to
+    /// `local_path`. An interrupted copy is resumed, the file is verified by its
+    /// checksum.
+    pub async fn pull(
+        &self,
+        remote_id: &str,
+        remote_path: &str,
+        local_path: impl AsRef<Path>,
+    ) -> Result<Transfer> {
+        let portal = self.create_portal(remote_id, String::new()).await?;
+        portal.pull(remote_path, local_path.as_ref()).await
+    }
+
+    /// Copy `local_path` to `remote_path`, `<root>/<path>` on the file service of
+    /// `remote_id`. An interrupted copy is resumed, the file is verified by its
+    /// checksum.
+    pub async fn push(
+        &self,
+        remote_id: &str,
+        local_path: impl AsRef<Path>,
+        remote_path: &str,
+    ) -> Result<Transfer> {
+        let portal = self.create_portal(remote_id, String::new()).await?;
+        portal.push(local_path.as_ref(), remote_path).await
+    }
+
+    /// Stop forwarding to one service or destination, the portal is removed with
+    /// its last listener
+    pub async fn remove_service(&self, remote_id: &str, service: &str) -> Result<()> {
+        let Some(portal) = self.portals.read().await.get(remote_id).map(Arc::clone) else {
+            return Ok(());
+        };
+        portal.remove_listener(service);
+        if portal.listener_count() == 0 {
+            self.remove_portal(remote_id).await?;
+        }
+        Ok(())
+    }
+
     pub async fn remove_portal(&self, remote_id: &str) -> Result<()> {
         debug!("Removing portal for: {}", remote_id);
 
+        if let Some(reconnect) = self.reconnects.write().await.remove(remote_id) {
+            reconnect.abort();
+        }
         let portal = {
             let mut portals = self.portals.write().await;
             let p = portals.remove(remote_id);
@@ -141,10 +240,57 @@ impl PortalManager {
         Ok(())
     }
 
-    async fn event_loop(
+    /// Adjust the limit on all data the portal to `remote_id` sends
+    pub async fn set_session_rate_limit(
+        &self,
+        remote_id: &str,
+        limit: Option<RateLimit>,
+    ) -> Result<()> {
+        self.portal(remote_id).await?.set_session_rate_limit(limit);
+        Ok(())
+    }
+
+    /// Adjust the per-stream limit of the portal to `remote_id`
+    pub async fn set_stream_rate_limit(
         &self,
+        remote_id: &str,
+        limit: Option<RateLimit>,
+    ) -> Result<()> {
+        self.portal(remote_id).await?.set_stream_rate_limit(limit);
+        Ok(())
+    }
+
+    /// Renegotiate the path to `remote_id` without dropping its streams, e.g.
+    /// after a network change. Done by itself once the connection is interrupted.
+    pub async fn restart_ice(&self, remote_id: &str) -> Result<()> {
+        self.portal(remote_id).await?.restart_ice().await
+    }
+
+    /// Candidate pair, protocol, ICE round-trip time and traffic of the
+    /// connection to `remote_id`, `None` until connected
+    pub async fn connection_path(&self, remote_id: &str) -> Result<Option<ConnectionPath>> {
+        Ok(self.portal(remote_id).await?.connection_path().await)
+    }
+
+    /// Round-trip time, jitter and loss of the link to `remote_id`, `None` until
+    /// the first ping was answered
+    pub async fn link_stats(&self, remote_id: &str) -> Result<Option<LinkStats>> {
+        Ok(self.portal(remote_id).await?.link_stats())
+    }
+
+    async fn portal(&self, remote_id: &str) -> Result<Arc<Portal>> {
+        self.portals
+            .read()
+            .await
+            .get(remote_id)
+            .map(Arc::clone)
+            .ok_or_else(|| anyhow!("No portal for {}", remote_id))
+    }
+
+    async fn event_loop(
+        self: &Arc<Self>,
         mut signal_event_rx: mpsc::UnboundedReceiver<SignalEvent>,
-        mut portal_event_rx: mpsc::UnboundedReceiver<PortalEvent>,
+        mut portal_event_rx: mpsc::UnboundedReceiver<PeerEvent>,
     ) {
         loop {
             tokio::select! {
@@ -161,18 +307,34 @@ impl PortalManager {
         debug!("PortalManager event loop exited");
     }
 
-    async fn handle_signal_event(&self, event: SignalEvent) -> bool {
+    async fn handle_signal_event(self: &Arc<Self>, event: SignalEvent) -> bool {
         match event {
             SignalEvent::RemoteOnline(remote_id) => {
                 debug!("Remote {} is online", remote_id);
                 if let Some(notifier) = self.online_notifiers.read().await.get(&remote_id) {
                     notifier.notify_one();
                 }
+                if let Some(portal) = self.portals.read().await.get(&remote_id) {
+                    portal.set_online(true);
+                }
             }
             SignalEvent::RemoteOffline(remote_id) => {
                 let mut portals = self.portals.write().await;
-                if portals.remove(&remote_id).is_some() {
-                    info!("Portal removed (offline): {}, total: {}", remote_id, portals.len());
+                match portals.get(&remote_id).map(Arc::clone) {
+                    Some(portal) if portal.is_lazy() => {
+                        portal.set_online(false);
+                        debug!("Remote {} went offline, portal kept until needed", remote_id);
+                    }
+                    Some(portal) if portal.is_persistent() => {
+                        drop(portals);
+                        info!("Remote {} went offline, waiting for it to come back", remote_id);
+                        self.spawn_reconnect(portal).await;
+                    }
+                    Some(_) => {
+                        portals.remove(&remote_id);
+                        info!("Portal removed (offline): {}, total: {}", remote_id, portals.len());
+                    }
+                    None => {}
                 }
             }
             SignalEvent::SignalMessage(msg) => {
@@ -194,51 +356,102 @@ impl PortalManager {
         false
     }
 
-    async fn handle_portal_event(&self, event: PortalEvent) {
+    async fn handle_portal_event(self: &Arc<Self>, event: PeerEvent) {
         match event {
-            PortalEvent::Offer { remote_id, payload } => {
-                trace!("Sending offer to: {}", remote_id);
+            PeerEvent::Signal { remote_id, payload } => {
+                trace!("Sending {:?} to: {}", payload.signal_type, remote_id);
                 if let Err(e) = self
                     .signal
                     .publish_signal_message(&remote_id, &payload, SignalRole::Callee)
                     .await
                 {
-                    error!("Failed to send offer to {}: {}", remote_id, e);
+                    error!("Failed to send {:?} to {}: {}", payload.signal_type, remote_id, e);
                 }
             }
-            PortalEvent::Candidate { remote_id, payload } => {
-                trace!("Sending candidate to: {}", remote_id);
-                if let Err(e) = self
-                    .signal
-                    .publish_signal_message(&remote_id, &payload, SignalRole::Callee)
-                    .await
-                {
-                    error!("Failed to send candidate to {}: {}", remote_id, e);
+            PeerEvent::Connected { remote_id } => {
+                let path = self.connection_path(&remote_id).await.ok().flatten();
+                path::log_connected(&remote_id, path);
+            }
+            PeerEvent::Disconnected { remote_id } => {
+                if let Err(e) = self.restart_ice(&remote_id).await {
+                    warn!("ICE restart with {} failed: {}", remote_id, e);
                 }
             }
-            PortalEvent::Connected { remote_id } => debug!("{} connected", remote_id),
-            PortalEvent::Closed { remote_id } => {
+            PeerEvent::Closed { remote_id } => {
                 let mut portals = self.portals.write().await;
-                portals.remove(&remote_id);
-                info!("Portal {} closed, removed, total: {}", remote_id, portals.len());
+                match portals.get(&remote_id).map(Arc::clone) {
+                    // Closing a replaced connection reports it too
+                    Some(portal) if portal.is_active() => {
+                        debug!("{} close event ignored, still active", remote_id)
+                    }
+                    Some(portal) if portal.is_lazy() => {
+                        info!(
+                            "Portal {} disconnected, connecting on its next connection",
+                            remote_id
+                        )
+                    }
+                    Some(portal) if portal.is_persistent() => {
+                        drop(portals);
+                        info!("Portal {} lost its connection, reconnecting", remote_id);
+                        self.spawn_reconnect(portal).await;
+                    }
+                    _ => {
+                        portals.remove(&remote_id);
+                        info!("Portal {} closed, removed, total: {}", remote_id, portals.len());
+                    }
+                }
+            }
+            PeerEvent::Expired { remote_id, reason } => {
+                info!("Portal {} {}, removing", remote_id, reason);
+                if let Err(e) = self.remove_portal(&remote_id).await {
+                    warn!("Failed to remove portal {}: {}", remote_id, e);
+                }
             }
         }
     }
 
-    async fn wait_remote_online(&self, remote_id: &str) -> Result<()> {
-        let notify = Arc::new(Notify::new());
-        {
-            let mut notifiers = self.online_notifiers.write().await;
-            notifiers.insert(remote_id.to_string(), notify.clone());
-            debug!("Online notifier added: {}, total: {}", remote_id, notifiers.len());
+    /// Reconnect a persistent portal in the background: wait for the remote to be
+    /// online, offer a new connection and wait for it, retrying with backoff
+    async fn spawn_reconnect(self: &Arc<Self>, portal: Arc<Portal>) {
+        let remote_id = portal.remote_id().to_string();
+        let mut reconnects = self.reconnects.write().await;
+        if reconnects.get(&remote_id).is_some_and(|r| !r.is_finished()) {
+            debug!("Portal {} already reconnecting", remote_id);
+            return;
         }
-        self.signal.subscribe_remote_status(remote_id, SignalRole::Callee).await?;
 
-        match timeout(self.config.online_timeout, notify.notified()).await {
-            Ok(_) => {
-                debug!("Remote {} is now online", remote_id);
-                Ok(())
+        let manager = Arc::clone(self);
+        let handle = tokio::spawn(async move {
+            let mut delay = RECONNECT_DELAY;
+            loop {
+                match manager.reconnect(&portal).await {
+                    Ok(()) => {
+                        info!("Portal {} reconnected", portal.remote_id());
+                        return;
+                    }
+                    Err(e) => warn!(
+                        "Reconnecting to {} failed: {}, retrying in {}s",
+                        portal.remote_id(),
+                        e,
+                        delay.as_secs()
+                    ),
+                }
+                sleep(delay).await;
+                delay = (delay * 2).min(RECONNECT_MAX_DELAY);
             }
+        });
+        reconnects.insert(remote_id, handle.abort_handle());
+    }
+
+    async fn reconnect(&self, portal: &Portal) -> Result<()> {
+        self.remote_online(portal.remote_id()).await?;
+        portal.reconnect().await?;
+        portal.wait_connected().await
+    }
+
+    async fn wait_remote_online(&self, remote_id: &str) -> Result<()> {
+        match timeout(self.config.online_timeout, self.remote_online(remote_id)).await {
+            Ok(result) => result,
             Err(_) => {
                 let mut notifiers = self.online_notifiers.write().await;
                 notifiers.remove(remote_id);
@@ -255,4 +468,18 @@ impl PortalManager {
             }
         }
     }
+
+    /// Wait until `remote_id` reports it is online
+    async fn remote_online(&self, remote_id: &str) -> Result<()> {
+        let notify = Arc::new(Notify::new());
+        {
+            let mut notifiers = self.online_notifiers.write().await;
+            notifiers.insert(remote_id.to_string(), notify.clone());
+            debug!("Online notifier added: {}, total: {}", remote_id, notifiers.len());
+        }
+        self.signal.subscribe_remote_status(remote_id, SignalRole::Callee).await?;
+        notify.notified().await;
+        debug!("Remote {} is now online", remote_id);
+        Ok(())
+    }
 }
@@ -1,233 +1,291 @@
-use crate::binder::spawn_dc_socket_bridge;
-use crate::config::{PeerConfig, RTC_API};
+use crate::binder::{Layers, Listener};
+use crate::compression::{CompressionStats, Compressor};
+use crate::config::{ChannelProfile, PeerConfig, RateLimit};
+use crate::control::CONTROL_LABEL;
+use crate::mux::{Mux, MUX_LABEL};
+use crate::negotiation::SessionOptions;
+use crate::peer::{self, Connection, Peer, PeerEvent};
+use crate::pool::Pool;
+use crate::refusal::{StreamError, StreamErrorKind};
+use crate::reverse::{self, Opener};
+use crate::service::{acceptor, reject_data_channel, serve_data_channel, ServiceMap, StreamLimit};
+use crate::shaping::{Shaper, TokenBucket};
 use anyhow::Result;
+use async_trait::async_trait;
 use signal::{SignalPayload, SignalType};
-use std::sync::Arc;
-use tokio::net::TcpStream;
-#[cfg(unix)]
-use tokio::net::UnixStream;
-use tokio::sync::{mpsc, Notify};
-use tokio::time::timeout;
-use tracing::{debug, error, trace, warn};
-use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
-use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use std::sync::{Arc, Mutex as StdMutex, Weak};
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tracing::{debug, info, warn};
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+use webrtc::data_channel::RTCDataChannel;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc::peer_connection::RTCPeerConnection;
 
-#[derive(Debug, Clone)]
-pub enum ProxyEvent {
-    Candidate { remote_id: String, payload: SignalPayload },
-    Answer { remote_id: String, payload: SignalPayload },
-    Connected { remote_id: String },
-    Closed { remote_id: String },
-}
+type Channels = Arc<StdMutex<Vec<Weak<RTCDataChannel>>>>;
 
 #[allow(dead_code)]
 pub struct Proxy {
-    pub local_id: String,
-    pub remote_id: String,
-    pub addr_uri: String,
+    pub services: Arc<ServiceMap>,
     pub config: PeerConfig,
-    pc: Arc<RTCPeerConnection>,
-    connected_notify: Arc<Notify>,
+    connection: Arc<Connection>,
+    mux: Arc<StdMutex<Option<Arc<Mux>>>>,
+    pool: Option<Arc<Pool>>,
+    /// Dedicated channels the portal opened
+    channels: Channels,
+    layers: Layers,
+    /// Listeners opened for the portal's reverse forwards
+    reverse: Vec<Listener>,
+    /// Reports the session as expired once it ran out of its lifetime
+    expiry: Option<AbortHandle>,
 }
 
 impl Proxy {
     pub async fn new(
         local_id: String,
         remote_id: String,
-        addr_uri: String,
+        services: Arc<ServiceMap>,
         config: PeerConfig,
-        event_tx: mpsc::UnboundedSender<ProxyEvent>,
+        event_tx: mpsc::UnboundedSender<PeerEvent>,
         offer: SignalPayload,
+        global_limit: Arc<TokenBucket>,
     ) -> Result<Arc<Self>> {
-        let rtc_config = config.to_rtc_configuration();
-        let pc = Arc::new(RTC_API.new_peer_connection(rtc_config).await?);
-        let connected_notify = Arc::new(Notify::new());
-
-        Self::setup_ice_candidate_callback(
-            &pc,
-            event_tx.clone(),
-            local_id.clone(),
-            remote_id.clone(),
-        );
-        Self::setup_connection_state_callback(
-            &pc,
-            connected_notify.clone(),
-            event_tx.clone(),
-            remote_id.clone(),
+        let connection = Arc::new(
+            Connection::new(local_id.clone(), remote_id.clone(), &config, event_tx.clone()).await?,
         );
-        Self::setup_data_channel_callback(&pc, addr_uri.clone());
+        let pc = Arc::clone(&connection.pc);
 
         let desc = RTCSessionDescription::offer(offer.payload)?;
+        let asked = SessionOptions::parse(&desc);
+        let mut agreed = asked.accept();
+        // Pooled channels left idle by the limit would only cost memory
+        if let (Some((_, count)), Some(max)) = (&mut agreed.pool, config.max_channels) {
+            *count = (*count).min(u16::try_from(max).unwrap_or(u16::MAX));
+        }
+        let limit = config.max_channels.map(StreamLimit::new).map(Arc::new);
+        let layers = Layers {
+            shaper: Shaper::new(
+                Some(global_limit),
+                config.session_rate_limit,
+                config.stream_rate_limit,
+            ),
+            compressor: Compressor::new(agreed.compression, config.compression_threshold),
+            activity: Arc::default(),
+            lifetime: config.stream_lifetime,
+        };
+
+        let mux = Arc::new(StdMutex::new(None));
+        let channels = Channels::default();
+        Self::setup_data_channel_callback(
+            &pc,
+            Arc::downgrade(&connection),
+            Arc::clone(&services),
+            Arc::clone(&mux),
+            Arc::clone(&channels),
+            layers.clone(),
+            limit.clone(),
+        );
         pc.set_remote_description(desc).await?;
 
+        let pool = match agreed.pool {
+            Some((base, count)) => {
+                let accept = acceptor(Arc::clone(&services), layers.clone(), limit);
+                Some(Pool::create(&pc, base, count, Some(accept)).await?)
+            }
+            None => None,
+        };
+
+        let mut reverse = Vec::new();
+        if !asked.reverse.is_empty() && !config.reverse_forwarding {
+            warn!("{} asked for reverse forwarding, which is disabled", remote_id);
+        } else if !asked.reverse.is_empty() {
+            let opener = Arc::new(Opener {
+                pc: Arc::clone(&pc),
+                local_id,
+                profile: config.channel_profile,
+                mux: Arc::clone(&mux),
+                layers: layers.clone(),
+            });
+            if asked.reverse.len() > reverse::MAX_LISTENERS {
+                warn!(
+                    "{} asked for {} reverse listeners, only the first {} are opened",
+                    remote_id,
+                    asked.reverse.len(),
+                    reverse::MAX_LISTENERS
+                );
+            }
+            for addr_uri in asked.reverse.into_iter().take(reverse::MAX_LISTENERS) {
+                if !config.reverse_allow.iter().any(|rule| rule.allows(&addr_uri)) {
+                    warn!("Reverse forwarding from {} is not allowed for {}", addr_uri, remote_id);
+                    continue;
+                }
+                match reverse::listen(&addr_uri, Arc::clone(&opener)).await {
+                    Ok(listener) => {
+                        info!("Reverse forwarding {} to {}", listener.local_addr, remote_id);
+                        agreed.reverse.push(addr_uri);
+                        reverse.push(listener);
+                    }
+                    Err(e) => warn!("Reverse forwarding from {} failed: {}", addr_uri, e),
+                }
+            }
+        }
+
         let answer = pc.create_answer(None).await?;
         pc.set_local_description(answer.clone()).await?;
 
-        let payload = SignalPayload {
-            from_id: local_id.clone(),
-            payload: answer.sdp,
-            signal_type: SignalType::Answer,
-        };
-        event_tx.send(ProxyEvent::Answer { remote_id: remote_id.clone(), payload })?;
+        connection.send_signal(SignalType::Answer, agreed.annotate(&answer)?)?;
 
-        let proxy = Arc::new(Self { local_id, remote_id, addr_uri, config, pc, connected_notify });
+        let expiry = peer::spawn_expiry(
+            &remote_id,
+            config.session_lifetime,
+            Arc::clone(&layers.activity),
+            event_tx,
+        );
+        let proxy = Arc::new(Self {
+            services,
+            config,
+            connection,
+            mux,
+            pool,
+            channels,
+            layers,
+            reverse,
+            expiry,
+        });
         Ok(proxy)
     }
 
-    #[allow(dead_code)]
-    pub async fn wait_connected(&self) -> Result<()> {
-        match timeout(self.config.connect_timeout, self.connected_notify.notified()).await {
-            Ok(_) => {
-                debug!("Proxy connected to {}", self.remote_id);
-                Ok(())
-            }
-            Err(_) => Err(anyhow::anyhow!(
-                "Timeout waiting for connection to {} ({}s)",
-                self.remote_id,
-                self.config.connect_timeout.as_secs()
-            )),
-        }
+    /// Limit all data this proxy sends to its portal, `None` lifts the limit
+    pub fn set_session_rate_limit(&self, limit: Option<RateLimit>) {
+        self.layers.shaper.set_session_limit(limit);
     }
 
-    pub async fn handle_signal_message(&self, msg: SignalPayload) -> Result<()> {
-        match msg.signal_type {
-            SignalType::Candidate => {
-                let candidate =
-                    RTCIceCandidateInit { candidate: msg.payload, ..Default::default() };
-                self.pc.add_ice_candidate(candidate).await?;
-                trace!("ICE candidate added for {}", self.remote_id);
-            }
-            _ => warn!("Unexpected message type: {:?} from {}", msg.signal_type, msg.from_id),
-        }
-        Ok(())
+    /// Limit the data each stream of this proxy sends, running streams included
+    pub fn set_stream_rate_limit(&self, limit: Option<RateLimit>) {
+        self.layers.shaper.set_stream_limit(limit);
     }
 
-    #[allow(dead_code)]
-    pub fn is_connected(&self) -> bool {
-        self.pc.connection_state() == RTCPeerConnectionState::Connected
-    }
-
-    pub fn is_active(&self) -> bool {
-        !matches!(
-            self.pc.connection_state(),
-            RTCPeerConnectionState::Failed
-                | RTCPeerConnectionState::Closed
-                | RTCPeerConnectionState::Disconnected
-        )
+    /// Profiles of the dedicated DataChannels the portal has open
+    pub fn channel_profiles(&self) -> Vec<ChannelProfile> {
+        let channels = self.channels.lock().unwrap();
+        channels
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|dc| dc.ready_state() != RTCDataChannelState::Closed)
+            .map(|dc| ChannelProfile::from_data_channel(&dc))
+            .collect()
     }
 }
 
 impl Proxy {
-    fn setup_ice_candidate_callback(
+    /// The connection is held weakly, the peer connection keeps its callbacks
+    fn setup_data_channel_callback(
         pc: &RTCPeerConnection,
-        event_tx: mpsc::UnboundedSender<ProxyEvent>,
-        local_id: String,
-        remote_id: String,
+        connection: Weak<Connection>,
+        services: Arc<ServiceMap>,
+        mux: Arc<StdMutex<Option<Arc<Mux>>>>,
+        channels: Channels,
+        layers: Layers,
+        limit: Option<Arc<StreamLimit>>,
     ) {
-        pc.on_ice_candidate(Box::new(move |c| {
-            let event_tx = event_tx.clone();
-            let local_id = local_id.clone();
-            let remote_id = remote_id.clone();
+        pc.on_data_channel(Box::new(move |dc| {
+            let services = Arc::clone(&services);
+            let mux = Arc::clone(&mux);
+            let channels = Arc::clone(&channels);
+            let layers = layers.clone();
+            let connection = connection.upgrade();
+            let limit = limit.clone();
             Box::pin(async move {
-                if let Some(candidate) = c {
-                    if let Ok(json) = candidate.to_json() {
-                        let payload = SignalPayload {
-                            from_id: local_id,
-                            payload: json.candidate,
-                            signal_type: SignalType::Candidate,
-                        };
-                        let _ = event_tx.send(ProxyEvent::Candidate { remote_id, payload });
+                if dc.label() == CONTROL_LABEL {
+                    if let Some(connection) = connection {
+                        connection.accept_control(dc);
                     }
+                    return;
                 }
-            })
-        }));
-    }
-
-    fn setup_connection_state_callback(
-        pc: &RTCPeerConnection,
-        notify: Arc<Notify>,
-        event_tx: mpsc::UnboundedSender<ProxyEvent>,
-        remote_id: String,
-    ) {
-        pc.on_peer_connection_state_change(Box::new(move |state| {
-            let notify = notify.clone();
-            let event_tx = event_tx.clone();
-            let rid = remote_id.clone();
-            Box::pin(async move {
-                trace!("PeerConnection state for {}: {:?}", rid, state);
-                match state {
-                    RTCPeerConnectionState::Connected => {
-                        notify.notify_one();
-                        let _ = event_tx.send(ProxyEvent::Connected { remote_id: rid });
-                    }
-                    RTCPeerConnectionState::Disconnected => {
-                        debug!("PeerConnection disconnected for {}", rid);
-                        let _ = event_tx.send(ProxyEvent::Closed { remote_id: rid });
+                if dc.label() == MUX_LABEL {
+                    // Its streams count against the limit, not the channel
+                    let mut mux = mux.lock().unwrap();
+                    if mux.is_some() {
+                        warn!("{} refused, multiplexed already", dc.label());
+                        let message = "One multiplexed DataChannel per connection";
+                        reject_data_channel(
+                            &dc,
+                            &StreamError::new(StreamErrorKind::Failed, message),
+                        );
+                        return;
                     }
-                    RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed => {
-                        let _ = event_tx.send(ProxyEvent::Closed { remote_id: rid.clone() });
-                        if state == RTCPeerConnectionState::Failed {
-                            warn!("PeerConnection failed for {}", rid);
-                        }
-                    }
-                    _ => {}
+                    debug!("New multiplexed DataChannel");
+                    let accept = acceptor(services, layers, limit);
+                    *mux = Some(Mux::new(dc, false, Some(accept)));
+                    return;
                 }
-            })
-        }));
-    }
-
-    fn setup_data_channel_callback(pc: &RTCPeerConnection, addr_uri: String) {
-        pc.on_data_channel(Box::new(move |dc| {
-            let addr_uri = addr_uri.clone();
-            Box::pin(async move {
-                if dc.label() == "DEFAULT" {
+                if let Some(limit) = limit.filter(|l| !l.admit_channel(&dc)) {
+                    warn!("{} refused, {} streams open already", dc.label(), limit.max);
+                    reject_data_channel(&dc, &limit.error());
                     return;
                 }
-                debug!("New DataChannel: {}", dc.label());
-                Self::connect_and_bridge(dc, &addr_uri).await;
+                debug!(
+                    "New DataChannel: {} ({})",
+                    dc.label(),
+                    ChannelProfile::from_data_channel(&dc)
+                );
+                {
+                    let mut channels = channels.lock().unwrap();
+                    channels.retain(|dc| dc.strong_count() > 0);
+                    channels.push(Arc::downgrade(&dc));
+                }
+                serve_data_channel(dc, &services, &layers).await;
             })
         }));
     }
+}
 
-    async fn connect_and_bridge(dc: Arc<webrtc::data_channel::RTCDataChannel>, addr_uri: &str) {
-        if addr_uri.starts_with("unix://") {
-            #[cfg(unix)]
-            {
-                let socket_path = addr_uri.trim_start_matches("unix://");
-                match UnixStream::connect(socket_path).await {
-                    Ok(stream) => {
-                        debug!("Connected to Unix socket: {}", socket_path);
-                        spawn_dc_socket_bridge(dc, stream);
-                    }
-                    Err(e) => error!("Failed to connect to Unix socket {}: {}", socket_path, e),
-                }
-            }
-            #[cfg(not(unix))]
-            error!("Unix socket not supported on this platform");
-        } else {
-            match TcpStream::connect(addr_uri).await {
-                Ok(stream) => {
-                    debug!("Connected to TCP: {}", addr_uri);
-                    spawn_dc_socket_bridge(dc, stream);
-                }
-                Err(e) => error!("Failed to connect to {}: {}", addr_uri, e),
-            }
+#[async_trait]
+impl Peer for Proxy {
+    fn local_id(&self) -> &str {
+        &self.connection.local_id
+    }
+
+    fn remote_id(&self) -> &str {
+        &self.connection.remote_id
+    }
+
+    fn connection(&self) -> Arc<Connection> {
+        Arc::clone(&self.connection)
+    }
+
+    /// The first offer is handled on creation, later ones restart ICE
+    async fn handle_description(&self, msg: SignalPayload) -> Result<()> {
+        if msg.signal_type != SignalType::Offer {
+            warn!("Unexpected message type: {:?} from {}", msg.signal_type, msg.from_id);
+            return Ok(());
         }
+        let pc = &self.connection.pc;
+        pc.set_remote_description(RTCSessionDescription::offer(msg.payload)?).await?;
+        let answer = pc.create_answer(None).await?;
+        pc.set_local_description(answer.clone()).await?;
+        self.connection.send_signal(SignalType::Answer, answer.sdp)?;
+        info!("ICE restarted by {}", self.remote_id());
+        Ok(())
+    }
+
+    /// Compression counters, `None` unless the portal asked for compression
+    fn compression_stats(&self) -> Option<Arc<CompressionStats>> {
+        self.layers.compressor.as_ref().map(|c| c.stats())
     }
 }
 
 impl Drop for Proxy {
     fn drop(&mut self) {
-        let pc = self.pc.clone();
-        let remote_id = self.remote_id.clone();
-        tokio::spawn(async move {
-            if let Err(e) = pc.close().await {
-                warn!("Failed to close PeerConnection for {}: {}", remote_id, e);
-            }
-        });
-
-        debug!("Proxy dropped for {}", self.remote_id);
+        if let Some(expiry) = &self.expiry {
+            expiry.abort();
+        }
+        if let Some(stats) = self.compression_stats() {
+            info!("Compression for {}: {}", self.remote_id(), stats);
+        }
+        if let Some(stats) = self.link_stats() {
+            info!("Link to {}: {}", self.remote_id(), stats);
+        }
+        debug!("Proxy dropped for {}", self.remote_id());
     }
 }
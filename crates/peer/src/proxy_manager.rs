@@ -1,20 +1,43 @@
-use crate::config::PeerConfig;
-use crate::proxy::{Proxy, ProxyEvent};
+use crate::allowlist::{AllowRule, Allowlist};
+use crate::config::{ChannelProfile, PeerConfig, RateLimit};
+use crate::control::LinkStats;
+use crate::exec::{self, EXEC_SCHEME};
+use crate::files::FileRoot;
+use crate::negotiation::SessionOptions;
+use crate::path::{self, ConnectionPath};
+use crate::peer::{Peer, PeerEvent};
+use crate::proxy::Proxy;
+use crate::refusal::{StreamError, StreamErrorKind};
+use crate::serial::{SerialTarget, SERIAL_SCHEME};
+use crate::service::ServiceMap;
+use crate::shaping::TokenBucket;
+use crate::shell::ShellConfig;
 use anyhow::{anyhow, Result};
-use signal::{MqttConfig, Signal, SignalEvent, SignalRole, SignalType};
+use signal::{MqttConfig, Signal, SignalEvent, SignalPayload, SignalRole, SignalType};
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
 use tokio::sync::{mpsc, RwLock};
 use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use tracing::{debug, error, info, trace, warn};
 
+const DEFAULT_OFFER_WINDOW: Duration = Duration::from_secs(60);
+
 pub struct ProxyManager {
     pub local_id: String,
     pub signal: Arc<Signal>,
     pub config: PeerConfig,
-    pub target_addr: String,
+    pub services: Arc<ServiceMap>,
+    /// Terminals for the portals it allows
+    shell: Option<Arc<ShellConfig>>,
     proxies: Arc<RwLock<HashMap<String, Arc<Proxy>>>>,
-    proxy_event_tx: mpsc::UnboundedSender<ProxyEvent>,
+    proxy_event_tx: mpsc::UnboundedSender<PeerEvent>,
+    /// Shared by all proxies, e.g. to protect the device uplink
+    rate_limit: Arc<TokenBucket>,
+    /// Peer connections served at once, further offers are rejected
+    max_connections: Option<usize>,
+    offer_limit: Option<OfferLimit>,
 }
 
 /// Builder for ProxyManager
@@ -23,7 +46,14 @@ pub struct ProxyManagerBuilder {
     local_id: Option<String>,
     mqtt_config: Option<MqttConfig>,
     peer_config: Option<PeerConfig>,
-    target_addr: Option<String>,
+    services: ServiceMap,
+    allowlist: Allowlist,
+    rate_limit: Option<RateLimit>,
+    shell: Option<ShellConfig>,
+    max_connections: Option<usize>,
+    max_offers: Option<u32>,
+    max_total_offers: Option<u32>,
+    offer_window: Option<Duration>,
 }
 
 impl ProxyManagerBuilder {
@@ -42,8 +72,66 @@ impl ProxyManagerBuilder {
         self
     }
 
+    /// Target of connections that don't name a service
     pub fn target_addr(mut self, addr: impl Into<String>) -> Self {
-        self.target_addr = Some(addr.into());
+        self.services.set_default(addr);
+        self
+    }
+
+    /// Expose `addr` as the service `name`, portals pick it per connection
+    pub fn service(mut self, name: impl Into<String>, addr: impl Into<String>) -> Self {
+        self.services.insert(name, addr);
+        self
+    }
+
+    /// Let portals pull files from `root`, and push files into it if writable
+    pub fn file_root(mut self, root: FileRoot) -> Self {
+        self.services.add_file_root(root);
+        self
+    }
+
+    /// Let portals forward to destinations matching `rule`, see [`Allowlist`]
+    pub fn allow(mut self, rule: AllowRule) -> Self {
+        self.allowlist.push(rule);
+        self
+    }
+
+    /// Limit the data sent by all proxies together
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Serve interactive terminals to the portals `config` allows
+    pub fn shell(mut self, config: ShellConfig) -> Self {
+        self.shell = Some(config);
+        self
+    }
+
+    /// Reject offers of new portals while `max` peer connections are served
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Reject offers, ICE restarts included, beyond `max` per portal within the
+    /// offer window. Portal IDs are not authenticated, a portal changing its ID
+    /// starts afresh, which [`Self::max_total_offers`] guards against.
+    pub fn max_offers(mut self, max: u32) -> Self {
+        self.max_offers = Some(max);
+        self
+    }
+
+    /// Drop offers beyond `max` of all portals together within the offer window,
+    /// without replying
+    pub fn max_total_offers(mut self, max: u32) -> Self {
+        self.max_total_offers = Some(max);
+        self
+    }
+
+    /// Window offers are counted in, a minute by default
+    pub fn offer_window(mut self, window: Duration) -> Self {
+        self.offer_window = Some(window);
         self
     }
 
@@ -52,10 +140,31 @@ impl ProxyManagerBuilder {
         let local_id = self.local_id.ok_or_else(|| anyhow!("local_id is required"))?;
         let mqtt_config = self.mqtt_config.ok_or_else(|| anyhow!("mqtt config is required"))?;
         let peer_config = self.peer_config.unwrap_or_default();
-        let target_addr = self.target_addr.ok_or_else(|| anyhow!("target_addr is required"))?;
+        let mut services = self.services;
+        services.set_allowlist(self.allowlist);
+        if services.is_empty() && self.shell.is_none() && !peer_config.reverse_forwarding {
+            return Err(anyhow!(
+                "target_addr, a service, an allow rule, a file root, a shell or reverse forwarding \
+                 is required"
+            ));
+        }
+        if let Some(shell) = &self.shell {
+            shell.validate()?;
+        }
+        for root in services.file_roots() {
+            root.validate()?;
+        }
+
+        for addr in services.addrs() {
+            if addr.starts_with(SERIAL_SCHEME) {
+                addr.parse::<SerialTarget>()?;
+            } else if addr.starts_with(EXEC_SCHEME) {
+                exec::parse(addr)?;
+            }
+        }
 
         #[cfg(not(unix))]
-        if target_addr.starts_with("unix://") {
+        if services.addrs().any(|addr| addr.starts_with("unix://")) {
             return Err(anyhow!("Unix socket not supported on this platform"));
         }
 
@@ -70,9 +179,18 @@ impl ProxyManagerBuilder {
             local_id,
             signal,
             config: peer_config,
-            target_addr,
+            services: Arc::new(services),
+            shell: self.shell.map(Arc::new),
             proxies,
             proxy_event_tx,
+            rate_limit: Arc::new(TokenBucket::new(self.rate_limit)),
+            max_connections: self.max_connections,
+            offer_limit: (self.max_offers.is_some() || self.max_total_offers.is_some()).then(
+                || {
+                    let window = self.offer_window.unwrap_or(DEFAULT_OFFER_WINDOW);
+                    OfferLimit::new(self.max_offers, self.max_total_offers, window)
+                },
+            ),
         });
 
         let m = Arc::clone(&manager);
@@ -94,10 +212,62 @@ impl ProxyManager {
         self.proxies.read().await.len()
     }
 
+    /// Adjust the limit shared by all proxies, `None` lifts it
+    pub fn set_rate_limit(&self, limit: Option<RateLimit>) {
+        self.rate_limit.set_limit(limit);
+        info!("Global rate limit: {}", limit.map_or("none".to_string(), |l| l.to_string()));
+    }
+
+    /// Adjust the limit of the proxy serving `remote_id`
+    pub async fn set_session_rate_limit(
+        &self,
+        remote_id: &str,
+        limit: Option<RateLimit>,
+    ) -> Result<()> {
+        self.proxy(remote_id).await?.set_session_rate_limit(limit);
+        Ok(())
+    }
+
+    /// Adjust the per-stream limit of the proxy serving `remote_id`
+    pub async fn set_stream_rate_limit(
+        &self,
+        remote_id: &str,
+        limit: Option<RateLimit>,
+    ) -> Result<()> {
+        self.proxy(remote_id).await?.set_stream_rate_limit(limit);
+        Ok(())
+    }
+
+    /// Candidate pair, protocol, ICE round-trip time and traffic of the
+    /// connection to `remote_id`, `None` until connected
+    pub async fn connection_path(&self, remote_id: &str) -> Result<Option<ConnectionPath>> {
+        Ok(self.proxy(remote_id).await?.connection_path().await)
+    }
+
+    /// Round-trip time, jitter and loss of the link to `remote_id`, `None` until
+    /// the first ping was answered
+    pub async fn link_stats(&self, remote_id: &str) -> Result<Option<LinkStats>> {
+        Ok(self.proxy(remote_id).await?.link_stats())
+    }
+
+    /// Channel profiles of the dedicated streams `remote_id` has open
+    pub async fn channel_profiles(&self, remote_id: &str) -> Result<Vec<ChannelProfile>> {
+        Ok(self.proxy(remote_id).await?.channel_profiles())
+    }
+
+    async fn proxy(&self, remote_id: &str) -> Result<Arc<Proxy>> {
+        self.proxies
+            .read()
+            .await
+            .get(remote_id)
+            .map(Arc::clone)
+            .ok_or_else(|| anyhow!("No proxy for {}", remote_id))
+    }
+
     async fn event_loop(
         &self,
         mut signal_event_rx: mpsc::UnboundedReceiver<SignalEvent>,
-        mut proxy_event_rx: mpsc::UnboundedReceiver<ProxyEvent>,
+        mut proxy_event_rx: mpsc::UnboundedReceiver<PeerEvent>,
     ) {
         loop {
             tokio::select! {
@@ -131,29 +301,74 @@ impl ProxyManager {
         false
     }
 
-    async fn handle_signal_message(&self, msg: signal::SignalPayload) -> Result<()> {
+    async fn handle_signal_message(&self, msg: SignalPayload) -> Result<()> {
+        if let Some(limit) =
+            self.offer_limit.as_ref().filter(|_| msg.signal_type == SignalType::Offer)
+        {
+            match limit.admit(&msg.from_id) {
+                Admission::Admitted => {}
+                Admission::Rejected(max) => {
+                    let message =
+                        format!("Too many offers (max {} per {}s)", max, limit.window.as_secs());
+                    let error = StreamError::new(StreamErrorKind::Overloaded, message);
+                    self.reject(&msg.from_id, error).await;
+                    return Ok(());
+                }
+                Admission::Dropped => {
+                    debug!("Offer from {} dropped, over the offer limit", msg.from_id);
+                    return Ok(());
+                }
+            }
+        }
         match msg.signal_type {
+            SignalType::Offer if SessionOptions::is_ice_restart(&msg.payload) => {
+                debug!("Received ICE restart from: {}", msg.from_id);
+                if let Some(proxy) = self.proxies.read().await.get(&msg.from_id) {
+                    proxy.handle_signal_message(msg).await?;
+                } else {
+                    warn!("No proxy found for {}, ignoring ICE restart", msg.from_id);
+                }
+            }
             SignalType::Offer => {
                 let remote_id = msg.from_id.clone();
                 debug!("Received offer from: {}", remote_id);
+                if let Some(max) = self.max_connections {
+                    let proxies = self.proxies.read().await;
+                    // A portal offering again replaces its own connection
+                    let others = proxies.keys().filter(|id| **id != remote_id).count();
+                    if others >= max {
+                        drop(proxies);
+                        let message = format!("Too many connections (max {})", max);
+                        let error = StreamError::new(StreamErrorKind::Overloaded, message);
+                        self.reject(&remote_id, error).await;
+                        return Ok(());
+                    }
+                }
 
+                let services = match &self.shell {
+                    Some(shell) if shell.allows(&remote_id) => {
+                        Arc::new(self.services.with_shell(Arc::clone(shell)))
+                    }
+                    _ => Arc::clone(&self.services),
+                };
                 let proxy = Proxy::new(
                     self.local_id.clone(),
                     remote_id.clone(),
-                    self.target_addr.clone(),
+                    services,
                     self.config.clone(),
                     self.proxy_event_tx.clone(),
                     msg,
+                    Arc::clone(&self.rate_limit),
                 )
                 .await?;
 
                 let mut proxies = self.proxies.write().await;
                 proxies.insert(remote_id.clone(), proxy);
                 info!(
-                    "Proxy created: {} -> {} (target: {}), total: {}",
+                    "Proxy created: {} -> {} (services: {}), total: {}",
                     self.local_id,
                     remote_id,
-                    self.target_addr,
+                    self.services,
                     proxies.len()
                 );
                 // info!("Proxy added: {}, total: {}", remote_id, proxies.len());
@@ -171,32 +386,55 @@ impl ProxyManager {
         Ok(())
     }
 
-    async fn handle_proxy_event(&self, event: ProxyEvent) {
+    async fn handle_proxy_event(&self, event: PeerEvent) {
         match event {
-            ProxyEvent::Answer { remote_id, payload } => {
-                trace!("Sending answer to: {}", remote_id);
+            PeerEvent::Signal { remote_id, payload } => {
+                trace!("Sending {:?} to: {}", payload.signal_type, remote_id);
                 if let Err(e) = self
                     .signal
                     .publish_signal_message(&remote_id, &payload, SignalRole::Caller)
                     .await
                 {
-                    error!("Failed to send answer to {}: {}", remote_id, e);
+                    error!("Failed to send {:?} to {}: {}", payload.signal_type, remote_id, e);
                 }
             }
-            ProxyEvent::Candidate { remote_id, payload } => {
-                trace!("Sending candidate to: {}", remote_id);
-                if let Err(e) = self
-                    .signal
-                    .publish_signal_message(&remote_id, &payload, SignalRole::Caller)
-                    .await
-                {
-                    error!("Failed to send candidate to {}: {}", remote_id, e);
-                }
+            PeerEvent::Connected { remote_id } => {
+                let path = self.connection_path(&remote_id).await.ok().flatten();
+                path::log_connected(&remote_id, path);
             }
-            ProxyEvent::Connected { remote_id } => debug!("{} connected", remote_id),
-            ProxyEvent::Closed { remote_id } => {
+            PeerEvent::Disconnected { remote_id } => {
+                debug!("{} disconnected, waiting for an ICE restart", remote_id)
+            }
+            PeerEvent::Closed { remote_id } => {
                 self.try_remove_proxy(&remote_id).await;
             }
+            PeerEvent::Expired { remote_id, reason } => {
+                let proxy = {
+                    let mut proxies = self.proxies.write().await;
+                    let proxy = proxies.remove(&remote_id);
+                    info!("{} {}, closing, count: {}", remote_id, reason, proxies.len());
+                    proxy
+                };
+                if let Some(proxy) = proxy {
+                    let _ = proxy.connection().pc.close().await;
+                }
+            }
+        }
+    }
+
+    /// Refuse an offer of `remote_id` without allocating anything for it, and
+    /// tell it why
+    async fn reject(&self, remote_id: &str, error: StreamError) {
+        warn!("Offer from {} rejected, {}", remote_id, error);
+        let payload = SignalPayload {
+            from_id: self.local_id.clone(),
+            payload: error.encode(),
+            signal_type: SignalType::Reject,
+        };
+        if let Err(e) =
+            self.signal.publish_signal_message(remote_id, &payload, SignalRole::Caller).await
+        {
+            error!("Failed to send {:?} to {}: {}", payload.signal_type, remote_id, e);
         }
     }
 
@@ -212,3 +450,90 @@ impl ProxyManager {
         }
     }
 }
+
+/// Offers all portals together and each portal may send within a window,
+/// counted in fixed windows
+struct OfferLimit {
+    /// Offers per portal
+    max: Option<u32>,
+    /// Offers of all portals, checked first as portals can make up new IDs
+    max_total: Option<u32>,
+    window: Duration,
+    counts: StdMutex<OfferCounts>,
+}
+
+struct OfferCounts {
+    /// Start of the window of all portals and their offers within it
+    total: (Instant, u32),
+    /// Start of each portal's window and its offers within it
+    portals: HashMap<String, (Instant, u32)>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Admission {
+    Admitted,
+    /// The first offer over the portal's limit, told so
+    Rejected(u32),
+    /// Offers beyond, not to answer anyone a spoofed ID names
+    Dropped,
+}
+
+impl OfferLimit {
+    fn new(max: Option<u32>, max_total: Option<u32>, window: Duration) -> Self {
+        let counts = OfferCounts { total: (Instant::now(), 0), portals: HashMap::new() };
+        Self { max, max_total, window, counts: StdMutex::new(counts) }
+    }
+
+    /// Count an offer of `remote_id`
+    fn admit(&self, remote_id: &str) -> Admission {
+        let now = Instant::now();
+        let mut counts = self.counts.lock().unwrap();
+        let (start, offers) = &mut counts.total;
+        if now.duration_since(*start) >= self.window {
+            *start = now;
+            *offers = 0;
+        }
+        *offers += 1;
+        if self.max_total.is_some_and(|max| *offers > max) {
+            return Admission::Dropped;
+        }
+
+        let Some(max) = self.max else {
+            return Admission::Admitted;
+        };
+        // Portals whose window is over start afresh, so many IDs can't pile up
+        counts.portals.retain(|_, (start, _)| now.duration_since(*start) < self.window);
+        let (_, offers) = counts.portals.entry(remote_id.to_string()).or_insert((now, 0));
+        *offers = offers.saturating_add(1);
+        match *offers {
+            n if n <= max => Admission::Admitted,
+            n if n == max + 1 => Admission::Rejected(max),
+            _ => Admission::Dropped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_offer_limit() {
+        let limit = OfferLimit::new(Some(2), Some(5), Duration::from_secs(10));
+        assert_eq!(limit.admit("a"), Admission::Admitted);
+        assert_eq!(limit.admit("a"), Admission::Admitted);
+        assert_eq!(limit.admit("a"), Admission::Rejected(2));
+        assert_eq!(limit.admit("a"), Admission::Dropped);
+        assert_eq!(limit.admit("b"), Admission::Admitted);
+        // New IDs don't get around the total
+        assert_eq!(limit.admit("c"), Admission::Dropped);
+
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(limit.admit("a"), Admission::Admitted);
+        assert_eq!(limit.counts.lock().unwrap().portals.len(), 1);
+
+        let limit = OfferLimit::new(None, Some(1), Duration::from_secs(10));
+        assert_eq!(limit.admit("a"), Admission::Admitted);
+        assert_eq!(limit.admit("b"), Admission::Dropped);
+    }
+}
@@ -0,0 +1,155 @@
+//! Why one side refused a stream the other opened.
+//!
+//! The refusal travels wherever the stream's carrier has room for text: a text
+//! message on a dedicated DataChannel, the payload of a mux CLOSE frame or the
+//! reason of a pooled channel's `close:<reason>`. A proxy refusing a whole
+//! connection sends it as a `Reject` signal instead. It reads `<kind>: <message>`,
+//! e.g. `refused: Failed to connect to 127.0.0.1:502: Connection refused`.
+//! Reasons without a known kind, as older peers send them, read as `failed`.
+
+use std::fmt;
+use std::io;
+
+/// What went wrong, for the opening side to act on without parsing messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StreamErrorKind {
+    /// Nothing listens at the target
+    Refused,
+    /// The target didn't answer in time
+    Timeout,
+    /// No route to the target or its name didn't resolve
+    Unreachable,
+    /// The allowlist or the proxy's policy forbids the target
+    NotAllowed,
+    /// The requested service doesn't exist
+    NoService,
+    /// The proxy is at one of its limits, trying again later may work
+    Overloaded,
+    Failed,
+}
+
+impl StreamErrorKind {
+    const ALL: [Self; 7] = [
+        Self::Refused,
+        Self::Timeout,
+        Self::Unreachable,
+        Self::NotAllowed,
+        Self::NoService,
+        Self::Overloaded,
+        Self::Failed,
+    ];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Refused => "refused",
+            Self::Timeout => "timeout",
+            Self::Unreachable => "unreachable",
+            Self::NotAllowed => "not-allowed",
+            Self::NoService => "no-service",
+            Self::Overloaded => "overloaded",
+            Self::Failed => "failed",
+        }
+    }
+
+    /// Classify a failed connection attempt
+    fn of_io(err: &io::Error) -> Self {
+        #[cfg(unix)]
+        if matches!(err.raw_os_error(), Some(libc::ENETUNREACH | libc::EHOSTUNREACH)) {
+            return Self::Unreachable;
+        }
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset => Self::Refused,
+            io::ErrorKind::TimedOut => Self::Timeout,
+            io::ErrorKind::NotFound | io::ErrorKind::AddrNotAvailable => Self::Unreachable,
+            io::ErrorKind::PermissionDenied => Self::NotAllowed,
+            _ => Self::Failed,
+        }
+    }
+}
+
+impl fmt::Display for StreamErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// The reason a stream was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct StreamError {
+    pub kind: StreamErrorKind,
+    pub message: String,
+}
+
+impl StreamError {
+    pub fn new(kind: StreamErrorKind, message: impl Into<String>) -> Self {
+        Self { kind, message: message.into() }
+    }
+
+    /// A failed attempt to connect to `target`
+    pub fn connect(target: &str, err: &io::Error) -> Self {
+        Self::new(StreamErrorKind::of_io(err), format!("Failed to connect to {}: {}", target, err))
+    }
+
+    /// The refusal behind `err`, classified by the I/O error it wraps otherwise
+    pub fn from_error(err: &anyhow::Error) -> Self {
+        if let Some(error) = err.downcast_ref::<Self>() {
+            return error.clone();
+        }
+        let kind = err
+            .chain()
+            .find_map(|e| e.downcast_ref::<io::Error>())
+            .map_or(StreamErrorKind::Failed, StreamErrorKind::of_io);
+        Self::new(kind, err.to_string())
+    }
+
+    pub fn encode(&self) -> String {
+        self.to_string()
+    }
+
+    pub fn decode(s: &str) -> Self {
+        let parsed = s.split_once(':').and_then(|(kind, message)| {
+            let kind = StreamErrorKind::ALL.into_iter().find(|k| k.as_str() == kind)?;
+            Some(Self::new(kind, message.strip_prefix(' ').unwrap_or(message)))
+        });
+        parsed.unwrap_or_else(|| Self::new(StreamErrorKind::Failed, s))
+    }
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_stream_error_encoding() {
+        let error = StreamError::new(StreamErrorKind::NotAllowed, "Destination not allowed: a:1");
+        assert_eq!(error.encode(), "not-allowed: Destination not allowed: a:1");
+        assert_eq!(StreamError::decode(&error.encode()), error);
+
+        // Reasons of older peers carry no kind
+        let legacy = StreamError::decode("Unknown service: cam");
+        assert_eq!(legacy.kind, StreamErrorKind::Failed);
+        assert_eq!(legacy.message, "Unknown service: cam");
+    }
+
+    #[test]
+    fn test_classify_errors() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        let error = StreamError::connect("127.0.0.1:1", &refused);
+        assert_eq!(error.kind, StreamErrorKind::Refused);
+        assert!(error.message.starts_with("Failed to connect to 127.0.0.1:1: "));
+
+        let timed_out = anyhow::Error::from(io::Error::from(io::ErrorKind::TimedOut));
+        assert_eq!(StreamError::from_error(&timed_out).kind, StreamErrorKind::Timeout);
+        assert_eq!(StreamError::from_error(&anyhow::Error::from(error.clone())), error);
+        assert_eq!(StreamError::from_error(&anyhow!("broken")).kind, StreamErrorKind::Failed);
+    }
+}
@@ -0,0 +1,215 @@
+//! Reverse forwarding, proxy side.
+//!
+//! A portal lists proxy side addresses in its offer. A proxy with reverse
+//! forwarding enabled listens on them and opens a stream back to the portal
+//! for each accepted connection, naming the address as the service. The
+//! portal connects it to the local target it mapped that address to.
+//! Streams go over the mux when the portal multiplexes, dedicated channels
+//! otherwise; pre-negotiated channels are claimed by the portal only.
+//!
+//! The addresses come from the portal, so the proxy only listens where its
+//! [`ReverseRule`]s allow, on at most [`MAX_LISTENERS`] of them per session.
+//! An existing path is only replaced if it is a socket.
+
+#[cfg(unix)]
+use crate::binder::remove_socket;
+use crate::binder::{spawn_bridge, spawn_dc_socket_bridge, Layers, Listener};
+use crate::config::ChannelProfile;
+use crate::mux::Mux;
+use crate::service::StreamRequest;
+use crate::AllowRule;
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tracing::{debug, error};
+use webrtc::peer_connection::RTCPeerConnection;
+
+/// Most listeners one portal may ask for
+pub(crate) const MAX_LISTENERS: usize = 16;
+
+/// Where portals may ask the proxy to listen: `<IP or CIDR>[:<ports>]` for TCP,
+/// e.g. `127.0.0.1:8000-8100`, or `unix://<directory>/*` for sockets directly
+/// inside a directory, e.g. `unix:///run/lrc/*`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReverseRule {
+    Tcp(AllowRule),
+    Unix(PathBuf),
+}
+
+impl ReverseRule {
+    /// Listening on the loopback addresses only, when no rule is given
+    pub fn loopback() -> Vec<Self> {
+        ["127.0.0.0/8", "[::1]"].iter().map(|r| Self::Tcp(r.parse().unwrap())).collect()
+    }
+
+    pub(crate) fn allows(&self, addr_uri: &str) -> bool {
+        match (self, addr_uri.strip_prefix("unix://")) {
+            (Self::Unix(dir), Some(path)) => {
+                let path = Path::new(path);
+                path.file_name().is_some() && path.parent() == Some(dir.as_path())
+            }
+            (Self::Tcp(rule), None) => {
+                addr_uri.parse::<SocketAddr>().is_ok_and(|addr| rule.allows_addr(addr))
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for ReverseRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(path) = s.strip_prefix("unix://") else {
+            return Ok(Self::Tcp(s.parse()?));
+        };
+        match path.strip_suffix("/*") {
+            Some("") => Ok(Self::Unix(PathBuf::from("/"))),
+            Some(dir) if dir.starts_with('/') && !dir.split('/').any(|c| c == "..") => {
+                Ok(Self::Unix(PathBuf::from(dir)))
+            }
+            _ => Err(anyhow!("Expected unix://<absolute directory>/*, got {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ReverseRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(rule) => write!(f, "{}", rule),
+            Self::Unix(dir) if dir == Path::new("/") => write!(f, "unix:///*"),
+            Self::Unix(dir) => write!(f, "unix://{}/*", dir.display()),
+        }
+    }
+}
+
+/// Opens streams towards the portal
+pub(crate) struct Opener {
+    pub pc: Arc<RTCPeerConnection>,
+    pub local_id: String,
+    pub profile: ChannelProfile,
+    /// Set once the portal's multiplexed channel arrived
+    pub mux: Arc<StdMutex<Option<Arc<Mux>>>>,
+    pub layers: Layers,
+}
+
+impl Opener {
+    async fn connect<S>(&self, socket: S, request: &str)
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let mux = self.mux.lock().unwrap().clone();
+        if let Some(mux) = mux {
+            spawn_bridge(mux.open_stream(request), socket, &self.layers);
+            return;
+        }
+
+        let label = format!("{}-{}", self.local_id, Utc::now().timestamp_millis());
+        let mut init = self.profile.to_init();
+        init.protocol = Some(request.to_string());
+        match self.pc.create_data_channel(&label, Some(init)).await {
+            Ok(dc) => spawn_dc_socket_bridge(dc, socket, &self.layers),
+            Err(e) => error!("create_data_channel failed: {:?}", e),
+        }
+    }
+}
+
+/// Listen on `addr_uri` and forward its connections to the portal
+pub(crate) async fn listen(addr_uri: &str, opener: Arc<Opener>) -> Result<Listener> {
+    let request = StreamRequest::service(addr_uri).encode();
+    let (handle, local_addr) = if addr_uri.starts_with("unix://") {
+        #[cfg(unix)]
+        {
+            let socket_path = addr_uri.trim_start_matches("unix://");
+            remove_socket(socket_path);
+            let listener = UnixListener::bind(socket_path)?;
+            debug!("Reverse forwarding from unix://{}", socket_path);
+            let handle = tokio::spawn(accept_loop_unix(listener, opener, request));
+            (handle.abort_handle(), addr_uri.to_string())
+        }
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!("Unix socket not supported"));
+    } else {
+        let listener = TcpListener::bind(addr_uri).await?;
+        let local_addr = listener.local_addr()?.to_string();
+        debug!("Reverse forwarding from {}", local_addr);
+        let handle = tokio::spawn(accept_loop_tcp(listener, opener, request));
+        (handle.abort_handle(), local_addr)
+    };
+    Ok(Listener { addr_uri: addr_uri.to_string(), local_addr, handle })
+}
+
+async fn accept_loop_tcp(listener: TcpListener, opener: Arc<Opener>, request: String) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                error!("TCP accept failed: {:?}", e);
+                continue;
+            }
+        };
+        debug!("New reverse TCP connection from {}", addr);
+        if opener.profile.nodelay() {
+            let _ = socket.set_nodelay(true);
+        }
+        opener.connect(socket, &request).await;
+    }
+}
+
+#[cfg(unix)]
+async fn accept_loop_unix(listener: UnixListener, opener: Arc<Opener>, request: String) {
+    loop {
+        let (socket, _) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Unix socket accept failed: {:?}", e);
+                continue;
+            }
+        };
+        debug!("New reverse Unix socket connection");
+        opener.connect(socket, &request).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(rules: &[&str], addr_uri: &str) -> bool {
+        rules.iter().any(|r| r.parse::<ReverseRule>().unwrap().allows(addr_uri))
+    }
+
+    #[test]
+    fn test_reverse_rules() {
+        for rule in ["127.0.0.1:8000-8100", "unix:///run/lrc/*", "unix:///*"] {
+            assert_eq!(rule.parse::<ReverseRule>().unwrap().to_string(), rule);
+        }
+        assert!("unix:///run/lrc".parse::<ReverseRule>().is_err());
+        assert!("unix://run/*".parse::<ReverseRule>().is_err());
+        assert!("unix:///run/../etc/*".parse::<ReverseRule>().is_err());
+
+        let rules = ["127.0.0.1:8000-8100", "unix:///run/lrc/*"];
+        assert!(allowed(&rules, "127.0.0.1:8000"));
+        assert!(!allowed(&rules, "127.0.0.1:0"));
+        assert!(!allowed(&rules, "0.0.0.0:8000"));
+        assert!(!allowed(&rules, "localhost:8000"));
+        assert!(allowed(&rules, "unix:///run/lrc/repo.sock"));
+        assert!(!allowed(&rules, "unix:///run/lrc"));
+        assert!(!allowed(&rules, "unix:///run/lrc/sub/repo.sock"));
+        assert!(!allowed(&rules, "unix:///run/lrc/../../etc/passwd"));
+        assert!(!allowed(&rules, "unix:///etc/passwd"));
+
+        let loopback = ReverseRule::loopback();
+        assert!(loopback.iter().any(|r| r.allows("127.0.0.1:0")));
+        assert!(loopback.iter().any(|r| r.allows("[::1]:9000")));
+        assert!(!loopback.iter().any(|r| r.allows("192.168.1.2:9000")));
+    }
+}
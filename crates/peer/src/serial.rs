@@ -0,0 +1,224 @@
+//! Serial devices as stream endpoints.
+//!
+//! On the proxy side a `serial://<device>[?baud=<rate>&data-bits=<5-8>&parity=<none|odd|even>&stop-bits=<1|2>]`
+//! target opens the device for each stream, one stream at a time. On the portal
+//! side a `pty://<path>` listener creates a pseudo-terminal, links `<path>` to it
+//! and bridges it to a single stream, so tools that only talk to serial ports
+//! can open `<path>` as if the device was local.
+
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Mutex as StdMutex;
+use std::task::{Context, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_serial::{DataBits, Parity, SerialPortBuilderExt, SerialStream, StopBits};
+
+pub(crate) const SERIAL_SCHEME: &str = "serial://";
+pub(crate) const PTY_SCHEME: &str = "pty://";
+
+const DEFAULT_BAUD_RATE: u32 = 115_200;
+
+/// Devices with an open stream in this process. Opening a device exclusively
+/// keeps other processes out, not other streams of this one.
+static OPEN_DEVICES: Lazy<StdMutex<HashSet<String>>> = Lazy::new(Default::default);
+
+/// A `serial://` target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SerialTarget {
+    pub path: String,
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl FromStr for SerialTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest =
+            s.strip_prefix(SERIAL_SCHEME).ok_or_else(|| anyhow!("Not a serial target: {}", s))?;
+        let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+        if path.is_empty() {
+            return Err(anyhow!("Serial target without device: {}", s));
+        }
+
+        let mut target = Self {
+            path: path.to_string(),
+            baud_rate: DEFAULT_BAUD_RATE,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        };
+        for option in query.split('&').filter(|o| !o.is_empty()) {
+            let invalid = || anyhow!("Invalid serial option: {}", option);
+            match option.split_once('=').ok_or_else(invalid)? {
+                ("baud", value) => target.baud_rate = value.parse().map_err(|_| invalid())?,
+                ("data-bits", "5") => target.data_bits = DataBits::Five,
+                ("data-bits", "6") => target.data_bits = DataBits::Six,
+                ("data-bits", "7") => target.data_bits = DataBits::Seven,
+                ("data-bits", "8") => target.data_bits = DataBits::Eight,
+                ("parity", "none") => target.parity = Parity::None,
+                ("parity", "odd") => target.parity = Parity::Odd,
+                ("parity", "even") => target.parity = Parity::Even,
+                ("stop-bits", "1") => target.stop_bits = StopBits::One,
+                ("stop-bits", "2") => target.stop_bits = StopBits::Two,
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(target)
+    }
+}
+
+impl fmt::Display for SerialTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} baud)", self.path, self.baud_rate)
+    }
+}
+
+impl SerialTarget {
+    /// Open the device for one stream, fails while another stream has it open
+    pub fn open(&self) -> Result<Device<SerialStream>> {
+        if !OPEN_DEVICES.lock().unwrap().insert(self.path.clone()) {
+            return Err(anyhow!("Serial device busy: {}", self.path));
+        }
+        let claim = Claim(self.path.clone());
+
+        // Opened for exclusive access, which also locks the device on unix
+        let stream = tokio_serial::new(&self.path, self.baud_rate)
+            .data_bits(self.data_bits)
+            .parity(self.parity)
+            .stop_bits(self.stop_bits)
+            .open_native_async()?;
+        Ok(Device::new(stream, Some(claim)))
+    }
+}
+
+/// Releases a device of [`OPEN_DEVICES`]
+struct Claim(String);
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        OPEN_DEVICES.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// A device whose reads never end by themselves. Reads end once writing was
+/// shut down, which is how the bridge reports the other side closed, so the
+/// device is released along with the stream.
+pub(crate) struct Device<S> {
+    inner: S,
+    shut_down: bool,
+    read_waker: Option<Waker>,
+    _claim: Option<Claim>,
+}
+
+impl<S> Device<S> {
+    fn new(inner: S, claim: Option<Claim>) -> Self {
+        Self { inner, shut_down: false, read_waker: None, _claim: claim }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Device<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.shut_down {
+            return Poll::Ready(Ok(()));
+        }
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if poll.is_pending() {
+            self.read_waker = Some(cx.waker().clone());
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Device<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        if poll.is_ready() {
+            self.shut_down = true;
+            if let Some(waker) = self.read_waker.take() {
+                waker.wake();
+            }
+        }
+        poll
+    }
+}
+
+/// A pseudo-terminal reachable at `path`, with the master side to bridge. The
+/// slave side is held open so the terminal outlives the tools using it.
+#[cfg(unix)]
+pub(crate) struct Pty {
+    pub master: Device<SerialStream>,
+    pub slave: SerialStream,
+}
+
+#[cfg(unix)]
+impl Pty {
+    pub fn link(path: &str) -> Result<Self> {
+        use tokio_serial::SerialPort;
+
+        // Neither side is locked, tools may open the slave exclusively
+        let (master, slave) = SerialStream::pair()?;
+        let name = slave.name().ok_or_else(|| anyhow!("Pseudo-terminal without name"))?;
+        let _ = std::fs::remove_file(path);
+        std::os::unix::fs::symlink(&name, path)
+            .map_err(|e| anyhow!("Failed to link {} to {}: {}", path, name, e))?;
+        Ok(Self { master: Device::new(master, None), slave })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_serial_target() {
+        let target: SerialTarget = "serial:///dev/ttyUSB0".parse().unwrap();
+        assert_eq!(target.path, "/dev/ttyUSB0");
+        assert_eq!(target.baud_rate, DEFAULT_BAUD_RATE);
+
+        let target: SerialTarget =
+            "serial://COM3?baud=9600&data-bits=7&parity=even&stop-bits=2".parse().unwrap();
+        assert_eq!(target.path, "COM3");
+        assert_eq!(target.baud_rate, 9600);
+        assert_eq!(target.data_bits, DataBits::Seven);
+        assert_eq!(target.parity, Parity::Even);
+        assert_eq!(target.stop_bits, StopBits::Two);
+
+        assert!("serial://".parse::<SerialTarget>().is_err());
+        assert!("serial:///dev/ttyUSB0?baud=fast".parse::<SerialTarget>().is_err());
+        assert!("serial:///dev/ttyUSB0?parity=mark".parse::<SerialTarget>().is_err());
+        assert!("/dev/ttyUSB0".parse::<SerialTarget>().is_err());
+    }
+
+    #[test]
+    fn test_open_is_exclusive() {
+        let target: SerialTarget = "serial:///nonexistent/tty".parse().unwrap();
+        let _claim = Claim(target.path.clone());
+        OPEN_DEVICES.lock().unwrap().insert(target.path.clone());
+        let e = target.open().err().unwrap();
+        assert!(e.to_string().contains("busy"));
+    }
+}
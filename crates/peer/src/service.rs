@@ -0,0 +1,393 @@
+//! Named services behind one proxy, chosen by the portal per stream.
+//!
+//! The same map serves streams the proxy opens towards the portal for reverse
+//! forwarding, with the portal side targets as services.
+
+use crate::allowlist::Allowlist;
+use crate::binder::{spawn_bridge, spawn_dc_socket_bridge, AcceptFn, BoxSocket, Layers, Tunnel};
+use crate::config::ChannelProfile;
+use crate::exec::{Process, EXEC_SCHEME};
+use crate::files::{self, FileRoot, FILES_SERVICE};
+use crate::refusal::{StreamError, StreamErrorKind};
+use crate::serial::{SerialTarget, SERIAL_SCHEME};
+use crate::shell::{self, ShellConfig, SHELL_SERVICE};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex as StdMutex, Weak};
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tracing::{debug, error, warn};
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+use webrtc::data_channel::RTCDataChannel;
+
+/// How long a proxy tries to reach a TCP target, the OS alone retries for minutes
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a portal asks for when it opens a stream.
+///
+/// Sent as JSON in the DataChannel protocol field, the mux OPEN frame or the
+/// pool open message. The default request is sent as an empty string, which
+/// proxies without service support also understand.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct StreamRequest {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub service: String,
+    /// `host:port` to forward to instead of a service, checked against the allowlist
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub dest: String,
+}
+
+impl StreamRequest {
+    pub fn service(name: impl Into<String>) -> Self {
+        Self { service: name.into(), ..Default::default() }
+    }
+
+    pub fn dest(dest: impl Into<String>) -> Self {
+        Self { dest: dest.into(), ..Default::default() }
+    }
+
+    /// What the stream is forwarded to: the destination or the service name
+    pub fn target(&self) -> &str {
+        if self.dest.is_empty() {
+            &self.service
+        } else {
+            &self.dest
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        if *self == Self::default() {
+            return String::new();
+        }
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    pub fn decode(s: &str) -> Result<Self> {
+        if s.is_empty() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_str(s)?)
+    }
+}
+
+/// Targets a proxy exposes: an optional default one, any number of named ones
+/// and the destinations portals may pick themselves
+#[derive(Debug, Clone, Default)]
+pub struct ServiceMap {
+    default: Option<String>,
+    named: BTreeMap<String, String>,
+    allowlist: Allowlist,
+    /// Terminals behind the reserved `@shell` service, for allowed portals only
+    shell: Option<Arc<ShellConfig>>,
+    /// Directories shared by the reserved `@files` service
+    files: Vec<FileRoot>,
+}
+
+impl ServiceMap {
+    /// Target of streams that don't name a service
+    pub fn set_default(&mut self, addr_uri: impl Into<String>) {
+        self.default = Some(addr_uri.into());
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, addr_uri: impl Into<String>) {
+        self.named.insert(name.into(), addr_uri.into());
+    }
+
+    pub fn set_allowlist(&mut self, allowlist: Allowlist) {
+        self.allowlist = allowlist;
+    }
+
+    /// Destinations allowed for dynamic forwarding
+    pub fn allowlist(&self) -> &Allowlist {
+        &self.allowlist
+    }
+
+    /// Share a directory through the file service
+    pub fn add_file_root(&mut self, root: FileRoot) {
+        self.files.retain(|r| r.name != root.name);
+        self.files.push(root);
+    }
+
+    pub fn file_roots(&self) -> &[FileRoot] {
+        &self.files
+    }
+
+    /// The same services plus the shell
+    pub(crate) fn with_shell(&self, shell: Arc<ShellConfig>) -> Self {
+        Self { shell: Some(shell), ..self.clone() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none()
+            && self.named.is_empty()
+            && self.allowlist.is_empty()
+            && self.files.is_empty()
+    }
+
+    /// Names of the named services
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.named.keys().map(String::as_str)
+    }
+
+    /// All target addresses
+    pub fn addrs(&self) -> impl Iterator<Item = &str> {
+        self.default.iter().chain(self.named.values()).map(String::as_str)
+    }
+
+    /// Target address of `service`, the default target for an empty name
+    pub fn resolve(&self, service: &str) -> Result<&str> {
+        let addr = match service {
+            "" => self.default.as_ref(),
+            name => self.named.get(name),
+        };
+        addr.map(String::as_str).ok_or_else(|| match service {
+            "" => anyhow!("No default service"),
+            name => anyhow!("Unknown service: {}", name),
+        })
+    }
+}
+
+impl fmt::Display for ServiceMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut entries: Vec<String> = self.default.iter().cloned().collect();
+        entries.extend(self.named.iter().map(|(name, addr)| format!("{}={}", name, addr)));
+        if !self.allowlist.is_empty() {
+            entries.push(format!("allow {}", self.allowlist));
+        }
+        if !self.files.is_empty() {
+            let roots: Vec<String> = self.files.iter().map(FileRoot::to_string).collect();
+            entries.push(format!("files {}", roots.join(" ")));
+        }
+        write!(f, "{}", entries.join(", "))
+    }
+}
+
+/// Streams a portal may have open at once, dedicated channels and the streams
+/// inside multiplexed and pooled ones alike
+pub(crate) struct StreamLimit {
+    pub max: usize,
+    open: StdMutex<Vec<OpenStream>>,
+}
+
+enum OpenStream {
+    Channel(Weak<RTCDataChannel>),
+    Tunnel(Weak<dyn Tunnel>),
+}
+
+impl OpenStream {
+    /// Streams count until their bridge lets go of them
+    fn is_open(&self) -> bool {
+        match self {
+            Self::Channel(dc) => {
+                dc.upgrade().is_some_and(|dc| dc.ready_state() != RTCDataChannelState::Closed)
+            }
+            Self::Tunnel(tunnel) => tunnel.strong_count() > 0,
+        }
+    }
+}
+
+impl StreamLimit {
+    pub fn new(max: usize) -> Self {
+        Self { max, open: StdMutex::new(Vec::new()) }
+    }
+
+    /// Count the dedicated channel `dc` unless `max` streams are open already
+    pub fn admit_channel(&self, dc: &Arc<RTCDataChannel>) -> bool {
+        self.admit(OpenStream::Channel(Arc::downgrade(dc)))
+    }
+
+    /// Count a stream of a multiplexed or pooled channel the same way
+    pub fn admit_tunnel(&self, tunnel: &Arc<dyn Tunnel>) -> bool {
+        self.admit(OpenStream::Tunnel(Arc::downgrade(tunnel)))
+    }
+
+    fn admit(&self, stream: OpenStream) -> bool {
+        let mut open = self.open.lock().unwrap();
+        open.retain(OpenStream::is_open);
+        if open.len() >= self.max {
+            return false;
+        }
+        open.push(stream);
+        true
+    }
+
+    /// Why a stream over the limit is refused
+    pub fn error(&self) -> StreamError {
+        let message = format!("Too many streams (max {})", self.max);
+        StreamError::new(StreamErrorKind::Overloaded, message)
+    }
+}
+
+/// Serves streams of multiplexed and pre-negotiated channels, refusing those
+/// over `limit`
+pub(crate) fn acceptor(
+    services: Arc<ServiceMap>,
+    layers: Layers,
+    limit: Option<Arc<StreamLimit>>,
+) -> AcceptFn {
+    Arc::new(move |stream, request| {
+        let services = Arc::clone(&services);
+        let layers = layers.clone();
+        let limit = limit.clone();
+        tokio::spawn(async move {
+            if let Some(limit) = limit.filter(|l| !l.admit_tunnel(&stream)) {
+                warn!("{} refused, {} streams open already", stream.label(), limit.max);
+                stream.reject(&limit.error()).await;
+                return;
+            }
+            match connect_request(&services, &request, stream.label(), false).await {
+                Ok(socket) => spawn_bridge(stream, socket, &layers),
+                Err(e) => {
+                    error!("{}: {}", stream.label(), e);
+                    stream.reject(&StreamError::from_error(&e)).await;
+                }
+            }
+        });
+    })
+}
+
+/// Serves a dedicated channel, whose request travels in its protocol field
+pub(crate) async fn serve_data_channel(
+    dc: Arc<RTCDataChannel>,
+    services: &ServiceMap,
+    layers: &Layers,
+) {
+    let nodelay = ChannelProfile::from_data_channel(&dc).nodelay();
+    match connect_request(services, dc.protocol(), dc.label(), nodelay).await {
+        Ok(socket) => spawn_dc_socket_bridge(dc, socket, layers),
+        Err(e) => {
+            error!("{}: {}", dc.label(), e);
+            reject_data_channel(&dc, &StreamError::from_error(&e));
+        }
+    }
+}
+
+/// Refuse a dedicated channel the remote opened, telling it why
+pub(crate) fn reject_data_channel(dc: &Arc<RTCDataChannel>, error: &StreamError) {
+    // The channel isn't open yet while its callback runs, reject it once it is
+    let dc_for_open = Arc::clone(dc);
+    let reason = error.encode();
+    dc.on_open(Box::new(move || {
+        let dc = Arc::clone(&dc_for_open);
+        Box::pin(async move {
+            let _ = dc.send_text(reason).await;
+            let _ = dc.close().await;
+        })
+    }));
+}
+
+async fn connect_request(
+    services: &ServiceMap,
+    request: &str,
+    label: &str,
+    nodelay: bool,
+) -> Result<BoxSocket> {
+    let request = StreamRequest::decode(request)?;
+    if !request.dest.is_empty() {
+        let addrs = services.allowlist().resolve(&request.dest).await?;
+        let stream =
+            connect_tcp(&addrs[..]).await.map_err(|e| StreamError::connect(&request.dest, &e))?;
+        debug!("Connected to TCP: {} (dynamic)", request.dest);
+        if nodelay {
+            let _ = stream.set_nodelay(true);
+        }
+        return Ok(Box::new(stream));
+    }
+    if request.service == SHELL_SERVICE {
+        let config = services.shell.as_ref().ok_or_else(|| {
+            StreamError::new(StreamErrorKind::NotAllowed, "No shell for this portal")
+        })?;
+        let session = shell::open(config, label)?;
+        return Ok(Box::new(session));
+    }
+    if request.service == FILES_SERVICE {
+        if services.files.is_empty() {
+            return Err(StreamError::new(StreamErrorKind::NoService, "No file service").into());
+        }
+        return Ok(Box::new(files::open(&services.files, label)));
+    }
+    let addr_uri = services
+        .resolve(&request.service)
+        .map_err(|e| StreamError::new(StreamErrorKind::NoService, e.to_string()))?;
+    if addr_uri.starts_with(EXEC_SCHEME) {
+        let process = Process::spawn(addr_uri, &request.service, label)?;
+        debug!("Spawned command: {}", addr_uri);
+        return Ok(Box::new(process));
+    }
+    connect_target(addr_uri, nodelay).await.map_err(|e| {
+        let kind = StreamError::from_error(&e).kind;
+        StreamError::new(kind, format!("Failed to connect to {}: {}", addr_uri, e)).into()
+    })
+}
+
+async fn connect_target(addr_uri: &str, nodelay: bool) -> Result<BoxSocket> {
+    if addr_uri.starts_with(SERIAL_SCHEME) {
+        let target: SerialTarget = addr_uri.parse()?;
+        let device = target.open()?;
+        debug!("Opened serial device: {}", target);
+        Ok(Box::new(device))
+    } else if addr_uri.starts_with("unix://") {
+        #[cfg(unix)]
+        {
+            let socket_path = addr_uri.trim_start_matches("unix://");
+            let stream = UnixStream::connect(socket_path).await?;
+            debug!("Connected to Unix socket: {}", socket_path);
+            Ok(Box::new(stream))
+        }
+        #[cfg(not(unix))]
+        Err(anyhow!("Unix socket not supported on this platform"))
+    } else {
+        let stream = connect_tcp(addr_uri).await?;
+        debug!("Connected to TCP: {}", addr_uri);
+        if nodelay {
+            let _ = stream.set_nodelay(true);
+        }
+        Ok(Box::new(stream))
+    }
+}
+
+/// Connect to the first address that answers within `CONNECT_TIMEOUT`
+async fn connect_tcp(addrs: impl ToSocketAddrs) -> io::Result<TcpStream> {
+    tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addrs))
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_request_encoding() {
+        assert_eq!(StreamRequest::default().encode(), "");
+        assert_eq!(StreamRequest::decode("").unwrap(), StreamRequest::default());
+
+        let request = StreamRequest::service("gps");
+        assert_eq!(request.encode(), r#"{"service":"gps"}"#);
+        assert_eq!(StreamRequest::decode(&request.encode()).unwrap(), request);
+        assert!(StreamRequest::decode("gps").is_err());
+
+        let request = StreamRequest::dest("192.168.1.50:502");
+        assert_eq!(request.encode(), r#"{"dest":"192.168.1.50:502"}"#);
+        assert_eq!(request.target(), "192.168.1.50:502");
+        assert_eq!(StreamRequest::service("gps").target(), "gps");
+    }
+
+    #[test]
+    fn test_resolve_service() {
+        let mut services = ServiceMap::default();
+        services.insert("gps", "127.0.0.1:9000");
+        assert_eq!(services.resolve("gps").unwrap(), "127.0.0.1:9000");
+        assert!(services.resolve("").is_err());
+        assert!(services.resolve("cam").is_err());
+
+        services.set_default("unix:///run/api.sock");
+        assert_eq!(services.resolve("").unwrap(), "unix:///run/api.sock");
+        assert_eq!(services.to_string(), "unix:///run/api.sock, gps=127.0.0.1:9000");
+    }
+}
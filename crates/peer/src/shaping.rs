@@ -0,0 +1,188 @@
+//! Token bucket rate limiting of tunneled data.
+//!
+//! Limits apply to the bytes a peer sends. Every stream passes through its own
+//! bucket, the bucket of its session (one portal or proxy) and, on the proxy
+//! side, the bucket shared by all sessions of the manager. Waiting for tokens
+//! stalls the socket read, so the shaping propagates back to the sender.
+
+use crate::binder::Tunnel;
+use crate::config::RateLimit;
+use crate::refusal::StreamError;
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration, Instant};
+
+struct BucketState {
+    /// May go negative when a chunk is larger than the burst
+    tokens: f64,
+    refilled: Instant,
+}
+
+pub(crate) struct TokenBucket {
+    limit: Arc<StdMutex<Option<RateLimit>>>,
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    pub fn new(limit: Option<RateLimit>) -> Self {
+        Self::sharing(&Arc::new(StdMutex::new(limit)))
+    }
+
+    /// A separate bucket following a limit that is adjusted elsewhere
+    fn sharing(limit: &Arc<StdMutex<Option<RateLimit>>>) -> Self {
+        let tokens = limit.lock().unwrap().map_or(0.0, |l| l.burst as f64);
+        Self {
+            limit: Arc::clone(limit),
+            state: Mutex::new(BucketState { tokens, refilled: Instant::now() }),
+        }
+    }
+
+    pub fn limit(&self) -> Option<RateLimit> {
+        *self.limit.lock().unwrap()
+    }
+
+    pub fn set_limit(&self, limit: Option<RateLimit>) {
+        *self.limit.lock().unwrap() = limit;
+    }
+
+    /// Wait until `n` bytes may be sent. Waiters are served in order.
+    pub async fn acquire(&self, n: usize) {
+        let mut state = self.state.lock().await;
+        let Some(limit) = self.limit() else {
+            return;
+        };
+        if limit.bytes_per_sec == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let rate = limit.bytes_per_sec as f64;
+        let elapsed = now.duration_since(state.refilled).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * rate).min(limit.burst as f64);
+        state.refilled = now;
+
+        state.tokens -= n as f64;
+        if state.tokens < 0.0 {
+            sleep(Duration::from_secs_f64(-state.tokens / rate)).await;
+        }
+    }
+}
+
+/// Rate limits of one session, adjustable while streams are running
+pub(crate) struct Shaper {
+    global: Option<Arc<TokenBucket>>,
+    session: TokenBucket,
+    /// Every stream has its own bucket following this limit
+    stream_limit: Arc<StdMutex<Option<RateLimit>>>,
+}
+
+impl Shaper {
+    pub fn new(
+        global: Option<Arc<TokenBucket>>,
+        session_limit: Option<RateLimit>,
+        stream_limit: Option<RateLimit>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            global,
+            session: TokenBucket::new(session_limit),
+            stream_limit: Arc::new(StdMutex::new(stream_limit)),
+        })
+    }
+
+    pub fn set_session_limit(&self, limit: Option<RateLimit>) {
+        self.session.set_limit(limit);
+    }
+
+    pub fn set_stream_limit(&self, limit: Option<RateLimit>) {
+        *self.stream_limit.lock().unwrap() = limit;
+    }
+
+    pub fn wrap(self: &Arc<Self>, tunnel: Arc<dyn Tunnel>) -> Arc<dyn Tunnel> {
+        Arc::new(ShapedTunnel {
+            inner: tunnel,
+            shaper: Arc::clone(self),
+            bucket: TokenBucket::sharing(&self.stream_limit),
+        })
+    }
+
+    async fn acquire(&self, stream: &TokenBucket, n: usize) {
+        stream.acquire(n).await;
+        self.session.acquire(n).await;
+        if let Some(global) = &self.global {
+            global.acquire(n).await;
+        }
+    }
+}
+
+struct ShapedTunnel {
+    inner: Arc<dyn Tunnel>,
+    shaper: Arc<Shaper>,
+    bucket: TokenBucket,
+}
+
+#[async_trait]
+impl Tunnel for ShapedTunnel {
+    fn label(&self) -> &str {
+        self.inner.label()
+    }
+
+    async fn send(&self, data: Bytes) -> Result<()> {
+        self.shaper.acquire(&self.bucket, data.len()).await;
+        self.inner.send(data).await
+    }
+
+    async fn recv(&self) -> Option<Bytes> {
+        self.inner.recv().await
+    }
+
+    async fn consumed(&self, n: usize) {
+        self.inner.consumed(n).await;
+    }
+
+    async fn close(&self) {
+        self.inner.close().await;
+    }
+
+    fn refusal(&self) -> Option<StreamError> {
+        self.inner.refusal()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_bucket() {
+        let bucket = TokenBucket::new(Some(RateLimit { bytes_per_sec: 1000, burst: 500 }));
+        let start = Instant::now();
+        bucket.acquire(500).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        bucket.acquire(1000).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        bucket.set_limit(None);
+        bucket.acquire(1_000_000).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stream_buckets_are_separate() {
+        let shaper = Shaper::new(None, None, Some(RateLimit { bytes_per_sec: 100, burst: 100 }));
+        let a = TokenBucket::sharing(&shaper.stream_limit);
+        let b = TokenBucket::sharing(&shaper.stream_limit);
+        let start = Instant::now();
+        shaper.acquire(&a, 100).await;
+        shaper.acquire(&b, 100).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // Waits for the drained stream bucket, then for the session bucket
+        shaper.set_session_limit(Some(RateLimit { bytes_per_sec: 100, burst: 0 }));
+        shaper.acquire(&a, 50).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,775 @@
+//! Interactive terminals served by the proxy.
+//!
+//! A proxy with a shell configured serves the reserved `@shell` service to the
+//! portals it allows. Each stream runs the command on a new pseudo-terminal,
+//! as the configured user. A `shell://` portal listener attaches the local
+//! terminal, switched to raw mode, to a single such stream.
+//!
+//! Portal IDs are claimed by the portals themselves, so a terminal is only
+//! started for a portal presenting the proxy's [`ShellKey`] as well.
+//!
+//! Both directions carry frames of a type byte, a big endian `u32` payload
+//! length and the payload. The portal starts with `START`, holding its window
+//! size, `TERM` and, after a NUL byte, the key, then sends `DATA` with the
+//! terminal input and `RESIZE` whenever its window changes. The proxy sends
+//! `DATA` with the terminal output and finally `EXIT` with the command's exit
+//! status. Unknown frame types are skipped.
+
+use anyhow::{anyhow, Context, Result};
+use std::fmt;
+use std::io;
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub(crate) const SHELL_SERVICE: &str = "@shell";
+pub(crate) const SHELL_ADDR: &str = "shell://";
+
+pub(crate) const BUFFER_SIZE: usize = 16 * 1024;
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+const START: u8 = 0;
+const DATA: u8 = 1;
+const RESIZE: u8 = 2;
+const EXIT: u8 = 3;
+
+/// Secret a portal presents to start a terminal, kept out of logs
+#[derive(Clone, PartialEq, Eq)]
+pub struct ShellKey(String);
+
+impl ShellKey {
+    pub fn new(key: impl Into<String>) -> Self {
+        Self(key.into())
+    }
+
+    /// The first line of a file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read shell key from {}", path.display()))?;
+        match content.lines().next().map(str::trim) {
+            Some(key) if !key.is_empty() => Ok(Self::new(key)),
+            _ => Err(anyhow!("Empty shell key in {}", path.display())),
+        }
+    }
+
+    /// Compare in constant time, not to leak how much of a guess was right
+    fn matches(&self, key: &str) -> bool {
+        let (a, b) = (self.0.as_bytes(), key.as_bytes());
+        a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+    }
+}
+
+impl fmt::Debug for ShellKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ShellKey(..)")
+    }
+}
+
+/// Terminals served to the portals a proxy allows
+#[derive(Debug, Clone, Default)]
+pub struct ShellConfig {
+    /// Command line to run, split like `exec://` targets; the login shell if unset
+    pub command: Option<String>,
+    /// Account the command runs as, the proxy's own if unset
+    pub user: Option<String>,
+    /// Ids of the portals allowed to open a terminal, `*` allows any
+    pub callers: Vec<String>,
+    /// Key portals have to present, no terminal is started without one
+    pub key: Option<ShellKey>,
+    /// Accept `*` in `callers`, leaving the key as the only check
+    pub allow_any_caller: bool,
+}
+
+impl ShellConfig {
+    pub fn allows(&self, caller: &str) -> bool {
+        self.callers.iter().any(|c| c == "*" || c == caller)
+    }
+
+    /// Check the key, the callers, the command line and the user up front
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.key.is_none() {
+            return Err(anyhow!("A shell needs a key, portal IDs alone are not authenticated"));
+        }
+        if self.callers.iter().any(|c| c == "*") && !self.allow_any_caller {
+            return Err(anyhow!("A shell for any portal ('*') has to be allowed explicitly"));
+        }
+        #[cfg(unix)]
+        {
+            self.prepare().map(|_| ())
+        }
+        #[cfg(not(unix))]
+        Err(anyhow!("Shell not supported on this platform"))
+    }
+
+    /// The account to switch to and the command with its arguments
+    #[cfg(unix)]
+    fn prepare(&self) -> Result<(Option<Account>, Vec<String>)> {
+        let account = self.user.as_deref().map(Account::lookup).transpose()?;
+        let args = match &self.command {
+            Some(command) => crate::exec::split(command)?,
+            None => {
+                let shell = match &account {
+                    Some(account) => account.shell.clone(),
+                    None => std::env::var("SHELL").unwrap_or_default(),
+                };
+                let shell = if shell.is_empty() { "/bin/sh".to_string() } else { shell };
+                vec![shell, "-l".to_string()]
+            }
+        };
+        Ok((account, args))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct WindowSize {
+    pub cols: u16,
+    pub rows: u16,
+}
+
+impl Default for WindowSize {
+    fn default() -> Self {
+        Self { cols: 80, rows: 24 }
+    }
+}
+
+impl WindowSize {
+    fn to_bytes(self) -> [u8; 4] {
+        let [c0, c1] = self.cols.to_be_bytes();
+        let [r0, r1] = self.rows.to_be_bytes();
+        [c0, c1, r0, r1]
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            cols: u16::from_be_bytes([bytes[0], bytes[1]]),
+            rows: u16::from_be_bytes([bytes[2], bytes[3]]),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Frame {
+    Start { size: WindowSize, term: String, key: String },
+    Data(Vec<u8>),
+    Resize(WindowSize),
+    Exit(i32),
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &Frame) -> io::Result<()> {
+    let encoded: Vec<u8>;
+    let (kind, payload) = match frame {
+        Frame::Start { size, term, key } => {
+            encoded = [&size.to_bytes()[..], term.as_bytes(), &[0], key.as_bytes()].concat();
+            (START, &encoded[..])
+        }
+        Frame::Data(data) => (DATA, &data[..]),
+        Frame::Resize(size) => {
+            encoded = size.to_bytes().to_vec();
+            (RESIZE, &encoded[..])
+        }
+        Frame::Exit(code) => {
+            encoded = code.to_be_bytes().to_vec();
+            (EXIT, &encoded[..])
+        }
+    };
+    let mut header = [kind, 0, 0, 0, 0];
+    header[1..].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+    writer.write_all(&header).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await
+}
+
+/// The next known frame, `None` once the stream ended
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Frame>> {
+    loop {
+        let mut header = [0u8; 5];
+        match reader.read_exact(&mut header).await {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        };
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(anyhow!("Shell frame too large: {} bytes", len));
+        }
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload).await?;
+
+        let frame = match header[0] {
+            START | RESIZE | EXIT if len < 4 => {
+                return Err(anyhow!("Truncated shell frame of type {}", header[0]))
+            }
+            START => {
+                let mut fields = payload[4..].splitn(2, |b| *b == 0);
+                let mut field =
+                    || String::from_utf8_lossy(fields.next().unwrap_or(&[])).into_owned();
+                Frame::Start { size: WindowSize::from_bytes(&payload), term: field(), key: field() }
+            }
+            DATA => Frame::Data(payload),
+            RESIZE => Frame::Resize(WindowSize::from_bytes(&payload)),
+            EXIT => {
+                Frame::Exit(i32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]))
+            }
+            _ => continue,
+        };
+        return Ok(Some(frame));
+    }
+}
+
+#[cfg(unix)]
+use unix::Account;
+#[cfg(unix)]
+pub(crate) use unix::{attach, open};
+
+/// Shells need pseudo-terminals
+#[cfg(not(unix))]
+pub(crate) fn open(_config: &ShellConfig, _stream: &str) -> Result<tokio::io::DuplexStream> {
+    Err(anyhow!("Shell not supported on this platform"))
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use std::ffi::{CStr, CString};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Stdio};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::unix::AsyncFd;
+    use tokio::io::{DuplexStream, ReadHalf};
+    use tokio::process::{Child, Command};
+    use tokio::signal::unix::{signal, SignalKind};
+    use tokio::time::timeout;
+    use tracing::{debug, info, warn};
+
+    /// How long the portal has to start the terminal
+    const START_TIMEOUT: Duration = Duration::from_secs(10);
+    /// How long output left after the command exited is waited for, background
+    /// jobs may keep the terminal open
+    const DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+    /// How long the command has to exit once its terminal hung up
+    const HANGUP_TIMEOUT: Duration = Duration::from_secs(1);
+    /// Exit status sent when the command couldn't be started, as shells do
+    const SPAWN_FAILED: i32 = 127;
+    /// Exit status sent when the portal's key was wrong, as for commands that
+    /// may not be run
+    const DENIED: i32 = 126;
+
+    /// Start a terminal session for one stream, the returned end carries its frames
+    pub(crate) fn open(config: &ShellConfig, stream: &str) -> Result<DuplexStream> {
+        let (account, args) = config.prepare()?;
+        let key = config.key.clone();
+        let (socket, session) = tokio::io::duplex(BUFFER_SIZE);
+        let stream = stream.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = serve(session, key, account, args, &stream).await {
+                debug!("Shell {} ended: {}", stream, e);
+            }
+        });
+        Ok(socket)
+    }
+
+    async fn serve(
+        session: DuplexStream,
+        key: Option<ShellKey>,
+        account: Option<Account>,
+        args: Vec<String>,
+        stream: &str,
+    ) -> Result<()> {
+        let (mut reader, mut writer) = tokio::io::split(session);
+        let Ok(Ok(Some(Frame::Start { size, term, key: presented }))) =
+            timeout(START_TIMEOUT, read_frame(&mut reader)).await
+        else {
+            return Err(anyhow!("Terminal not started"));
+        };
+        if !key.is_some_and(|key| key.matches(&presented)) {
+            warn!("Shell {} refused, wrong key", stream);
+            write_frame(&mut writer, &Frame::Data(b"Permission denied\r\n".to_vec())).await?;
+            write_frame(&mut writer, &Frame::Exit(DENIED)).await?;
+            return Err(anyhow!("Wrong key"));
+        }
+
+        let (terminal, mut child) = match spawn(&args, account.as_ref(), size, &term, stream) {
+            Ok(spawned) => spawned,
+            Err(e) => {
+                let message = format!("{}\r\n", e);
+                write_frame(&mut writer, &Frame::Data(message.into_bytes())).await?;
+                write_frame(&mut writer, &Frame::Exit(SPAWN_FAILED)).await?;
+                return Err(e);
+            }
+        };
+        info!("Shell {} started: {}", stream, args.join(" "));
+
+        let terminal = Arc::new(terminal);
+        let mut input = tokio::spawn(forward_input(reader, Arc::clone(&terminal)));
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        let status = loop {
+            tokio::select! {
+                read = terminal.read(&mut buf) => match read {
+                    Ok(n) if n > 0 => {
+                        write_frame(&mut writer, &Frame::Data(buf[..n].to_vec())).await?;
+                    }
+                    // Everyone using the terminal closed it
+                    _ => break child.wait().await?,
+                },
+                status = child.wait() => {
+                    while let Ok(Ok(n @ 1..)) = timeout(DRAIN_TIMEOUT, terminal.read(&mut buf)).await {
+                        write_frame(&mut writer, &Frame::Data(buf[..n].to_vec())).await?;
+                    }
+                    break status?;
+                }
+                _ = &mut input => {
+                    debug!("Shell {} closed by the portal", stream);
+                    // Closing the master side hangs up the session, the command is
+                    // killed if it outlives that
+                    drop(terminal);
+                    let _ = timeout(HANGUP_TIMEOUT, child.wait()).await;
+                    return Ok(());
+                }
+            }
+        };
+        input.abort();
+
+        let code = exit_code(status);
+        info!("Shell {} exited with {}", stream, code);
+        write_frame(&mut writer, &Frame::Exit(code)).await?;
+        writer.shutdown().await?;
+        Ok(())
+    }
+
+    async fn forward_input(
+        mut reader: ReadHalf<DuplexStream>,
+        terminal: Arc<Terminal>,
+    ) -> Result<()> {
+        while let Some(frame) = read_frame(&mut reader).await? {
+            match frame {
+                Frame::Data(data) => terminal.write_all(&data).await?,
+                Frame::Resize(size) => terminal.resize(size)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn exit_code(status: ExitStatus) -> i32 {
+        status.code().unwrap_or_else(|| 128 + status.signal().unwrap_or(0))
+    }
+
+    fn spawn(
+        args: &[String],
+        account: Option<&Account>,
+        size: WindowSize,
+        term: &str,
+        stream: &str,
+    ) -> Result<(Terminal, Child)> {
+        let (terminal, slave) = Terminal::open(size)?;
+        let mut command = Command::new(&args[0]);
+        command
+            .args(&args[1..])
+            .env("TERM", if term.is_empty() { "dumb" } else { term })
+            .env("LRC_STREAM", stream)
+            .stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave))
+            .kill_on_drop(true);
+        if let Some(account) = account {
+            command
+                .uid(account.uid)
+                .gid(account.gid)
+                .current_dir(&account.home)
+                .env("HOME", &account.home)
+                .env("USER", &account.name)
+                .env("LOGNAME", &account.name)
+                .env("SHELL", &account.shell);
+        }
+        // SAFETY: only async-signal-safe calls run between fork and exec
+        unsafe {
+            command.pre_exec(|| {
+                // A session of its own, controlled by the terminal on its stdin
+                if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn().map_err(|e| anyhow!("Failed to run {}: {}", args[0], e))?;
+        Ok((terminal, child))
+    }
+
+    /// Master side of a pseudo-terminal
+    struct Terminal {
+        master: AsyncFd<OwnedFd>,
+    }
+
+    impl Terminal {
+        /// A new terminal and its slave side
+        fn open(size: WindowSize) -> Result<(Self, OwnedFd)> {
+            let (mut master, mut slave) = (-1, -1);
+            let mut winsize = to_winsize(size);
+            // SAFETY: the pointers are valid for the duration of the call
+            let rc = unsafe {
+                libc::openpty(
+                    &mut master,
+                    &mut slave,
+                    std::ptr::null_mut(),
+                    std::ptr::null_mut(),
+                    // Only declared mutable on some platforms
+                    std::ptr::addr_of_mut!(winsize),
+                )
+            };
+            if rc < 0 {
+                return Err(anyhow!(
+                    "Failed to open a pseudo-terminal: {}",
+                    io::Error::last_os_error()
+                ));
+            }
+            // SAFETY: openpty returned two open descriptors we own
+            let (master, slave) =
+                unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+            for fd in [&master, &slave] {
+                // SAFETY: fcntl on a descriptor we own
+                unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+            }
+            // SAFETY: as above
+            unsafe {
+                let flags = libc::fcntl(master.as_raw_fd(), libc::F_GETFL);
+                libc::fcntl(master.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK);
+            }
+            Ok((Self { master: AsyncFd::new(master)? }, slave))
+        }
+
+        async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+            loop {
+                let mut guard = self.master.readable().await?;
+                let result = guard.try_io(|fd| {
+                    // SAFETY: buf is valid for writes of its length
+                    let n =
+                        unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+                    if n < 0 {
+                        Err(io::Error::last_os_error())
+                    } else {
+                        Ok(n as usize)
+                    }
+                });
+                if let Ok(result) = result {
+                    return result;
+                }
+            }
+        }
+
+        async fn write_all(&self, mut data: &[u8]) -> io::Result<()> {
+            while !data.is_empty() {
+                let mut guard = self.master.writable().await?;
+                let result = guard.try_io(|fd| {
+                    // SAFETY: data is valid for reads of its length
+                    let n =
+                        unsafe { libc::write(fd.as_raw_fd(), data.as_ptr().cast(), data.len()) };
+                    if n < 0 {
+                        Err(io::Error::last_os_error())
+                    } else {
+                        Ok(n as usize)
+                    }
+                });
+                if let Ok(result) = result {
+                    data = &data[result?..];
+                }
+            }
+            Ok(())
+        }
+
+        fn resize(&self, size: WindowSize) -> io::Result<()> {
+            let winsize = to_winsize(size);
+            // SAFETY: TIOCSWINSZ reads a winsize
+            if unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ as _, &winsize) } < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    fn to_winsize(size: WindowSize) -> libc::winsize {
+        libc::winsize { ws_row: size.rows, ws_col: size.cols, ws_xpixel: 0, ws_ypixel: 0 }
+    }
+
+    /// A user of the system
+    #[derive(Debug)]
+    pub(super) struct Account {
+        name: String,
+        uid: u32,
+        gid: u32,
+        home: String,
+        pub shell: String,
+    }
+
+    impl Account {
+        pub fn lookup(name: &str) -> Result<Self> {
+            let c_name = CString::new(name)?;
+            // SAFETY: passwd is plain data, filled in by getpwnam_r
+            let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+            let mut buf = vec![0 as libc::c_char; 16 * 1024];
+            let mut result = std::ptr::null_mut();
+            // SAFETY: all pointers are valid, the strings end up in buf
+            let rc = unsafe {
+                libc::getpwnam_r(
+                    c_name.as_ptr(),
+                    &mut passwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                )
+            };
+            if rc != 0 || result.is_null() {
+                return Err(anyhow!("Unknown user: {}", name));
+            }
+            // SAFETY: getpwnam_r succeeded, the fields point to strings in buf
+            let string = |s| unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned();
+            Ok(Self {
+                name: name.to_string(),
+                uid: passwd.pw_uid,
+                gid: passwd.pw_gid,
+                home: string(passwd.pw_dir),
+                shell: string(passwd.pw_shell),
+            })
+        }
+    }
+
+    /// Attach the process' terminal to a shell stream, returns the exit status
+    /// of the command once the stream ended
+    pub(crate) async fn attach(
+        session: DuplexStream,
+        key: Option<&ShellKey>,
+    ) -> Result<Option<i32>> {
+        let (mut reader, mut writer) = tokio::io::split(session);
+        let _raw_mode = RawMode::enable();
+        let term = std::env::var("TERM").unwrap_or_default();
+        let key = key.map(|k| k.0.clone()).unwrap_or_default();
+        write_frame(&mut writer, &Frame::Start { size: window_size(), term, key }).await?;
+
+        let input = tokio::spawn(forward_terminal(writer));
+        let mut stdout = tokio::io::stdout();
+        let mut status = None;
+        while let Some(frame) = read_frame(&mut reader).await? {
+            match frame {
+                Frame::Data(data) => {
+                    stdout.write_all(&data).await?;
+                    stdout.flush().await?;
+                }
+                Frame::Exit(code) => {
+                    status = Some(code);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        input.abort();
+        Ok(status)
+    }
+
+    async fn forward_terminal<W: AsyncWrite + Unpin>(mut writer: W) -> Result<()> {
+        let mut stdin = tokio::io::stdin();
+        let mut window_change = signal(SignalKind::window_change())?;
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        loop {
+            tokio::select! {
+                read = stdin.read(&mut buf) => match read? {
+                    0 => break,
+                    n => write_frame(&mut writer, &Frame::Data(buf[..n].to_vec())).await?,
+                },
+                Some(()) = window_change.recv() => {
+                    write_frame(&mut writer, &Frame::Resize(window_size())).await?;
+                }
+            }
+        }
+        // Input ended, resizes are all that's left to send
+        while window_change.recv().await.is_some() {
+            write_frame(&mut writer, &Frame::Resize(window_size())).await?;
+        }
+        Ok(())
+    }
+
+    /// Size of the terminal on stdout, the default one if there is none
+    fn window_size() -> WindowSize {
+        // SAFETY: winsize is plain data, filled in by TIOCGWINSZ
+        let mut winsize: libc::winsize = unsafe { std::mem::zeroed() };
+        // SAFETY: TIOCGWINSZ writes a winsize
+        let rc = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ as _, &mut winsize) };
+        if rc < 0 || winsize.ws_col == 0 || winsize.ws_row == 0 {
+            return WindowSize::default();
+        }
+        WindowSize { cols: winsize.ws_col, rows: winsize.ws_row }
+    }
+
+    /// Raw mode of the terminal on stdin, restored on drop
+    struct RawMode(libc::termios);
+
+    impl RawMode {
+        /// `None` if stdin isn't a terminal
+        fn enable() -> Option<Self> {
+            // SAFETY: termios is plain data, filled in by tcgetattr
+            let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+            // SAFETY: the termios pointers are valid for the duration of the calls
+            unsafe {
+                if libc::isatty(libc::STDIN_FILENO) != 1
+                    || libc::tcgetattr(libc::STDIN_FILENO, &mut saved) < 0
+                {
+                    return None;
+                }
+                let mut raw = saved;
+                libc::cfmakeraw(&mut raw);
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw);
+            }
+            Some(Self(saved))
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            // SAFETY: restores the settings read in enable
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0) };
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[tokio::test]
+        async fn test_shell_session() {
+            let config = ShellConfig {
+                command: Some(
+                    "sh -c 'echo $TERM; stty size; read line; echo got $line; exit 3'".into(),
+                ),
+                key: Some(ShellKey::new("secret")),
+                ..Default::default()
+            };
+            let (mut reader, mut writer) = tokio::io::split(open(&config, "s1").unwrap());
+            let size = WindowSize { cols: 100, rows: 30 };
+            let start = Frame::Start { size, term: "xterm".into(), key: "secret".into() };
+            write_frame(&mut writer, &start).await.unwrap();
+            write_frame(&mut writer, &Frame::Data(b"hello\r".to_vec())).await.unwrap();
+
+            let mut output = Vec::new();
+            let status = loop {
+                match read_frame(&mut reader).await.unwrap() {
+                    Some(Frame::Data(data)) => output.extend(data),
+                    Some(Frame::Exit(code)) => break code,
+                    frame => panic!("Unexpected frame: {:?}", frame),
+                }
+            };
+            assert_eq!(status, 3);
+            let output = String::from_utf8_lossy(&output);
+            // The terminal echoes input whenever it arrives, between lines of output
+            assert!(output.contains("xterm\r\n"), "{:?}", output);
+            assert!(output.contains("30 100\r\n"), "{:?}", output);
+            assert!(output.contains("got hello\r\n"), "{:?}", output);
+            assert!(read_frame(&mut reader).await.unwrap().is_none());
+        }
+
+        #[tokio::test]
+        async fn test_shell_spawn_failure() {
+            let config = ShellConfig {
+                command: Some("/nonexistent/shell".into()),
+                key: Some(ShellKey::new("secret")),
+                ..Default::default()
+            };
+            let (mut reader, mut writer) = tokio::io::split(open(&config, "s2").unwrap());
+            let start = Frame::Start {
+                size: WindowSize::default(),
+                term: String::new(),
+                key: "secret".into(),
+            };
+            write_frame(&mut writer, &start).await.unwrap();
+            assert!(matches!(read_frame(&mut reader).await.unwrap(), Some(Frame::Data(_))));
+            assert_eq!(read_frame(&mut reader).await.unwrap(), Some(Frame::Exit(SPAWN_FAILED)));
+        }
+
+        #[tokio::test]
+        async fn test_shell_wrong_key() {
+            let config = ShellConfig { command: Some("true".into()), ..Default::default() };
+            for (key, presented) in [(None, ""), (Some("secret"), ""), (Some("secret"), "secreT")] {
+                let config = ShellConfig { key: key.map(ShellKey::new), ..config.clone() };
+                let (mut reader, mut writer) = tokio::io::split(open(&config, "s3").unwrap());
+                let start = Frame::Start {
+                    size: WindowSize::default(),
+                    term: String::new(),
+                    key: presented.into(),
+                };
+                write_frame(&mut writer, &start).await.unwrap();
+                assert!(matches!(read_frame(&mut reader).await.unwrap(), Some(Frame::Data(_))));
+                assert_eq!(read_frame(&mut reader).await.unwrap(), Some(Frame::Exit(DENIED)));
+            }
+        }
+
+        #[test]
+        fn test_unknown_user() {
+            let config = ShellConfig {
+                user: Some("no-such-user-lrc".into()),
+                key: Some(ShellKey::new("secret")),
+                ..Default::default()
+            };
+            assert!(config.validate().unwrap_err().to_string().contains("Unknown user"));
+            assert!(Account::lookup("root").is_ok());
+        }
+
+        #[test]
+        fn test_validate_shell() {
+            let config = ShellConfig {
+                command: Some("true".into()),
+                callers: vec!["*".into()],
+                ..Default::default()
+            };
+            assert!(config.validate().unwrap_err().to_string().contains("key"));
+            let config = ShellConfig { key: Some(ShellKey::new("secret")), ..config };
+            assert!(config.validate().unwrap_err().to_string().contains("'*'"));
+            let config = ShellConfig { allow_any_caller: true, ..config };
+            assert!(config.validate().is_ok());
+            assert_eq!(format!("{:?}", config.key.unwrap()), "ShellKey(..)");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_frame_roundtrip() {
+        let frames = [
+            Frame::Start {
+                size: WindowSize { cols: 132, rows: 43 },
+                term: "xterm-256color".into(),
+                key: "secret".into(),
+            },
+            Frame::Data(b"ls -l\r".to_vec()),
+            Frame::Resize(WindowSize::default()),
+            Frame::Exit(-1),
+        ];
+        let mut buf = Vec::new();
+        for frame in &frames {
+            write_frame(&mut buf, frame).await.unwrap();
+        }
+        // Unknown types are skipped
+        buf.extend([9, 0, 0, 0, 1, 0]);
+        write_frame(&mut buf, &Frame::Data(Vec::new())).await.unwrap();
+
+        let mut reader = &buf[..];
+        for frame in frames {
+            assert_eq!(read_frame(&mut reader).await.unwrap(), Some(frame));
+        }
+        assert_eq!(read_frame(&mut reader).await.unwrap(), Some(Frame::Data(Vec::new())));
+        assert_eq!(read_frame(&mut reader).await.unwrap(), None);
+
+        let mut truncated = &[RESIZE, 0, 0, 0, 2, 0, 80][..];
+        assert!(read_frame(&mut truncated).await.is_err());
+    }
+
+    #[test]
+    fn test_allowed_callers() {
+        let config = ShellConfig { callers: vec!["laptop".into()], ..Default::default() };
+        assert!(config.allows("laptop"));
+        assert!(!config.allows("other"));
+        assert!(!ShellConfig::default().allows("laptop"));
+        assert!(ShellConfig { callers: vec!["*".into()], ..Default::default() }.allows("other"));
+    }
+}
@@ -0,0 +1,256 @@
+//! Minimal SOCKS5 server side (RFC 1928) for portal listeners.
+//!
+//! Only CONNECT is supported, either without authentication or with
+//! username/password (RFC 1929). The destination is forwarded to the proxy as a dynamic
+//! forwarding request, which checks it against its allowlist. Success is
+//! replied before the proxy connects, a refused destination shows up as the
+//! connection closing right away.
+
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+/// How long a client has to get through to its CONNECT request
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+
+const METHOD_NONE: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+const METHOD_UNACCEPTABLE: u8 = 0xFF;
+
+const CMD_CONNECT: u8 = 1;
+
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// SOCKS5 settings of a portal listener, parsed from `[<username>:<password>]`
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Socks5Config {
+    /// Username and password clients must present, any client is let in without
+    pub credentials: Option<(String, String)>,
+}
+
+impl FromStr for Socks5Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Ok(Self::default());
+        }
+        match s.split_once(':') {
+            Some((user, password)) if !user.is_empty() && user.len() <= 255 => {
+                if password.len() > 255 {
+                    return Err(anyhow!("SOCKS5 password longer than 255 bytes"));
+                }
+                Ok(Self { credentials: Some((user.to_string(), password.to_string())) })
+            }
+            _ => Err(anyhow!("Invalid SOCKS5 credentials, expected <username>:<password>")),
+        }
+    }
+}
+
+impl fmt::Debug for Socks5Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let user = self.credentials.as_ref().map(|(user, _)| user.as_str());
+        f.debug_struct("Socks5Config").field("user", &user).finish()
+    }
+}
+
+/// Negotiate with a client up to its CONNECT request, returns the requested
+/// `host:port`. The socket is ready for bridging once this succeeds, and to be
+/// closed if it fails, a client taking too long included.
+pub(crate) async fn handshake<S>(socket: &mut S, config: &Socks5Config) -> Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    timeout(HANDSHAKE_TIMEOUT, negotiate(socket, config))
+        .await
+        .map_err(|_| anyhow!("SOCKS5 handshake timed out ({}s)", HANDSHAKE_TIMEOUT.as_secs()))?
+}
+
+async fn negotiate<S>(socket: &mut S, config: &Socks5Config) -> Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let [version, count] = read_array(socket).await?;
+    if version != VERSION {
+        return Err(anyhow!("Unsupported SOCKS version {}", version));
+    }
+    let mut methods = vec![0u8; count as usize];
+    socket.read_exact(&mut methods).await?;
+
+    let method = match config.credentials {
+        Some(_) => METHOD_PASSWORD,
+        None => METHOD_NONE,
+    };
+    if !methods.contains(&method) {
+        socket.write_all(&[VERSION, METHOD_UNACCEPTABLE]).await?;
+        return Err(anyhow!("No acceptable SOCKS5 authentication method"));
+    }
+    socket.write_all(&[VERSION, method]).await?;
+    if let Some((user, password)) = &config.credentials {
+        authenticate(socket, user, password).await?;
+    }
+
+    let [version, command, _, atyp] = read_array(socket).await?;
+    if version != VERSION {
+        return Err(anyhow!("Unsupported SOCKS version {}", version));
+    }
+    let host = match atyp {
+        ATYP_IPV4 => Ipv4Addr::from(read_array::<_, 4>(socket).await?).to_string(),
+        ATYP_IPV6 => format!("[{}]", Ipv6Addr::from(read_array::<_, 16>(socket).await?)),
+        ATYP_DOMAIN => {
+            let [len] = read_array(socket).await?;
+            let mut name = vec![0u8; len as usize];
+            socket.read_exact(&mut name).await?;
+            String::from_utf8(name).map_err(|_| anyhow!("Invalid SOCKS5 domain name"))?
+        }
+        _ => {
+            reply(socket, REPLY_ADDRESS_NOT_SUPPORTED).await?;
+            return Err(anyhow!("Unsupported SOCKS5 address type {}", atyp));
+        }
+    };
+    let port = u16::from_be_bytes(read_array(socket).await?);
+    if command != CMD_CONNECT {
+        reply(socket, REPLY_COMMAND_NOT_SUPPORTED).await?;
+        return Err(anyhow!("Unsupported SOCKS5 command {}", command));
+    }
+
+    reply(socket, REPLY_SUCCEEDED).await?;
+    Ok(format!("{}:{}", host, port))
+}
+
+async fn authenticate<S>(socket: &mut S, user: &str, password: &str) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let [version, len] = read_array(socket).await?;
+    if version != AUTH_VERSION {
+        return Err(anyhow!("Unsupported SOCKS5 auth version {}", version));
+    }
+    let mut given_user = vec![0u8; len as usize];
+    socket.read_exact(&mut given_user).await?;
+    let [len] = read_array(socket).await?;
+    let mut given_password = vec![0u8; len as usize];
+    socket.read_exact(&mut given_password).await?;
+
+    if given_user != user.as_bytes() || given_password != password.as_bytes() {
+        socket.write_all(&[AUTH_VERSION, 1]).await?;
+        return Err(anyhow!(
+            "SOCKS5 authentication failed for {}",
+            String::from_utf8_lossy(&given_user)
+        ));
+    }
+    socket.write_all(&[AUTH_VERSION, 0]).await?;
+    Ok(())
+}
+
+/// The bound address is meaningless through the tunnel and left unspecified
+async fn reply<S>(socket: &mut S, code: u8) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    socket.write_all(&[VERSION, code, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).await?;
+    Ok(())
+}
+
+async fn read_array<S, const N: usize>(socket: &mut S) -> Result<[u8; N]>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = [0u8; N];
+    socket.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    async fn client_handshake(
+        config: Socks5Config,
+        greeting: &'static [u8],
+        request: &'static [u8],
+    ) -> (Result<String>, Vec<u8>) {
+        let (mut client, mut server) = duplex(1024);
+        let server = tokio::spawn(async move { handshake(&mut server, &config).await });
+        client.write_all(greeting).await.unwrap();
+        client.write_all(request).await.unwrap();
+        let result = server.await.unwrap();
+        let _ = client.shutdown().await;
+        let mut replies = Vec::new();
+        let _ = client.read_to_end(&mut replies).await;
+        (result, replies)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_timeout() {
+        let (mut client, mut server) = duplex(1024);
+        client.write_all(&[VERSION, 1]).await.unwrap();
+        let error = handshake(&mut server, &Socks5Config::default()).await.unwrap_err();
+        assert!(error.to_string().contains("timed out"));
+    }
+
+    #[test]
+    fn test_parse_config() {
+        assert_eq!("".parse::<Socks5Config>().unwrap(), Socks5Config::default());
+        let config: Socks5Config = "alice:s3:cret".parse().unwrap();
+        assert_eq!(config.credentials, Some(("alice".into(), "s3:cret".into())));
+        assert!(!format!("{:?}", config).contains("s3:cret"));
+        assert!("alice".parse::<Socks5Config>().is_err());
+        assert!(":secret".parse::<Socks5Config>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_without_auth() {
+        let request = b"\x05\x01\x00\x03\x09plc.local\x01\xf6";
+        let (dest, replies) =
+            client_handshake(Socks5Config::default(), b"\x05\x01\x00", request).await;
+        assert_eq!(dest.unwrap(), "plc.local:502");
+        assert_eq!(&replies[..2], &[VERSION, METHOD_NONE]);
+        assert_eq!(replies[3], REPLY_SUCCEEDED);
+
+        let request = b"\x05\x01\x00\x04\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01\x00\x16";
+        let (dest, _) = client_handshake(Socks5Config::default(), b"\x05\x01\x00", request).await;
+        assert_eq!(dest.unwrap(), "[::1]:22");
+    }
+
+    #[tokio::test]
+    async fn test_password_auth() {
+        let config: Socks5Config = "alice:secret".parse().unwrap();
+        let request = b"\x01\x05alice\x06secret\x05\x01\x00\x01\xc0\xa8\x01\x32\x01\xf6";
+        let (dest, replies) = client_handshake(config.clone(), b"\x05\x02\x00\x02", request).await;
+        assert_eq!(dest.unwrap(), "192.168.1.50:502");
+        assert_eq!(&replies[..4], &[VERSION, METHOD_PASSWORD, AUTH_VERSION, 0]);
+
+        let request = b"\x01\x05alice\x05wrong";
+        let (dest, replies) = client_handshake(config.clone(), b"\x05\x01\x02", request).await;
+        assert!(dest.is_err());
+        assert_eq!(replies, [VERSION, METHOD_PASSWORD, AUTH_VERSION, 1]);
+
+        let (dest, replies) = client_handshake(config, b"\x05\x01\x00", b"").await;
+        assert!(dest.is_err());
+        assert_eq!(replies, [VERSION, METHOD_UNACCEPTABLE]);
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_command() {
+        let request = b"\x05\x02\x00\x01\x7f\x00\x00\x01\x00\x50";
+        let (dest, replies) =
+            client_handshake(Socks5Config::default(), b"\x05\x01\x00", request).await;
+        assert!(dest.is_err());
+        assert_eq!(replies[3], REPLY_COMMAND_NOT_SUPPORTED);
+    }
+}
@@ -0,0 +1,84 @@
+//! Standard input and output as a single stream.
+//!
+//! A portal created for `stdio://` bridges its own process' stdin and stdout
+//! to one stream instead of listening, for tools that run a command to reach
+//! a host, such as ssh's `ProxyCommand`.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, Stdin, Stdout};
+use tokio::sync::Notify;
+
+pub(crate) const STDIO_ADDR: &str = "stdio://";
+
+pub(crate) struct Stdio {
+    stdin: Stdin,
+    stdout: Stdout,
+    /// Notified once the stream ended, stdin may still block on its reader
+    closed: Arc<Notify>,
+}
+
+impl Stdio {
+    pub fn new(closed: Arc<Notify>) -> Self {
+        Self { stdin: tokio::io::stdin(), stdout: tokio::io::stdout(), closed }
+    }
+}
+
+impl AsyncRead for Stdio {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Stdio {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stdout).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let result = Pin::new(&mut self.stdout).poll_shutdown(cx);
+        if result.is_ready() {
+            self.closed.notify_one();
+        }
+        result
+    }
+}
+
+impl Drop for Stdio {
+    fn drop(&mut self) {
+        self.closed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_closed_on_shutdown_and_drop() {
+        let closed = Arc::new(Notify::new());
+        let mut stdio = Stdio::new(Arc::clone(&closed));
+        stdio.shutdown().await.unwrap();
+        assert!(timeout(Duration::from_secs(1), closed.notified()).await.is_ok());
+
+        drop(Stdio::new(Arc::clone(&closed)));
+        assert!(timeout(Duration::from_secs(1), closed.notified()).await.is_ok());
+    }
+}
@@ -1,9 +1,15 @@
 use anyhow::{anyhow, Result};
+use peer::gateway::Gateway;
 use peer::portal_manager::PortalManager;
 use peer::proxy_manager::ProxyManager;
-use peer::PeerConfig;
+use peer::{
+    CandidateKind, ChannelProfile, FileRoot, Lifetime, Peer, PeerConfig, RateLimit, StreamMode,
+};
 use signal::MqttConfig;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::time::timeout;
 use tracing::info;
 
@@ -34,6 +40,51 @@ fn test_peer_config() -> PeerConfig {
     }
 }
 
+/// TCP echo server standing in for the robot-side service
+async fn spawn_echo_server(addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut r, mut w) = socket.split();
+                let _ = tokio::io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Server writing `banner` to every connection, then closing it
+async fn spawn_banner_server(addr: &str, banner: &'static [u8]) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let _ = socket.write_all(banner).await;
+        }
+    });
+    Ok(())
+}
+
+/// Read everything the portal forwards until the stream is closed
+async fn read_to_end(portal_addr: &str) -> Result<Vec<u8>> {
+    let mut socket = TcpStream::connect(portal_addr).await?;
+    let mut data = Vec::new();
+    timeout(Duration::from_secs(5), socket.read_to_end(&mut data)).await??;
+    Ok(data)
+}
+
+/// Send `payload` through the portal and expect it echoed back unchanged
+async fn assert_echo(portal_addr: &str, payload: Vec<u8>) -> Result<()> {
+    let mut socket = TcpStream::connect(portal_addr).await?;
+    let (mut r, mut w) = socket.split();
+    let mut echoed = vec![0u8; payload.len()];
+    let (write, read) = tokio::join!(w.write_all(&payload), r.read_exact(&mut echoed));
+    write?;
+    read?;
+    assert_eq!(echoed, payload);
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_portal_proxy_connection() -> Result<()> {
     init_tracing();
@@ -112,3 +163,1269 @@ async fn test_portal_timeout() -> Result<()> {
         Err(e) => Err(anyhow!("Unexpected error: {}", e)),
     }
 }
+
+#[tokio::test]
+async fn test_dedicated_streams() -> Result<()> {
+    init_tracing();
+
+    let target_addr = "127.0.0.1:19020";
+    spawn_echo_server(target_addr).await?;
+
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_dedicated")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .target_addr(target_addr)
+        .run()
+        .await?;
+
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_dedicated")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .run()
+        .await?;
+
+    let portal_addr = "127.0.0.1:19021";
+    timeout(
+        Duration::from_secs(15),
+        portal_manager.create_portal("test_proxy_dedicated", portal_addr.to_string()),
+    )
+    .await??;
+
+    let (a, b) = tokio::join!(
+        assert_echo(portal_addr, b"hello".to_vec()),
+        assert_echo(portal_addr, vec![7u8; 64 * 1024]),
+    );
+    a?;
+    b?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_channel_profile() -> Result<()> {
+    init_tracing();
+
+    let target_addr = "127.0.0.1:19212";
+    spawn_echo_server(target_addr).await?;
+
+    let (proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_profile")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .target_addr(target_addr)
+        .run()
+        .await?;
+
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_profile")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .run()
+        .await?;
+
+    let portal_addr = "127.0.0.1:19213";
+    timeout(
+        Duration::from_secs(15),
+        portal_manager.create_portal(
+            "test_proxy_profile",
+            format!("{}?profile=max-retransmits:3", portal_addr),
+        ),
+    )
+    .await??;
+
+    // The dedicated channel reaches the proxy with the portal's profile
+    let mut socket = TcpStream::connect(portal_addr).await?;
+    socket.write_all(b"hello").await?;
+    let mut echoed = [0u8; 5];
+    timeout(Duration::from_secs(5), socket.read_exact(&mut echoed)).await??;
+    let profiles = proxy_manager.channel_profiles("test_portal_profile").await?;
+    assert_eq!(profiles, [ChannelProfile::MaxRetransmits(3)]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multiplexed_streams() -> Result<()> {
+    init_tracing();
+
+    let target_addr = "127.0.0.1:19010";
+    spawn_echo_server(target_addr).await?;
+
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_mux")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .target_addr(target_addr)
+        .run()
+        .await?;
+
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_mux")
+        .mqtt(test_mqtt_config())
+        .peer(PeerConfig { stream_mode: StreamMode::Multiplexed, ..test_peer_config() })
+        .run()
+        .await?;
+
+    let portal_addr = "127.0.0.1:19011";
+    timeout(
+        Duration::from_secs(15),
+        portal_manager.create_portal("test_proxy_mux", portal_addr.to_string()),
+    )
+    .await??;
+
+    // Several concurrent streams, one larger than the flow control window
+    let bulk: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let (a, b, c) = tokio::join!(
+        assert_echo(portal_addr, b"hello".to_vec()),
+        assert_echo(portal_addr, bulk),
+        assert_echo(portal_addr, b"world".to_vec()),
+    );
+    a?;
+    b?;
+    c?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pooled_streams() -> Result<()> {
+    init_tracing();
+
+    let target_addr = "127.0.0.1:19030";
+    spawn_echo_server(target_addr).await?;
+
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_pool")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .target_addr(target_addr)
+        .run()
+        .await?;
+
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_pool")
+        .mqtt(test_mqtt_config())
+        .peer(PeerConfig { stream_mode: StreamMode::Pooled(2), ..test_peer_config() })
+        .run()
+        .await?;
+
+    let portal_addr = "127.0.0.1:19031";
+    let portal = timeout(
+        Duration::from_secs(15),
+        portal_manager.create_portal("test_proxy_pool", portal_addr.to_string()),
+    )
+    .await??;
+    let pool_idle = || async {
+        timeout(Duration::from_secs(10), async {
+            while portal.pool_stats().map_or(true, |s| s.open < s.channels || s.busy > 0) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+    };
+    pool_idle().await?;
+
+    // More concurrent streams than pooled channels falls back to dedicated ones
+    let (a, b, c) = tokio::join!(
+        assert_echo(portal_addr, b"hello".to_vec()),
+        assert_echo(portal_addr, vec![7u8; 64 * 1024]),
+        assert_echo(portal_addr, b"world".to_vec()),
+    );
+    a?;
+    b?;
+    c?;
+    let stats = portal.pool_stats().unwrap();
+    assert_eq!((stats.channels, stats.claims + stats.misses), (2, 3), "{}", stats);
+    assert!(stats.claims >= 2, "{}", stats);
+
+    // Released channels are reused
+    for _ in 0..4 {
+        pool_idle().await?;
+        assert_echo(portal_addr, b"again".to_vec()).await?;
+    }
+    let reused = portal.pool_stats().unwrap();
+    assert_eq!(reused.claims, stats.claims + 4, "{}", reused);
+    assert_eq!(reused.misses, stats.misses, "{}", reused);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compressed_streams() -> Result<()> {
+    init_tracing();
+
+    let target_addr = "127.0.0.1:19040";
+    spawn_echo_server(target_addr).await?;
+
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_compress")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .target_addr(target_addr)
+        .run()
+        .await?;
+
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_compress")
+        .mqtt(test_mqtt_config())
+        .peer(PeerConfig { stream_mode: StreamMode::Multiplexed, ..test_peer_config() })
+        .run()
+        .await?;
+
+    let portal_addr = "127.0.0.1:19041";
+    let portal = timeout(
+        Duration::from_secs(15),
+        portal_manager
+            .create_portal("test_proxy_compress", format!("{}?compress=zstd", portal_addr)),
+    )
+    .await??;
+
+    let json = br#"{"joint": "elbow", "position": 0.0, "velocity": 0.0}"#.repeat(4096);
+    let (a, b) =
+        tokio::join!(assert_echo(portal_addr, json), assert_echo(portal_addr, b"hello".to_vec()),);
+    a?;
+    b?;
+
+    let stats = portal.compression_stats().expect("compression negotiated");
+    assert!(stats.ratio() > 4.0, "{}", stats);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rate_limited_streams() -> Result<()> {
+    init_tracing();
+
+    let target_addr = "127.0.0.1:19050";
+    spawn_echo_server(target_addr).await?;
+
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_rate")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .target_addr(target_addr)
+        .run()
+        .await?;
+
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_rate")
+        .mqtt(test_mqtt_config())
+        .peer(PeerConfig {
+            session_rate_limit: Some(RateLimit::new(64 * 1024).with_burst(16 * 1024)),
+            ..test_peer_config()
+        })
+        .run()
+        .await?;
+
+    let portal_addr = "127.0.0.1:19051";
+    timeout(
+        Duration::from_secs(15),
+        portal_manager.create_portal("test_proxy_rate", portal_addr.to_string()),
+    )
+    .await??;
+
+    // 128 KiB at 64 KiB/s after a 16 KiB burst
+    let start = std::time::Instant::now();
+    assert_echo(portal_addr, vec![1u8; 128 * 1024]).await?;
+    assert!(start.elapsed() >= Duration::from_millis(1500), "{:?}", start.elapsed());
+
+    portal_manager.set_session_rate_limit("test_proxy_rate", None).await?;
+    let start = std::time::Instant::now();
+    assert_echo(portal_addr, vec![1u8; 128 * 1024]).await?;
+    assert!(start.elapsed() < Duration::from_millis(1500), "{:?}", start.elapsed());
+
+    assert!(portal_manager.set_session_rate_limit("nobody", None).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_named_services() -> Result<()> {
+    init_tracing();
+
+    spawn_banner_server("127.0.0.1:19060", b"gps").await?;
+    spawn_banner_server("127.0.0.1:19061", b"cam").await?;
+
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_services")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .service("gps", "127.0.0.1:19060")
+        .service("cam", "127.0.0.1:19061")
+        .run()
+        .await?;
+
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_services")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .run()
+        .await?;
+
+    // One peer connection, one listener per service
+    let gps = timeout(
+        Duration::from_secs(15),
+        portal_manager.create_portal("test_proxy_services", "127.0.0.1:0?service=gps".into()),
+    )
+    .await??;
+    let cam = portal_manager
+        .create_portal("test_proxy_services", "127.0.0.1:0?service=cam".into())
+        .await?;
+    let unknown = portal_manager
+        .create_portal("test_proxy_services", "127.0.0.1:0?service=lidar".into())
+        .await?;
+    assert!(Arc::ptr_eq(&gps, &cam));
+    assert_eq!(gps.listener_count(), 3);
+
+    assert_eq!(read_to_end(&gps.local_addr("gps").unwrap()).await?, b"gps");
+    assert_eq!(read_to_end(&cam.local_addr("cam").unwrap()).await?, b"cam");
+    assert!(read_to_end(&unknown.local_addr("lidar").unwrap()).await?.is_empty());
+
+    portal_manager.remove_service("test_proxy_services", "lidar").await?;
+    assert_eq!(gps.listener_count(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dynamic_forwarding() -> Result<()> {
+    init_tracing();
+
+    spawn_banner_server("127.0.0.1:19070", b"plc").await?;
+    spawn_banner_server("127.0.0.1:19071", b"ssh").await?;
+
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_dynamic")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .allow("127.0.0.0/8:19070".parse()?)
+        .run()
+        .await?;
+
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_dynamic")
+        .mqtt(test_mqtt_config())
+        .peer(PeerConfig { stream_mode: StreamMode::Multiplexed, ..test_peer_config() })
+        .run()
+        .await?;
+
+    let portal = timeout(
+        Duration::from_secs(15),
+        portal_manager
+            .create_portal("test_proxy_dynamic", "127.0.0.1:0?dest=127.0.0.1:19070".into()),
+    )
+    .await??;
+    portal_manager
+        .create_portal("test_proxy_dynamic", "127.0.0.1:0?dest=127.0.0.1:19071".into())
+        .await?;
+
+    assert_eq!(read_to_end(&portal.local_addr("127.0.0.1:19070").unwrap()).await?, b"plc");
+    // Denied by the allowlist, the proxy refuses the stream
+    assert!(read_to_end(&portal.local_addr("127.0.0.1:19071").unwrap()).await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_socks5_forwarding() -> Result<()> {
+    init_tracing();
+
+    spawn_banner_server("127.0.0.1:19080", b"plc").await?;
+
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_socks5")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .allow("127.0.0.1:19080".parse()?)
+        .run()
+        .await?;
+
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_socks5")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .run()
+        .await?;
+
+    let portal = timeout(
+        Duration::from_secs(15),
+        portal_manager.create_portal("test_proxy_socks5", "127.0.0.1:0?socks5=alice:secret".into()),
+    )
+    .await??;
+
+    let mut client = TcpStream::connect(portal.local_addr("socks5").unwrap()).await?;
+    client.write_all(b"\x05\x01\x02").await?;
+    client.write_all(b"\x01\x05alice\x06secret").await?;
+    client.write_all(b"\x05\x01\x00\x01\x7f\x00\x00\x01").await?;
+    client.write_all(&19080u16.to_be_bytes()).await?;
+
+    let mut replies = [0u8; 14];
+    client.read_exact(&mut replies).await?;
+    assert_eq!(&replies[..4], &[5, 2, 1, 0]);
+    assert_eq!(&replies[4..6], &[5, 0]);
+
+    let mut banner = Vec::new();
+    timeout(Duration::from_secs(5), client.read_to_end(&mut banner)).await??;
+    assert_eq!(banner, b"plc");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_http_proxy_connect() -> Result<()> {
+    init_tracing();
+
+    spawn_banner_server("127.0.0.1:19090", b"plc").await?;
+
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_http")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .allow("localhost:19090".parse()?)
+        .run()
+        .await?;
+
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_http")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .run()
+        .await?;
+
+    let portal = timeout(
+        Duration::from_secs(15),
+        portal_manager.create_portal("test_proxy_http", "127.0.0.1:0?http-proxy".into()),
+    )
+    .await??;
+
+    let mut client = TcpStream::connect(portal.local_addr("http-proxy").unwrap()).await?;
+    client.write_all(b"CONNECT localhost:19090 HTTP/1.1\r\nHost: localhost:19090\r\n\r\n").await?;
+    let mut reply = Vec::new();
+    timeout(Duration::from_secs(5), client.read_to_end(&mut reply)).await??;
+    assert_eq!(reply, b"HTTP/1.1 200 Connection Established\r\n\r\nplc");
+    Ok(())
+}
+
+/// Answers each request with its request line and forwarded prefix
+async fn spawn_http_server(addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0u8; 1];
+                    if socket.read(&mut byte).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    head.push(byte[0]);
+                }
+                let head = String::from_utf8_lossy(&head);
+                let request_line = head.lines().next().unwrap_or_default();
+                let prefix = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("X-Forwarded-Prefix: "))
+                    .unwrap_or_default();
+                let body = format!("{} {}", request_line, prefix);
+                let response =
+                    format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                let _ = socket.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn http_get(addr: &str, target: &str) -> Result<String> {
+    let mut client = TcpStream::connect(addr).await?;
+    let request = format!("GET {} HTTP/1.1\r\nHost: gw\r\n\r\n", target);
+    client.write_all(request.as_bytes()).await?;
+    let mut response = String::new();
+    timeout(Duration::from_secs(15), client.read_to_string(&mut response)).await??;
+    Ok(response)
+}
+
+#[tokio::test]
+async fn test_http_gateway() -> Result<()> {
+    init_tracing();
+
+    spawn_http_server("127.0.0.1:19100").await?;
+
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_gateway")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .service("web", "127.0.0.1:19100")
+        .run()
+        .await?;
+
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_gateway")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .run()
+        .await?;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(Arc::new(Gateway::new(portal_manager)).serve(listener));
+
+    let response = http_get(&addr, "/robots/test_proxy_gateway/web/status?x=1").await?;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with("GET /status?x=1 HTTP/1.1 /robots/test_proxy_gateway/web"));
+
+    // The portal created by the first request is reused
+    let response = http_get(&addr, "/robots/test_proxy_gateway/web").await?;
+    assert!(response.contains("GET / HTTP/1.1"));
+
+    assert!(http_get(&addr, "/status").await?.starts_with("HTTP/1.1 404"));
+
+    // An offline robot is answered from its failure until it expires
+    let response = http_get(&addr, "/robots/test_proxy_gateway_offline/web").await?;
+    assert!(response.starts_with("HTTP/1.1 502"));
+    let started = std::time::Instant::now();
+    let response = http_get(&addr, "/robots/test_proxy_gateway_offline/web").await?;
+    assert!(response.starts_with("HTTP/1.1 502"));
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    // Failures are cached per service, another one is tried afresh
+    let started = std::time::Instant::now();
+    let response = http_get(&addr, "/robots/test_proxy_gateway_offline/api").await?;
+    assert!(response.starts_with("HTTP/1.1 502"));
+    assert!(started.elapsed() >= Duration::from_secs(1));
+
+    // A client that never sends its request head is disconnected
+    let mut idle = TcpStream::connect(&addr).await?;
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(15), idle.read_to_end(&mut response)).await??;
+    assert!(response.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_stdio_portal() -> Result<()> {
+    // Shutting down normally would wait for the blocking read of stdin
+    let runtime = tokio::runtime::Runtime::new()?;
+    let result = runtime.block_on(stdio_portal());
+    runtime.shutdown_background();
+    result
+}
+
+async fn stdio_portal() -> Result<()> {
+    init_tracing();
+
+    let target_addr = "127.0.0.1:19210";
+    spawn_banner_server(target_addr, b"stdio\n").await?;
+
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_stdio")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .target_addr(target_addr)
+        .run()
+        .await?;
+
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_stdio")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .run()
+        .await?;
+
+    let portal = timeout(
+        Duration::from_secs(15),
+        portal_manager.create_portal("test_proxy_stdio", "stdio://".to_string()),
+    )
+    .await??;
+
+    // The target closing after its banner ends the stdio stream
+    timeout(Duration::from_secs(10), portal.stdio_closed()).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reverse_forwarding() -> Result<()> {
+    init_tracing();
+
+    // Served on the portal side, reached from listeners on the proxy side
+    spawn_banner_server("127.0.0.1:19110", b"repo").await?;
+
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_reverse")
+        .mqtt(test_mqtt_config())
+        .peer(PeerConfig { reverse_forwarding: true, ..test_peer_config() })
+        .run()
+        .await?;
+
+    for (mode, remote_addr) in
+        [(StreamMode::Dedicated, "127.0.0.1:19111"), (StreamMode::Multiplexed, "127.0.0.1:19112")]
+    {
+        let (portal_manager, _) = PortalManager::builder()
+            .local_id(format!("test_portal_reverse_{}", mode))
+            .mqtt(test_mqtt_config())
+            .peer(PeerConfig { stream_mode: mode, ..test_peer_config() })
+            .reverse_forward(remote_addr, "127.0.0.1:19110")
+            .run()
+            .await?;
+
+        timeout(
+            Duration::from_secs(15),
+            portal_manager.create_portal("test_proxy_reverse", "127.0.0.1:0".into()),
+        )
+        .await??;
+
+        assert_eq!(read_to_end(remote_addr).await?, b"repo");
+        assert_eq!(read_to_end(remote_addr).await?, b"repo");
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_serial_over_pty() -> Result<()> {
+    use tokio_serial::{SerialPort, SerialPortBuilderExt, SerialStream};
+    init_tracing();
+
+    // A pseudo-terminal pair stands in for the robot's serial device
+    let (mut device, device_tty) = SerialStream::pair()?;
+    let device_path = device_tty.name().ok_or_else(|| anyhow!("pty without name"))?;
+
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_serial")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .service("gnss", format!("serial://{}?baud=9600", device_path))
+        .run()
+        .await?;
+
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_serial")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .run()
+        .await?;
+
+    let pty_path = std::env::temp_dir().join("peer_test_serial_pty");
+    let pty_uri = format!("pty://{}?service=gnss", pty_path.display());
+    let portal = timeout(
+        Duration::from_secs(15),
+        portal_manager.create_portal("test_proxy_serial", pty_uri),
+    )
+    .await??;
+
+    let mut tool = tokio_serial::new(pty_path.to_string_lossy(), 9600).open_native_async()?;
+    tool.write_all(b"$PMTK220,100*2F\r\n").await?;
+    let mut received = [0u8; 17];
+    timeout(Duration::from_secs(5), device.read_exact(&mut received)).await??;
+    assert_eq!(&received, b"$PMTK220,100*2F\r\n");
+
+    device.write_all(b"$GPGGA\r\n").await?;
+    let mut received = [0u8; 8];
+    timeout(Duration::from_secs(5), tool.read_exact(&mut received)).await??;
+    assert_eq!(&received, b"$GPGGA\r\n");
+
+    // The device is taken by the pseudo-terminal's stream, other streams are refused
+    let (other_manager, _) = PortalManager::builder()
+        .local_id("test_portal_serial_other")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .run()
+        .await?;
+    let other =
+        other_manager.create_portal("test_proxy_serial", "127.0.0.1:0?service=gnss".into()).await?;
+    assert!(read_to_end(&other.local_addr("gnss").unwrap()).await?.is_empty());
+
+    assert!(portal.remove_listener("gnss"));
+    assert!(!pty_path.exists());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_exec_target() -> Result<()> {
+    init_tracing();
+
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_exec")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .service("diag", "exec://sh -c 'echo \"$LRC_SERVICE ready\"; exec cat'")
+        .run()
+        .await?;
+
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_exec")
+        .mqtt(test_mqtt_config())
+        .peer(PeerConfig { stream_mode: StreamMode::Multiplexed, ..test_peer_config() })
+        .run()
+        .await?;
+
+    let portal = timeout(
+        Duration::from_secs(15),
+        portal_manager.create_portal("test_proxy_exec", "127.0.0.1:0?service=diag".into()),
+    )
+    .await??;
+
+    // Each connection runs its own command
+    for _ in 0..2 {
+        let mut socket = TcpStream::connect(portal.local_addr("diag").unwrap()).await?;
+        let mut banner = [0u8; 11];
+        timeout(Duration::from_secs(5), socket.read_exact(&mut banner)).await??;
+        assert_eq!(&banner, b"diag ready\n");
+
+        socket.write_all(b"status\n").await?;
+        let mut echoed = [0u8; 7];
+        timeout(Duration::from_secs(5), socket.read_exact(&mut echoed)).await??;
+        assert_eq!(&echoed, b"status\n");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_file_transfer() -> Result<()> {
+    init_tracing();
+
+    let dir = std::env::temp_dir().join(format!("lrc-files-{}", std::process::id()));
+    let (logs, maps, local) = (dir.join("logs"), dir.join("maps"), dir.join("local"));
+    for path in [&logs, &maps, &local] {
+        std::fs::create_dir_all(path)?;
+    }
+    let log: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(logs.join("driver.log"), &log)?;
+
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_files")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .file_root(format!("logs={}", logs.display()).parse::<FileRoot>()?)
+        .file_root(format!("maps={}:rw", maps.display()).parse::<FileRoot>()?)
+        .run()
+        .await?;
+
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_files")
+        .mqtt(test_mqtt_config())
+        .peer(PeerConfig { stream_mode: StreamMode::Multiplexed, ..test_peer_config() })
+        .run()
+        .await?;
+
+    let pulled = local.join("driver.log");
+    let transfer = timeout(
+        Duration::from_secs(15),
+        portal_manager.pull("test_proxy_files", "logs/driver.log", &pulled),
+    )
+    .await??;
+    assert_eq!(transfer.size, log.len() as u64);
+    assert_eq!(std::fs::read(&pulled)?, log);
+
+    // A partial copy resumes where it stopped
+    std::fs::rename(&pulled, local.join("driver.log.part"))?;
+    std::fs::OpenOptions::new().write(true).open(local.join("driver.log.part"))?.set_len(1000)?;
+    let transfer = timeout(
+        Duration::from_secs(15),
+        portal_manager.pull("test_proxy_files", "logs/driver.log", &pulled),
+    )
+    .await??;
+    assert_eq!(transfer.resumed, 1000);
+    assert_eq!(std::fs::read(&pulled)?, log);
+
+    let pushed = timeout(
+        Duration::from_secs(15),
+        portal_manager.push("test_proxy_files", &pulled, "maps/a"),
+    )
+    .await??;
+    assert_eq!(pushed.size, log.len() as u64);
+    assert_eq!(std::fs::read(maps.join("a"))?, log);
+
+    // Roots are read-only unless configured writable, paths stay inside them
+    assert!(portal_manager.push("test_proxy_files", &pulled, "logs/a").await.is_err());
+    assert!(portal_manager
+        .pull("test_proxy_files", "maps/../logs/driver.log", &pulled)
+        .await
+        .is_err());
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_persistent_portal_reconnects() -> Result<()> {
+    init_tracing();
+
+    let target_addr = "127.0.0.1:19130";
+    spawn_echo_server(target_addr).await?;
+
+    let start_proxy = || {
+        ProxyManager::builder()
+            .local_id("test_proxy_persistent")
+            .mqtt(test_mqtt_config())
+            .peer(test_peer_config())
+            .target_addr(target_addr)
+            .run()
+    };
+    let (proxy_manager, proxy_loop) = start_proxy().await?;
+
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_persistent")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .run()
+        .await?;
+
+    let portal_addr = "127.0.0.1:19131";
+    let portal = timeout(
+        Duration::from_secs(15),
+        portal_manager
+            .create_portal("test_proxy_persistent", format!("{}?persistent", portal_addr)),
+    )
+    .await??;
+    assert!(portal.is_persistent());
+    assert_echo(portal_addr, b"before".to_vec()).await?;
+
+    // The proxy restarts, the listener stays bound and the portal reconnects
+    proxy_loop.abort();
+    drop(proxy_manager);
+    let _restarted = start_proxy().await?;
+
+    timeout(Duration::from_secs(60), async {
+        while assert_echo(portal_addr, b"after".to_vec()).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    })
+    .await?;
+    assert!(portal.is_connected());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_link_stats() -> Result<()> {
+    init_tracing();
+
+    let (proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_link_stats")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .target_addr("127.0.0.1:19150")
+        .run()
+        .await?;
+
+    let peer_config =
+        PeerConfig { keepalive_interval: Duration::from_millis(100), ..test_peer_config() };
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_link_stats")
+        .mqtt(test_mqtt_config())
+        .peer(peer_config)
+        .run()
+        .await?;
+
+    timeout(
+        Duration::from_secs(15),
+        portal_manager.create_portal("test_proxy_link_stats", String::new()),
+    )
+    .await??;
+
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let stats = portal_manager.link_stats("test_proxy_link_stats").await?.unwrap();
+    info!("Portal side: {}", stats);
+    assert!(stats.pongs_received >= 5);
+    assert!(stats.rtt < Duration::from_millis(500));
+    assert_eq!(stats.loss, 0.0);
+
+    // The proxy pings at its own, default interval
+    let stats = proxy_manager.link_stats("test_portal_link_stats").await?.unwrap();
+    assert!(stats.pongs_received >= 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connection_path() -> Result<()> {
+    init_tracing();
+
+    let target_addr = "127.0.0.1:19160";
+    spawn_echo_server(target_addr).await?;
+
+    let (proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_path")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .target_addr(target_addr)
+        .run()
+        .await?;
+
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_path")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .run()
+        .await?;
+
+    let portal_addr = "127.0.0.1:19161";
+    timeout(
+        Duration::from_secs(15),
+        portal_manager.create_portal("test_proxy_path", portal_addr.to_string()),
+    )
+    .await??;
+    assert_echo(portal_addr, vec![7u8; 4096]).await?;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    // Both ends of a local connection talk directly
+    let path = portal_manager.connection_path("test_proxy_path").await?.unwrap();
+    info!("Portal side: {}", path);
+    assert!(!path.is_relayed());
+    assert_eq!(path.local.kind, CandidateKind::Host);
+    assert_eq!(path.protocol, "udp");
+    assert!(path.bytes_sent > 4096);
+    assert!(path.bytes_received > 4096);
+    assert!(path.rtt.is_some());
+
+    let path = proxy_manager.connection_path("test_portal_path").await?.unwrap();
+    assert!(!path.is_relayed());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ice_restart_keeps_streams() -> Result<()> {
+    init_tracing();
+
+    let target_addr = "127.0.0.1:19140";
+    spawn_echo_server(target_addr).await?;
+
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_ice_restart")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .target_addr(target_addr)
+        .run()
+        .await?;
+
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_ice_restart")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .run()
+        .await?;
+
+    let portal_addr = "127.0.0.1:19141";
+    let portal = timeout(
+        Duration::from_secs(15),
+        portal_manager.create_portal("test_proxy_ice_restart", portal_addr.to_string()),
+    )
+    .await??;
+
+    // One connection held open across the restart
+    let mut socket = TcpStream::connect(portal_addr).await?;
+    let mut echoed = [0u8; 6];
+    socket.write_all(b"before").await?;
+    timeout(Duration::from_secs(5), socket.read_exact(&mut echoed)).await??;
+    assert_eq!(&echoed, b"before");
+
+    portal_manager.restart_ice("test_proxy_ice_restart").await?;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    socket.write_all(b"after!").await?;
+    timeout(Duration::from_secs(5), socket.read_exact(&mut echoed)).await??;
+    assert_eq!(&echoed, b"after!");
+    assert!(portal.is_connected());
+    assert_echo(portal_addr, b"new stream".to_vec()).await?;
+    Ok(())
+}
+
+/// Expect the portal to drop a connection the proxy couldn't serve right away,
+/// instead of only half-closing it
+async fn assert_refused(portal_addr: &str) -> Result<()> {
+    let mut socket = TcpStream::connect(portal_addr).await?;
+    socket.write_all(b"hello").await?;
+    let mut buffer = [0u8; 16];
+    match timeout(Duration::from_secs(5), socket.read(&mut buffer)).await? {
+        Ok(0) | Err(_) => {}
+        Ok(n) => return Err(anyhow!("{} unexpected bytes from a refused stream", n)),
+    }
+    // Writing to a closed socket is answered with a reset
+    let _ = socket.write_all(b"more").await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(socket.write_all(b"more").await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unreachable_target_closes_stream() -> Result<()> {
+    init_tracing();
+
+    // Nothing listens on the target port
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_unreachable")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .target_addr("127.0.0.1:19170")
+        .run()
+        .await?;
+
+    let modes = [StreamMode::Dedicated, StreamMode::Multiplexed, StreamMode::Pooled(4)];
+    for (i, stream_mode) in modes.into_iter().enumerate() {
+        let (portal_manager, _) = PortalManager::builder()
+            .local_id(format!("test_portal_unreachable_{}", i))
+            .mqtt(test_mqtt_config())
+            .peer(PeerConfig { stream_mode, ..test_peer_config() })
+            .run()
+            .await?;
+
+        let portal_addr = format!("127.0.0.1:{}", 19171 + i);
+        timeout(
+            Duration::from_secs(15),
+            portal_manager.create_portal("test_proxy_unreachable", portal_addr.clone()),
+        )
+        .await??;
+        assert_refused(&portal_addr).await?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lazy_portal() -> Result<()> {
+    init_tracing();
+
+    let target_addr = "127.0.0.1:19180";
+    spawn_echo_server(target_addr).await?;
+
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_lazy")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .target_addr(target_addr)
+        .run()
+        .await?;
+
+    let peer_config = PeerConfig {
+        lazy_idle_timeout: Duration::from_secs(1),
+        lazy_connect_timeout: Duration::from_secs(2),
+        ..test_peer_config()
+    };
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_lazy")
+        .mqtt(test_mqtt_config())
+        .peer(peer_config)
+        .run()
+        .await?;
+
+    // Returns once bound, connecting on the first connection
+    let portal_addr = "127.0.0.1:19181";
+    let portal = timeout(
+        Duration::from_secs(1),
+        portal_manager.create_portal("test_proxy_lazy", format!("{}?lazy", portal_addr)),
+    )
+    .await??;
+    assert!(portal.is_lazy());
+    assert!(!portal.is_connected());
+    assert_echo(portal_addr, b"first".to_vec()).await?;
+    assert!(portal.is_connected());
+
+    // Disconnected once idle, the next connection connects again
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert!(!portal.is_connected());
+    assert_echo(portal_addr, b"second".to_vec()).await?;
+
+    // Clients arriving together while disconnected share the new connection
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert!(!portal.is_connected());
+    tokio::try_join!(
+        assert_echo(portal_addr, b"third".to_vec()),
+        assert_echo(portal_addr, b"fourth".to_vec())
+    )?;
+
+    // Nobody answers for an offline remote, connections are dropped at the deadline
+    let offline_addr = "127.0.0.1:19182";
+    portal_manager
+        .create_portal("test_proxy_lazy_offline", format!("{}?lazy", offline_addr))
+        .await?;
+    assert!(read_to_end(offline_addr).await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_idle_timeouts_and_lifetimes() -> Result<()> {
+    init_tracing();
+
+    let target_addr = "127.0.0.1:19190";
+    spawn_echo_server(target_addr).await?;
+
+    let idle_config = PeerConfig {
+        stream_lifetime: Lifetime {
+            idle_timeout: Some(Duration::from_secs(1)),
+            max_lifetime: None,
+        },
+        ..test_peer_config()
+    };
+    let (_idle_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_idle")
+        .mqtt(test_mqtt_config())
+        .peer(idle_config)
+        .target_addr(target_addr)
+        .run()
+        .await?;
+
+    let max_config = PeerConfig {
+        session_lifetime: Lifetime {
+            idle_timeout: None,
+            max_lifetime: Some(Duration::from_secs(2)),
+        },
+        ..test_peer_config()
+    };
+    let (max_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_max_lifetime")
+        .mqtt(test_mqtt_config())
+        .peer(max_config)
+        .target_addr(target_addr)
+        .run()
+        .await?;
+
+    let portal_config = PeerConfig {
+        session_lifetime: Lifetime {
+            idle_timeout: Some(Duration::from_secs(2)),
+            max_lifetime: None,
+        },
+        ..test_peer_config()
+    };
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_lifetime")
+        .mqtt(test_mqtt_config())
+        .peer(portal_config)
+        .run()
+        .await?;
+
+    let portal_addr = "127.0.0.1:19191";
+    portal_manager.create_portal("test_proxy_idle", portal_addr.to_string()).await?;
+
+    // Traffic keeps a stream open past its idle timeout
+    let mut socket = TcpStream::connect(portal_addr).await?;
+    let mut echoed = [0u8; 4];
+    for _ in 0..5 {
+        socket.write_all(b"ping").await?;
+        socket.read_exact(&mut echoed).await?;
+        tokio::time::sleep(Duration::from_millis(400)).await;
+    }
+
+    // Left idle, the proxy closes it
+    let mut rest = Vec::new();
+    timeout(Duration::from_secs(3), socket.read_to_end(&mut rest)).await??;
+    assert!(rest.is_empty());
+    drop(socket);
+
+    // Without streams the portal is removed, along with its listener
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert!(portal_manager.connection_path("test_proxy_idle").await.is_err());
+    assert!(TcpStream::connect(portal_addr).await.is_err());
+
+    // The proxy closes a session at its maximum lifetime, busy or not
+    let max_addr = "127.0.0.1:19192";
+    portal_manager.create_portal("test_proxy_max_lifetime", max_addr.to_string()).await?;
+    let mut socket = TcpStream::connect(max_addr).await?;
+    socket.write_all(b"ping").await?;
+    socket.read_exact(&mut echoed).await?;
+    assert_eq!(max_proxy_manager.connection_count().await, 1);
+    let mut rest = Vec::new();
+    timeout(Duration::from_secs(5), socket.read_to_end(&mut rest)).await??;
+    assert_eq!(max_proxy_manager.connection_count().await, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_proxy_limits() -> Result<()> {
+    init_tracing();
+
+    let target_addr = "127.0.0.1:19200";
+    spawn_echo_server(target_addr).await?;
+
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_limits")
+        .mqtt(test_mqtt_config())
+        .peer(PeerConfig { max_channels: Some(2), ..test_peer_config() })
+        .target_addr(target_addr)
+        .max_connections(1)
+        .max_offers(2)
+        .run()
+        .await?;
+
+    let (portal_manager, _) = PortalManager::builder()
+        .local_id("test_portal_limits")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .run()
+        .await?;
+    let portal_addr = "127.0.0.1:19201";
+    portal_manager.create_portal("test_proxy_limits", portal_addr.to_string()).await?;
+
+    // Each dedicated stream is a DataChannel, the third one is refused
+    let mut first = TcpStream::connect(portal_addr).await?;
+    let mut second = TcpStream::connect(portal_addr).await?;
+    let mut echoed = [0u8; 4];
+    for socket in [&mut first, &mut second] {
+        socket.write_all(b"ping").await?;
+        socket.read_exact(&mut echoed).await?;
+    }
+    assert_refused(portal_addr).await?;
+
+    // A closed channel makes room again
+    drop(first);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_echo(portal_addr, b"room".to_vec()).await?;
+    drop(second);
+
+    // Another portal is rejected right away, first for the connection limit,
+    // then for sending too many offers
+    let (other_manager, _) = PortalManager::builder()
+        .local_id("test_portal_limits_other")
+        .mqtt(test_mqtt_config())
+        .peer(test_peer_config())
+        .run()
+        .await?;
+    for expected in ["Too many connections", "Too many connections", "Too many offers"] {
+        let started = std::time::Instant::now();
+        let error = other_manager
+            .create_portal("test_proxy_limits", String::new())
+            .await
+            .err()
+            .ok_or_else(|| anyhow!("Offer over the limits accepted"))?;
+        info!("Rejected: {}", error);
+        assert!(error.to_string().contains(expected));
+        assert!(error.to_string().contains("overloaded"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stream_limit_shared_channels() -> Result<()> {
+    init_tracing();
+
+    let target_addr = "127.0.0.1:19202";
+    spawn_echo_server(target_addr).await?;
+
+    let (_proxy_manager, _) = ProxyManager::builder()
+        .local_id("test_proxy_stream_limit")
+        .mqtt(test_mqtt_config())
+        .peer(PeerConfig { max_channels: Some(2), ..test_peer_config() })
+        .target_addr(target_addr)
+        .run()
+        .await?;
+
+    // Streams inside the one multiplexed channel and uses of pooled channels
+    // count like dedicated channels
+    let modes = [StreamMode::Multiplexed, StreamMode::Pooled(8)];
+    for (i, stream_mode) in modes.into_iter().enumerate() {
+        let (portal_manager, _) = PortalManager::builder()
+            .local_id(format!("test_portal_stream_limit_{}", i))
+            .mqtt(test_mqtt_config())
+            .peer(PeerConfig { stream_mode, ..test_peer_config() })
+            .run()
+            .await?;
+        let portal_addr = format!("127.0.0.1:{}", 19203 + i);
+        portal_manager.create_portal("test_proxy_stream_limit", portal_addr.clone()).await?;
+
+        let mut first = TcpStream::connect(&portal_addr).await?;
+        let mut second = TcpStream::connect(&portal_addr).await?;
+        let mut echoed = [0u8; 4];
+        for socket in [&mut first, &mut second] {
+            socket.write_all(b"ping").await?;
+            socket.read_exact(&mut echoed).await?;
+        }
+        assert_refused(&portal_addr).await?;
+
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_echo(&portal_addr, b"room".to_vec()).await?;
+    }
+    Ok(())
+}
@@ -6,6 +6,8 @@ pub enum SignalType {
     Offer,
     Answer,
     Candidate,
+    /// The callee refused the offer, the payload tells why
+    Reject,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -1,6 +1,6 @@
 use anyhow::Result;
 use clap::Args;
-use peer::PeerConfig;
+use peer::{ChannelProfile, Compression, Lifetime, PeerConfig, RateLimit, StreamMode};
 use signal::MqttConfig;
 use std::time::Duration;
 
@@ -58,6 +58,62 @@ pub struct PeerArgs {
     /// Timeout for WebRTC connection (seconds)
     #[arg(long, default_value = "5")]
     pub connect_timeout: u64,
+
+    /// Time an interrupted connection has to recover with an ICE restart, keeping its
+    /// streams (seconds, 0 closes it right away)
+    #[arg(long, default_value = "15")]
+    pub ice_restart_grace: u64,
+
+    /// Interval of the pings measuring round-trip time and detecting dead links
+    /// (milliseconds, 0 disables them)
+    #[arg(long, default_value = "1000")]
+    pub keepalive_interval: u64,
+
+    /// A link not answering pings for this long counts as disconnected (milliseconds)
+    #[arg(long, default_value = "3000")]
+    pub keepalive_timeout: u64,
+
+    /// DataChannel reliability: reliable, unordered, max-retransmits:<n> or max-lifetime:<ms>;
+    /// all but reliable may reorder or drop data and only suit message-oriented protocols
+    #[arg(long, default_value = "reliable")]
+    pub channel_profile: ChannelProfile,
+
+    /// Stream mapping: dedicated (one DataChannel per connection), mux (one shared DataChannel)
+    /// or pool:<n> (n pre-negotiated DataChannels reused across connections)
+    #[arg(long, default_value = "dedicated")]
+    pub stream_mode: StreamMode,
+
+    /// Tunnel compression: none, zstd or lz4 (negotiated with the proxy)
+    #[arg(long, default_value = "none")]
+    pub compression: Compression,
+
+    /// Chunks smaller than this many bytes are sent uncompressed
+    #[arg(long, default_value = "256")]
+    pub compression_threshold: usize,
+
+    /// Limit on all data sent to the peer, bytes per second with optional burst (e.g., 2M/256K)
+    #[arg(long)]
+    pub session_rate_limit: Option<RateLimit>,
+
+    /// Limit on the data sent by each connection, bytes per second with optional burst
+    #[arg(long)]
+    pub stream_rate_limit: Option<RateLimit>,
+
+    /// Close a portal or proxy session left without connections this long (seconds)
+    #[arg(long)]
+    pub session_idle_timeout: Option<u64>,
+
+    /// Close a portal or proxy session this long after it started (seconds)
+    #[arg(long)]
+    pub session_max_lifetime: Option<u64>,
+
+    /// Close a connection that carried no data this long (seconds)
+    #[arg(long)]
+    pub stream_idle_timeout: Option<u64>,
+
+    /// Close a connection this long after it was opened (seconds)
+    #[arg(long)]
+    pub stream_max_lifetime: Option<u64>,
 }
 
 impl PeerArgs {
@@ -77,6 +133,23 @@ impl PeerArgs {
             ice_servers,
             online_timeout: Duration::from_secs(self.online_timeout),
             connect_timeout: Duration::from_secs(self.connect_timeout),
+            ice_restart_grace: Duration::from_secs(self.ice_restart_grace),
+            keepalive_interval: Duration::from_millis(self.keepalive_interval),
+            keepalive_timeout: Duration::from_millis(self.keepalive_timeout),
+            channel_profile: self.channel_profile,
+            stream_mode: self.stream_mode,
+            compression: self.compression,
+            compression_threshold: self.compression_threshold,
+            session_rate_limit: self.session_rate_limit,
+            stream_rate_limit: self.stream_rate_limit,
+            session_lifetime: Lifetime {
+                idle_timeout: self.session_idle_timeout.map(Duration::from_secs),
+                max_lifetime: self.session_max_lifetime.map(Duration::from_secs),
+            },
+            stream_lifetime: Lifetime {
+                idle_timeout: self.stream_idle_timeout.map(Duration::from_secs),
+                max_lifetime: self.stream_max_lifetime.map(Duration::from_secs),
+            },
             ..Default::default()
         }
     }
@@ -123,6 +196,8 @@ pub fn init_runtime() {
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| "info,webrtc=off,webrtc_sctp=off,turn=error".into()),
         )
+        // Keeps stdout free for the stream of portald --stdio
+        .with_writer(std::io::stderr)
         .init();
 
     rustls::crypto::ring::default_provider()
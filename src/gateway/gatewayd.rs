@@ -0,0 +1,62 @@
+use anyhow::Result;
+use clap::Parser;
+use peer::gateway::Gateway;
+use peer::portal_manager::PortalManager;
+use remote_rpc_rs::{init_runtime, MqttArgs, PeerArgs};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+#[derive(Parser, Debug)]
+#[command(name = "gatewayd")]
+#[command(about = "WebRTC HTTP Gateway - Route HTTP requests to the web services of many robots")]
+struct Args {
+    /// Local client ID for signaling
+    #[arg(short, long)]
+    local_id: String,
+
+    /// HTTP listen address, requests go to /robots/<robot_id>/<service>/...
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    listen: String,
+
+    /// Also route <robot_id>.<domain> hosts (e.g., gw.local)
+    #[arg(long)]
+    host_domain: Option<String>,
+
+    /// Service host routed requests go to, the proxy's default target if empty
+    #[arg(long, default_value = "", requires = "host_domain")]
+    host_service: String,
+
+    #[command(flatten)]
+    mqtt: MqttArgs,
+
+    #[command(flatten)]
+    peer: PeerArgs,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    init_runtime();
+
+    let args = Args::parse();
+
+    let (manager, event_loop) = PortalManager::builder()
+        .local_id(&args.local_id)
+        .mqtt(args.mqtt.to_config()?)
+        .peer(args.peer.to_config())
+        .run()
+        .await?;
+
+    let mut gateway = Gateway::new(manager);
+    if let Some(domain) = &args.host_domain {
+        gateway = gateway.host_domain(domain, &args.host_service);
+    }
+    let listener = TcpListener::bind(&args.listen).await?;
+
+    tokio::select! {
+        result = Arc::new(gateway).serve(listener) => result?,
+        _ = event_loop => tracing::info!("PortalManager exited"),
+        _ = tokio::signal::ctrl_c() => tracing::info!("Shutting down..."),
+    }
+
+    Ok(())
+}
@@ -1,29 +1,153 @@
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
 use peer::portal_manager::PortalManager;
+use peer::ShellKey;
 use remote_rpc_rs::{init_runtime, MqttArgs, PeerArgs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(name = "portald")]
 #[command(about = "WebRTC Portal - Create local portal to remote service")]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
     /// Local client ID for signaling
     #[arg(short, long)]
     local_id: String,
 
     /// Remote proxy ID to connect
-    #[arg(short, long)]
-    remote_id: String,
+    #[arg(short, long, required_unless_present = "shell")]
+    remote_id: Option<String>,
 
-    /// Local address to listen for incoming connections
-    #[arg(short, long)]
-    portal_addr: String,
+    /// Local address to listen for incoming connections, can specify multiple
+    /// (e.g., 127.0.0.1:9000 or 127.0.0.1:9001?service=gps)
+    #[arg(short, long, required_unless_present_any = ["socks5", "http_proxy", "stdio", "shell"])]
+    portal_addr: Vec<String>,
+
+    /// Bridge stdin and stdout to one stream instead of listening, exits when it closes.
+    /// Takes portal options (e.g., --stdio service=ssh, for ssh -o ProxyCommand)
+    #[arg(long, num_args = 0..=1, default_missing_value = "",
+          conflicts_with_all = ["portal_addr", "socks5", "http_proxy"])]
+    stdio: Option<String>,
+
+    /// Attach this terminal to a shell on the remote proxy with this ID, exits with its status
+    #[arg(long, conflicts_with_all = ["remote_id", "portal_addr", "stdio", "socks5", "http_proxy"])]
+    shell: Option<String>,
+
+    /// File holding the key the remote's shell asks for
+    #[arg(long, requires = "shell")]
+    shell_key_file: Option<PathBuf>,
+
+    /// Local address of a SOCKS5 server reaching the remote's network (e.g., 127.0.0.1:1080)
+    #[arg(long)]
+    socks5: Option<String>,
+
+    /// Local address of an HTTP proxy (CONNECT and absolute URIs) reaching the remote's network
+    /// (e.g., 127.0.0.1:3128)
+    #[arg(long)]
+    http_proxy: Option<String>,
+
+    /// Forward connections to an address on the remote back to a local one, can specify
+    /// multiple (e.g., 127.0.0.1:8000=127.0.0.1:3000); the remote must allow reverse forwarding
+    #[arg(short = 'R', long, value_parser = parse_reverse)]
+    reverse: Vec<(String, String)>,
+
+    /// Keep the listeners when the connection is lost and reconnect whenever the remote is back
+    #[arg(long)]
+    persistent: bool,
+
+    /// Bind the listeners right away and connect on their first connection, disconnecting
+    /// once idle
+    #[arg(long)]
+    lazy: bool,
+
+    /// Time a lazy portal keeps its connection without streams (seconds)
+    #[arg(long, default_value = "60", requires = "lazy")]
+    lazy_idle_timeout: u64,
+
+    /// Time a connection to a lazy portal waits for it to connect (seconds)
+    #[arg(long, default_value = "10", requires = "lazy")]
+    lazy_connect_timeout: u64,
+
+    /// Username SOCKS5 clients must authenticate with, Optional
+    #[arg(long, requires_all = ["socks5", "socks5_password"])]
+    socks5_username: Option<String>,
+
+    /// Password SOCKS5 clients must authenticate with, Optional
+    #[arg(long, requires = "socks5_username")]
+    socks5_password: Option<String>,
 
     #[command(flatten)]
     mqtt: MqttArgs,
 
     #[command(flatten)]
     peer: PeerArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Copy a file from or to the file service of a remote proxy, resuming an
+    /// interrupted copy (e.g., cp robot1:logs/driver.log . or cp map.pgm robot1:maps/)
+    Cp {
+        /// Source, <REMOTE_ID>:<ROOT>/<PATH> for a remote file
+        source: String,
+
+        /// Destination, <REMOTE_ID>:<ROOT>/<PATH> for a remote file
+        dest: String,
+    },
+}
+
+/// A remote `<remote_id>:<path>`, a single letter before ':' is a drive letter
+fn split_remote(s: &str) -> Option<(&str, &str)> {
+    match s.split_once(':') {
+        Some((remote, path)) if remote.len() > 1 && !remote.contains(['/', '\\']) => {
+            Some((remote, path))
+        }
+        _ => None,
+    }
+}
+
+/// Copy between a local and a remote file, as `portald cp` does
+async fn copy(manager: &PortalManager, source: &str, dest: &str) -> Result<()> {
+    match (split_remote(source), split_remote(dest)) {
+        (Some((remote_id, remote_path)), None) => {
+            let mut local_path = PathBuf::from(dest);
+            if local_path.is_dir() {
+                let name = Path::new(remote_path)
+                    .file_name()
+                    .ok_or_else(|| anyhow!("Not a file: {}", source))?;
+                local_path.push(name);
+            }
+            let transfer = manager.pull(remote_id, remote_path, &local_path).await?;
+            tracing::info!("Copied {} to {}: {}", source, local_path.display(), transfer);
+        }
+        (None, Some((remote_id, remote_path))) => {
+            let mut remote_path = remote_path.to_string();
+            if remote_path.is_empty() || remote_path.ends_with('/') {
+                let name = Path::new(source)
+                    .file_name()
+                    .ok_or_else(|| anyhow!("Not a file: {}", source))?;
+                remote_path.push_str(&name.to_string_lossy());
+            }
+            let transfer = manager.push(remote_id, source, &remote_path).await?;
+            tracing::info!("Copied {} to {}:{}: {}", source, remote_id, remote_path, transfer);
+        }
+        _ => return Err(anyhow!("Copy needs one local and one remote (<REMOTE_ID>:<PATH>) file")),
+    }
+    Ok(())
+}
+
+fn parse_reverse(s: &str) -> Result<(String, String), String> {
+    // Unix socket paths may contain '=', split on the last one
+    match s.rsplit_once('=') {
+        Some((remote, local)) if !remote.is_empty() && !local.is_empty() => {
+            Ok((remote.to_string(), local.to_string()))
+        }
+        _ => Err(format!("expected <remote addr>=<local addr>, got '{}'", s)),
+    }
 }
 
 #[tokio::main]
@@ -32,16 +156,69 @@ async fn main() -> Result<()> {
 
     let args = Args::parse();
 
-    let (manager, event_loop) = PortalManager::builder()
+    let mut peer_config = args.peer.to_config();
+    peer_config.shell_key = args.shell_key_file.as_ref().map(ShellKey::from_file).transpose()?;
+    peer_config.persistent = args.persistent;
+    peer_config.lazy = args.lazy;
+    peer_config.lazy_idle_timeout = Duration::from_secs(args.lazy_idle_timeout);
+    peer_config.lazy_connect_timeout = Duration::from_secs(args.lazy_connect_timeout);
+    let mut builder = PortalManager::builder()
         .local_id(&args.local_id)
         .mqtt(args.mqtt.to_config()?)
-        .peer(args.peer.to_config())
-        .run()
-        .await?;
+        .peer(peer_config);
+    for (remote, local) in &args.reverse {
+        builder = builder.reverse_forward(remote, local);
+    }
+    let (manager, event_loop) = builder.run().await?;
 
-    manager.create_portal(&args.remote_id, args.portal_addr.clone()).await?;
+    if let Some(Command::Cp { source, dest }) = &args.command {
+        return tokio::select! {
+            result = copy(&manager, source, dest) => result,
+            _ = event_loop => Err(anyhow!("PortalManager exited")),
+        };
+    }
 
-    tracing::info!("Portal established: {} -> {}", args.portal_addr, args.remote_id);
+    if let Some(remote_id) = &args.shell {
+        let portal = manager.create_portal(remote_id, "shell://".to_string()).await?;
+        tokio::select! {
+            _ = portal.stdio_closed() => tracing::debug!("Shell closed"),
+            _ = event_loop => tracing::info!("PortalManager exited"),
+        }
+        // The runtime would wait for the blocking stdin read on shutdown
+        std::process::exit(portal.shell_status().unwrap_or(255));
+    }
+
+    let remote_id = args.remote_id.as_deref().unwrap_or_default();
+    if let Some(options) = &args.stdio {
+        let addr_uri = format!("stdio://?{}", options);
+        let portal = manager.create_portal(remote_id, addr_uri).await?;
+        tokio::select! {
+            _ = portal.stdio_closed() => tracing::debug!("Stdio stream closed"),
+            _ = event_loop => tracing::info!("PortalManager exited"),
+            _ = tokio::signal::ctrl_c() => tracing::info!("Shutting down..."),
+        }
+        // The runtime would wait for the blocking stdin read on shutdown
+        std::process::exit(0);
+    }
+
+    for portal_addr in &args.portal_addr {
+        manager.create_portal(remote_id, portal_addr.clone()).await?;
+        tracing::info!("Portal established: {} -> {}", portal_addr, remote_id);
+    }
+
+    if let Some(socks5_addr) = &args.socks5 {
+        let addr_uri = match (&args.socks5_username, &args.socks5_password) {
+            (Some(user), Some(password)) => format!("{}?socks5={}:{}", socks5_addr, user, password),
+            _ => format!("{}?socks5", socks5_addr),
+        };
+        manager.create_portal(remote_id, addr_uri).await?;
+        tracing::info!("SOCKS5 portal established: {} -> {}", socks5_addr, remote_id);
+    }
+
+    if let Some(http_proxy_addr) = &args.http_proxy {
+        manager.create_portal(remote_id, format!("{}?http-proxy", http_proxy_addr)).await?;
+        tracing::info!("HTTP proxy portal established: {} -> {}", http_proxy_addr, remote_id);
+    }
 
     tokio::select! {
         _ = event_loop => tracing::info!("PortalManager exited"),
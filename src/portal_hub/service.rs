@@ -53,19 +53,21 @@ impl PortalHubService {
     /// Create a portal to remote service
     pub async fn create_portal(&self, req: CreatePortalRequest) -> Result<CreatePortalResponse> {
         let user_id = self.resolve_user_id(&req.user_id)?;
-        let remote_id = Self::build_remote_id(&req.robot_id, &req.service_name);
-        let addr_uri = Self::build_addr_uri(&req);
+        let addr_uri = format!("{}?service={}", Self::build_addr_uri(&req), req.service_name);
 
         let manager = self.get_or_create_manager(&user_id).await?;
 
-        match manager.create_portal(&remote_id, addr_uri).await {
+        // All services of a robot share one peer connection to its proxyd
+        match manager.create_portal(&req.robot_id, addr_uri).await {
             Ok(portal) => {
-                let uri = portal.addr_uri.clone();
-                info!("✅ Portal {} created, URI: {}", remote_id, uri);
+                let uri = portal
+                    .local_addr(&req.service_name)
+                    .ok_or_else(|| anyhow!("Portal {} has no listener", req.robot_id))?;
+                info!("✅ Portal {}/{} created, URI: {}", req.robot_id, req.service_name, uri);
                 Ok(CreatePortalResponse { uri })
             }
             Err(e) => {
-                warn!("❌ Failed to create portal {}: {}", remote_id, e);
+                warn!("❌ Failed to create portal {}/{}: {}", req.robot_id, req.service_name, e);
                 Err(e)
             }
         }
@@ -74,12 +76,14 @@ impl PortalHubService {
     /// Destroy a portal
     pub async fn destroy_portal(&self, req: CreatePortalRequest) -> Result<()> {
         let user_id = self.resolve_user_id(&req.user_id)?;
-        let remote_id = Self::build_remote_id(&req.robot_id, &req.service_name);
 
         let managers = self.managers.read().await;
         if let Some(managed) = managers.get(&user_id) {
-            managed.manager.remove_portal(&remote_id).await?;
-            info!("🧹 Destroyed portal: user={}, remote={}", user_id, remote_id);
+            managed.manager.remove_service(&req.robot_id, &req.service_name).await?;
+            info!(
+                "🧹 Destroyed portal: user={}, remote={}/{}",
+                user_id, req.robot_id, req.service_name
+            );
         }
 
         Ok(())
@@ -124,10 +128,6 @@ impl PortalHubService {
         Ok(manager)
     }
 
-    fn build_remote_id(robot_id: &str, service_name: &str) -> String {
-        format!("{}-{}", robot_id, service_name)
-    }
-
     fn build_addr_uri(req: &CreatePortalRequest) -> String {
         match req.portal_type {
             PortalType::Unix => {
@@ -1,7 +1,10 @@
 use anyhow::Result;
 use clap::Parser;
 use peer::proxy_manager::ProxyManager;
+use peer::{AllowRule, FileRoot, RateLimit, ReverseRule, ShellConfig, ShellKey};
 use remote_rpc_rs::{init_runtime, MqttArgs, PeerArgs};
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(name = "proxyd")]
@@ -11,9 +14,75 @@ struct Args {
     #[arg(short, long)]
     local_id: String,
 
-    /// Target service address to proxy (e.g., 127.0.0.1:9000 or unix:///path/to/socket)
-    #[arg(short, long)]
-    proxy_addr: String,
+    /// Default target service address to proxy (e.g., 127.0.0.1:9000 or unix:///path/to/socket)
+    #[arg(short, long, required_unless_present_any = ["service", "allow", "reverse_forwarding", "shell", "files"])]
+    proxy_addr: Option<String>,
+
+    /// Named service portals can choose, can specify multiple (e.g., gps=127.0.0.1:9000)
+    #[arg(long, value_parser = parse_service)]
+    service: Vec<(String, String)>,
+
+    /// Destination portals may forward to, can specify multiple (e.g., 192.168.1.0/24:502, plc.local, *.lan:8000-8100)
+    #[arg(long)]
+    allow: Vec<AllowRule>,
+
+    /// Let portals open listeners here whose connections are forwarded back to them
+    #[arg(long)]
+    reverse_forwarding: bool,
+
+    /// Where portals may ask for those listeners, can specify multiple (e.g., 127.0.0.1:8000-8100, unix:///run/lrc/*), loopback addresses by default
+    #[arg(long, requires = "reverse_forwarding")]
+    reverse_allow: Vec<ReverseRule>,
+
+    /// Serve an interactive terminal to this portal ID, can specify multiple ('*' allows any,
+    /// with --shell-allow-any)
+    #[arg(long, requires = "shell_key_file")]
+    shell: Vec<String>,
+
+    /// File holding the key portals have to present to open a terminal
+    #[arg(long, requires = "shell")]
+    shell_key_file: Option<PathBuf>,
+
+    /// Accept --shell '*', leaving the key as the only check on who opens a terminal
+    #[arg(long, requires = "shell")]
+    shell_allow_any: bool,
+
+    /// Command the terminal runs (e.g., "tmux new -A -s field"), the login shell by default
+    #[arg(long, requires = "shell")]
+    shell_command: Option<String>,
+
+    /// User the terminal's command runs as, proxyd's own by default
+    #[arg(long, requires = "shell")]
+    shell_user: Option<String>,
+
+    /// Directory portals may copy files from and to, can specify multiple (e.g., logs=/var/log/robot:ro, maps=/opt/maps)
+    #[arg(long)]
+    files: Vec<FileRoot>,
+
+    /// Limit on the data sent by all portal sessions together (e.g., 4M/512K)
+    #[arg(long)]
+    rate_limit: Option<RateLimit>,
+
+    /// Peer connections served at once, further portals are rejected
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// Streams each portal may have open at once, whatever DataChannels carry them
+    #[arg(long)]
+    max_channels: Option<usize>,
+
+    /// Offers each portal may send per --offer-window, ICE restarts included; portals
+    /// changing their ID get around it, see --max-total-offers
+    #[arg(long)]
+    max_offers: Option<u32>,
+
+    /// Offers all portals together may send per --offer-window, further ones are dropped
+    #[arg(long)]
+    max_total_offers: Option<u32>,
+
+    /// Window --max-offers and --max-total-offers count offers in (seconds)
+    #[arg(long, default_value = "60")]
+    offer_window: u64,
 
     #[command(flatten)]
     mqtt: MqttArgs,
@@ -22,21 +91,68 @@ struct Args {
     peer: PeerArgs,
 }
 
+fn parse_service(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, addr)) if !name.is_empty() && !addr.is_empty() => {
+            Ok((name.to_string(), addr.to_string()))
+        }
+        _ => Err(format!("expected <name>=<addr>, got '{}'", s)),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     init_runtime();
 
     let args = Args::parse();
 
-    let (_manager, event_loop) = ProxyManager::builder()
+    let mut peer_config = args.peer.to_config();
+    peer_config.reverse_forwarding = args.reverse_forwarding;
+    if !args.reverse_allow.is_empty() {
+        peer_config.reverse_allow = args.reverse_allow;
+    }
+    peer_config.max_channels = args.max_channels;
+    let mut builder = ProxyManager::builder()
         .local_id(&args.local_id)
         .mqtt(args.mqtt.to_config()?)
-        .peer(args.peer.to_config())
-        .target_addr(&args.proxy_addr)
-        .run()
-        .await?;
+        .peer(peer_config);
+    if let Some(addr) = &args.proxy_addr {
+        builder = builder.target_addr(addr);
+    }
+    for (name, addr) in &args.service {
+        builder = builder.service(name, addr);
+    }
+    for rule in args.allow {
+        builder = builder.allow(rule);
+    }
+    for root in args.files {
+        builder = builder.file_root(root);
+    }
+    if let Some(limit) = args.rate_limit {
+        builder = builder.rate_limit(limit);
+    }
+    if let Some(max) = args.max_connections {
+        builder = builder.max_connections(max);
+    }
+    if let Some(max) = args.max_offers {
+        builder = builder.max_offers(max);
+    }
+    if let Some(max) = args.max_total_offers {
+        builder = builder.max_total_offers(max);
+    }
+    builder = builder.offer_window(Duration::from_secs(args.offer_window));
+    if !args.shell.is_empty() {
+        builder = builder.shell(ShellConfig {
+            command: args.shell_command,
+            user: args.shell_user,
+            callers: args.shell,
+            key: args.shell_key_file.map(ShellKey::from_file).transpose()?,
+            allow_any_caller: args.shell_allow_any,
+        });
+    }
+    let (manager, event_loop) = builder.run().await?;
 
-    tracing::info!("Proxyd started: {} -> {}", args.local_id, args.proxy_addr);
+    tracing::info!("Proxyd started: {} -> {}", args.local_id, manager.services);
 
     tokio::select! {
         _ = event_loop => tracing::info!("ProxyManager exited"),